@@ -1,8 +1,10 @@
 mod parse;
 mod plugins;
 mod target_config;
+mod tls;
 mod toml;
 mod utils_dns_proto;
+mod utils_listen_addr;
 mod utils_verifier;
 
 pub use {
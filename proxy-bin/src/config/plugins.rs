@@ -1,13 +1,36 @@
-use super::toml::ConfigToml;
+use super::toml::{ConfigToml, Upstream};
 use crate::error::*;
 use doh_auth_proxy_lib::QueryManipulationConfig;
-use std::{env, fs, path::PathBuf};
+use std::{collections::HashMap, env, fs, path::PathBuf, sync::Arc};
 
-/// Read query manipulation settings from paths specified in config toml
+/// Read query manipulation settings for the default upstream profile from paths specified in config toml
 impl TryFrom<&ConfigToml> for Option<QueryManipulationConfig> {
   type Error = anyhow::Error;
 
   fn try_from(value: &ConfigToml) -> Result<Self, Self::Error> {
+    (&value.upstream).try_into()
+  }
+}
+
+/// Read query manipulation settings for each named upstream profile, keyed by the profile name
+pub(super) fn read_profile_query_manipulation_configs(
+  config_toml: &ConfigToml,
+) -> anyhow::Result<HashMap<String, Arc<QueryManipulationConfig>>> {
+  let mut configs = HashMap::new();
+  for profile in config_toml.profiles.iter().flatten() {
+    let config: Option<QueryManipulationConfig> = (&profile.upstream).try_into()?;
+    if let Some(config) = config {
+      configs.insert(profile.name.clone(), Arc::new(config));
+    }
+  }
+  Ok(configs)
+}
+
+/// Read query manipulation settings from paths specified in upstream settings
+impl TryFrom<&Upstream> for Option<QueryManipulationConfig> {
+  type Error = anyhow::Error;
+
+  fn try_from(value: &Upstream) -> Result<Self, Self::Error> {
     if value.plugins.is_none() {
       // debug!("Query manipulation plugins are disabled");
       return Ok(None);
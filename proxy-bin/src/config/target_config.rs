@@ -1,11 +1,25 @@
-use super::{toml::ConfigToml, utils_dns_proto::parse_proto_sockaddr_str, utils_verifier::*};
+use super::{
+  plugins::read_profile_query_manipulation_configs,
+  toml::{ConfigToml, Upstream},
+  utils_dns_proto::parse_proto_sockaddr_str,
+  utils_listen_addr::{is_unix_listen_addr, parse_listen_addr_str, parse_unix_listen_addr_str, ListenProto},
+  utils_verifier::*,
+};
 use crate::{constants::*, error::*, log::*};
 use async_trait::async_trait;
 use doh_auth_proxy_lib::{
-  AuthenticationConfig, NextHopRelayConfig, ProxyConfig, QueryManipulationConfig, SubseqRelayConfig, TokenConfig,
+  AccessControlConfig, AccessDeniedAction, AuthenticationConfig, CircuitBreakerConfig, HealthcheckProbe, HedgingConfig,
+  NextHopRelayConfig, PathSelection, ProxyConfig, QueryManipulationConfig, RateLimitConfig, SubseqRelayConfig, TlsConfig,
+  TokenConfig, UpstreamProfile, DEFAULT_UPSTREAM_PROFILE, PATH_SELECTION_RANDOMNESS_PERCENT,
 };
 use hot_reload::{Reload, ReloaderError};
-use std::{env, sync::Arc};
+use ipnet::IpNet;
+use std::{
+  collections::HashMap,
+  env,
+  net::{IpAddr, SocketAddr},
+  sync::Arc,
+};
 use tokio::time::Duration;
 
 #[derive(PartialEq, Eq, Clone, Debug)]
@@ -15,6 +29,10 @@ pub struct TargetConfig {
   pub config_toml: ConfigToml,
   /// manipulation plugin config
   pub query_manipulation_config: Option<Arc<QueryManipulationConfig>>,
+  /// manipulation plugin configs of named upstream profiles
+  pub profile_query_manipulation_configs: HashMap<String, Arc<QueryManipulationConfig>>,
+  /// tls certificate and private key for encrypted listeners
+  pub tls_config: Option<TlsConfig>,
 }
 
 #[derive(Clone)]
@@ -38,10 +56,17 @@ impl Reload<TargetConfig> for ConfigReloader {
     let query_manipulation_config: Option<QueryManipulationConfig> = (&config_toml)
       .try_into()
       .map_err(|_e| ReloaderError::<TargetConfig>::Reload("Failed to reload manipulation plugin config"))?;
+    let profile_query_manipulation_configs = read_profile_query_manipulation_configs(&config_toml)
+      .map_err(|_e| ReloaderError::<TargetConfig>::Reload("Failed to reload manipulation plugin config of profiles"))?;
+    let tls_config: Option<TlsConfig> = (&config_toml)
+      .try_into()
+      .map_err(|_e| ReloaderError::<TargetConfig>::Reload("Failed to reload tls certificate and private key"))?;
 
     Ok(Some(TargetConfig {
       config_toml,
       query_manipulation_config: query_manipulation_config.map(Arc::new),
+      profile_query_manipulation_configs,
+      tls_config,
     }))
   }
 }
@@ -51,9 +76,13 @@ impl TargetConfig {
   pub async fn new(config_file: &str) -> anyhow::Result<Self> {
     let config_toml = ConfigToml::new(config_file)?;
     let query_manipulation_config: Option<QueryManipulationConfig> = (&config_toml).try_into()?;
+    let profile_query_manipulation_configs = read_profile_query_manipulation_configs(&config_toml)?;
+    let tls_config: Option<TlsConfig> = (&config_toml).try_into()?;
     Ok(Self {
       config_toml,
       query_manipulation_config: query_manipulation_config.map(Arc::new),
+      profile_query_manipulation_configs,
+      tls_config,
     })
   }
 }
@@ -67,11 +96,109 @@ impl TryInto<ProxyConfig> for &TargetConfig {
     /////////////////////////////
     // listen addresses
     if let Some(val) = &self.config_toml.listen_addresses {
-      if !val.iter().all(|v| verify_sock_addr(v).is_ok()) {
+      let (unix_addresses, val): (Vec<_>, Vec<_>) = val.iter().partition(|v| is_unix_listen_addr(v));
+      let Ok(unix_listen_paths) = unix_addresses
+        .iter()
+        .map(parse_unix_listen_addr_str)
+        .collect::<anyhow::Result<Vec<_>>>()
+      else {
+        bail!("Invalid Unix domain socket listen address");
+      };
+      proxy_config.unix_listen_paths = unix_listen_paths;
+      let Ok(listen_addresses) = val.iter().map(parse_listen_addr_str).collect::<anyhow::Result<Vec<_>>>() else {
         bail!("Invalid listen address");
-      }
-      proxy_config.listen_addresses = val.iter().map(|x| x.parse().unwrap()).collect();
+      };
+      proxy_config.listen_addresses = listen_addresses
+        .iter()
+        .filter(|(proto, _)| *proto == ListenProto::Do53)
+        .map(|(_, addr)| *addr)
+        .collect();
+      proxy_config.dot_listen_addresses = listen_addresses
+        .iter()
+        .filter(|(proto, _)| *proto == ListenProto::Dot)
+        .map(|(_, addr)| *addr)
+        .collect();
+      proxy_config.doh_listen_addresses = listen_addresses
+        .iter()
+        .filter(|(proto, _)| *proto == ListenProto::Doh)
+        .map(|(_, addr)| *addr)
+        .collect();
+      proxy_config.doq_listen_addresses = listen_addresses
+        .iter()
+        .filter(|(proto, _)| *proto == ListenProto::Doq)
+        .map(|(_, addr)| *addr)
+        .collect();
     };
+    if proxy_config.listen_addresses.is_empty()
+      && proxy_config.dot_listen_addresses.is_empty()
+      && proxy_config.doh_listen_addresses.is_empty()
+      && proxy_config.doq_listen_addresses.is_empty()
+      && proxy_config.unix_listen_paths.is_empty()
+    {
+      bail!("At least one listen address must be specified");
+    }
+
+    /////////////////////////////
+    // tls for encrypted listeners
+    if !proxy_config.dot_listen_addresses.is_empty() {
+      if self.tls_config.is_none() {
+        bail!("[tls] certificate_path and private_key_path must be specified for DoT listen addresses");
+      }
+      info!("DoT listen addresses: {:?}", proxy_config.dot_listen_addresses);
+    }
+    if !proxy_config.doh_listen_addresses.is_empty() {
+      if self.tls_config.is_none() {
+        bail!("[tls] certificate_path and private_key_path must be specified for DoH listen addresses");
+      }
+      info!("DoH listen addresses: {:?}", proxy_config.doh_listen_addresses);
+    }
+    if !proxy_config.doq_listen_addresses.is_empty() {
+      if self.tls_config.is_none() {
+        bail!("[tls] certificate_path and private_key_path must be specified for DoQ listen addresses");
+      }
+      info!("DoQ listen addresses: {:?}", proxy_config.doq_listen_addresses);
+    }
+    proxy_config.tls_config.clone_from(&self.tls_config);
+
+    /////////////////////////////
+    // transparent mode for plaintext listeners
+    if let Some(true) = self.config_toml.transparent {
+      if !cfg!(target_os = "linux") {
+        bail!("Transparent mode is supported only on Linux");
+      }
+      proxy_config.transparent_mode = true;
+      info!("Transparent mode is enabled for plaintext UDP and TCP listen addresses");
+    }
+
+    /////////////////////////////
+    // unix domain socket listeners
+    if !proxy_config.unix_listen_paths.is_empty() {
+      info!("Unix domain socket listen paths: {:?}", proxy_config.unix_listen_paths);
+    }
+    if let Some(val) = &self.config_toml.unix_socket_mode {
+      let Ok(mode) = u32::from_str_radix(val.trim_start_matches("0o"), 8) else {
+        bail!("Invalid unix_socket_mode, which must be an octal number like \"0660\": {val}");
+      };
+      if mode > 0o777 {
+        bail!("Invalid unix_socket_mode, which must be at most 0777: {val}");
+      }
+      proxy_config.unix_socket_mode = Some(mode);
+      if !proxy_config.unix_listen_paths.is_empty() {
+        info!("Unix domain socket permissions: {:o}", mode);
+      }
+    }
+
+    /////////////////////////////
+    // doh server path
+    if let Some(val) = &self.config_toml.doh_server_path {
+      if !val.starts_with('/') || val.contains('?') {
+        bail!("Invalid DoH server path: {val}");
+      }
+      proxy_config.doh_server_path.clone_from(val);
+    }
+    if !proxy_config.doh_listen_addresses.is_empty() {
+      info!("DoH server path: {}", proxy_config.doh_server_path);
+    }
 
     /////////////////////////////
     // bootstrap dns
@@ -104,46 +231,140 @@ impl TryInto<ProxyConfig> for &TargetConfig {
       proxy_config.healthcheck_period_sec = Duration::from_secs((val as u64) * 60);
     }
     info!(
-      "Check for health of all possible path candidates and purge DNS cache every {:?} min",
+      "Check for health of every path candidate and purge DNS cache every {:?} min",
       proxy_config.healthcheck_period_sec.as_secs() / 60
     );
 
     /////////////////////////////
-    // cache size
-    if let Some(val) = self.config_toml.max_cache_size {
-      proxy_config.max_cache_size = val;
+    // health check concurrency
+    if let Some(val) = self.config_toml.healthcheck.as_ref().and_then(|v| v.max_concurrent_probes) {
+      if val == 0 {
+        bail!("max_concurrent_probes of healthcheck must be positive");
+      }
+      proxy_config.healthcheck_max_concurrency = val;
     }
-    info!("Max cache size: {} (entries)", proxy_config.max_cache_size);
+    info!(
+      "At most {} health check probes are executed concurrently",
+      proxy_config.healthcheck_max_concurrency
+    );
 
     /////////////////////////////
-    // DoH target and method
-    if let Some(val) = &self.config_toml.target_urls {
-      if !val.iter().all(|x| verify_target_url(x).is_ok()) {
-        bail!("Invalid target urls");
+    // behavior when all paths are unhealthy
+    proxy_config.max_all_unhealthy_retry = self
+      .config_toml
+      .healthcheck
+      .as_ref()
+      .and_then(|v| v.max_all_unhealthy_retries);
+    match proxy_config.max_all_unhealthy_retry {
+      Some(val) => info!(
+        "Shut down the proxy after all paths are found unhealthy {} consecutive times",
+        val + 1
+      ),
+      None => info!("Keep serving from cache or with SERVFAIL while all paths are unhealthy"),
+    }
+
+    /////////////////////////////
+    // health check probes
+    if let Some(probes) = self.config_toml.healthcheck.as_ref().and_then(|v| v.probes.as_ref()) {
+      let default_probe = HealthcheckProbe::default();
+      let mut healthcheck_probes = vec![];
+      for probe in probes {
+        let targets = probe.targets.clone().unwrap_or_default();
+        if !targets.iter().all(|x| verify_target_url(x).is_ok()) {
+          bail!("Invalid target urls of health check probe for {}", probe.name);
+        }
+        let healthcheck_probe = HealthcheckProbe {
+          name: probe.name.clone(),
+          record_type: probe.record_type.clone().unwrap_or(default_probe.record_type.clone()),
+          expected_rcode: probe.expected_rcode.clone().unwrap_or(default_probe.expected_rcode.clone()),
+          expected_answers: probe.expected_answers.clone(),
+          require_ad: probe.require_ad.unwrap_or(false),
+          target_urls: targets.iter().map(|v| url::Url::parse(v).unwrap()).collect(),
+        };
+        if let Err(e) = healthcheck_probe.validate() {
+          bail!("{e}");
+        }
+        healthcheck_probes.push(healthcheck_probe);
       }
-      proxy_config.target_config.doh_target_urls = val.iter().map(|v| url::Url::parse(v).unwrap()).collect();
+      if !healthcheck_probes.iter().any(|v| v.target_urls.is_empty()) {
+        bail!("At least one health check probe without targets is required");
+      }
+      proxy_config.healthcheck_probes = healthcheck_probes;
+    }
+    proxy_config.healthcheck_probes.iter().for_each(|probe| {
+      info!(
+        "Health check probe: {} {} expecting {}{}{}{}",
+        probe.name,
+        probe.record_type,
+        probe.expected_rcode,
+        probe
+          .expected_answers
+          .as_ref()
+          .map(|v| format!(" with {:?}", v))
+          .unwrap_or_default(),
+        if probe.require_ad { " and AD flag" } else { "" },
+        if probe.target_urls.is_empty() {
+          String::new()
+        } else {
+          format!(" for {:?}", probe.target_urls.iter().map(|v| v.as_str()).collect::<Vec<_>>())
+        }
+      );
+    });
+
+    /////////////////////////////
+    // retries of upstream query over other paths
+    if let Some(val) = self.config_toml.upstream_retries {
+      proxy_config.max_upstream_retries = val;
+    }
+    if let Some(val) = self.config_toml.upstream_retry_on_servfail {
+      proxy_config.retry_upstream_on_servfail = val;
     }
     info!(
-      "Target (O)DoH resolvers: {:?}",
-      proxy_config
-        .target_config
-        .doh_target_urls
-        .iter()
-        .map(|x| x.as_str())
-        .collect::<Vec<_>>()
+      "Failed upstream query{} is retried over other paths at most {} times",
+      if proxy_config.retry_upstream_on_servfail {
+        " or SERVFAIL response"
+      } else {
+        ""
+      },
+      proxy_config.max_upstream_retries
     );
-    if let Some(val) = &self.config_toml.target_randomization {
-      if !val {
-        proxy_config.target_config.target_randomization = false;
-        info!("Target randomization is disabled");
+
+    /////////////////////////////
+    // udp buffer size
+    if let Some(val) = self.config_toml.udp_buffer_size {
+      if !(512..=65535).contains(&val) {
+        bail!("udp_buffer_size must be between 512 and 65535");
       }
+      proxy_config.udp_buffer_size = val;
     }
-    if let Some(val) = self.config_toml.use_get_method {
-      if val {
-        proxy_config.target_config.use_get = true;
-        info!("Use GET method for query");
+    info!(
+      "UDP buffer size: {} bytes (responses over UDP are truncated to fit within it)",
+      proxy_config.udp_buffer_size
+    );
+
+    /////////////////////////////
+    // tcp idle timeout
+    if let Some(val) = self.config_toml.tcp_idle_timeout {
+      if val == 0 {
+        bail!("tcp_idle_timeout must be positive");
       }
+      proxy_config.tcp_idle_timeout_sec = Duration::from_secs(val);
+    }
+    info!(
+      "Idle timeout of persistent TCP, DoT and DoH connections: {:?} sec",
+      proxy_config.tcp_idle_timeout_sec.as_secs()
+    );
+
+    /////////////////////////////
+    // drain grace period on reload
+    if let Some(val) = self.config_toml.drain_grace_period {
+      proxy_config.drain_grace_period_sec = Duration::from_secs(val);
     }
+    info!(
+      "Grace period to drain in-flight queries on reload: {:?} sec",
+      proxy_config.drain_grace_period_sec.as_secs()
+    );
+
     /////////////////////////////
     // User agent
     if let Some(val) = &self.config_toml.user_agent {
@@ -151,138 +372,443 @@ impl TryInto<ProxyConfig> for &TargetConfig {
     }
 
     /////////////////////////////
-    // Anonymization
-    if let Some(anon) = &self.config_toml.anonymization {
-      /////////////////////////////
-      // odoh and next hop of modoh
-      if let Some(odoh_relay_urls) = &anon.odoh_relay_urls {
-        if !odoh_relay_urls.iter().all(|x| verify_target_url(x).is_ok()) {
-          bail!("Invalid ODoH relay urls");
+    // Upstream settings of the default profile
+    proxy_config.upstream = parse_upstream(&self.config_toml.upstream, &self.query_manipulation_config)?;
+
+    ////////////////////////
+    // Rate limit
+    if let Some(rate_limit) = &self.config_toml.rate_limit {
+      let mut rate_limit_config = RateLimitConfig::default();
+      if let Some(val) = rate_limit.queries_per_second {
+        if val == 0 {
+          bail!("queries_per_second must be positive");
+        }
+        rate_limit_config.queries_per_sec = val;
+        // burst defaults to twice the sustained rate unless specified
+        rate_limit_config.burst = val.saturating_mul(2);
+      }
+      if let Some(val) = rate_limit.burst {
+        if val == 0 {
+          bail!("burst must be positive");
         }
-        let mut nexthop_relay_config = NextHopRelayConfig {
-          odoh_relay_urls: odoh_relay_urls.iter().map(|v| url::Url::parse(v).unwrap()).collect(),
-          odoh_relay_randomization: true,
+        rate_limit_config.burst = val;
+      }
+      if let Some(val) = rate_limit.ipv4_prefix_len {
+        if val > 32 {
+          bail!("ipv4_prefix_len must be equal to or less than 32");
+        }
+        rate_limit_config.ipv4_prefix_len = val;
+      }
+      if let Some(val) = rate_limit.ipv6_prefix_len {
+        if val > 128 {
+          bail!("ipv6_prefix_len must be equal to or less than 128");
+        }
+        rate_limit_config.ipv6_prefix_len = val;
+      }
+      if let Some(val) = rate_limit.quota_period {
+        if val == 0 {
+          bail!("quota_period must be positive");
+        }
+        rate_limit_config.quota_period_sec = Duration::from_secs(val);
+      }
+      rate_limit_config.quota = rate_limit.quota;
+      if let Some(val) = rate_limit.allow_untracked {
+        rate_limit_config.allow_untracked = val;
+      }
+
+      info!(
+        "Rate limit per client (/{} for IPv4, /{} for IPv6): {} queries/sec with burst of {}",
+        rate_limit_config.ipv4_prefix_len,
+        rate_limit_config.ipv6_prefix_len,
+        rate_limit_config.queries_per_sec,
+        rate_limit_config.burst
+      );
+      if let Some(quota) = rate_limit_config.quota {
+        info!(
+          "Query quota per client: {} queries in every rolling {} sec",
+          quota,
+          rate_limit_config.quota_period_sec.as_secs()
+        );
+      }
+      if rate_limit_config.allow_untracked {
+        info!("Allow queries from new clients without rate limit while too many clients are tracked");
+      }
+      proxy_config.rate_limit_config = Some(rate_limit_config);
+    }
+
+    ////////////////////////
+    // Access control
+    if let Some(access_control) = &self.config_toml.access_control {
+      if access_control.allow.is_some() || access_control.deny.is_some() {
+        let config = parse_access_control(&access_control.allow, &access_control.deny)?;
+        info!(
+          "Access control for all listeners: allow {:?}, deny {:?}",
+          config.allow, config.deny
+        );
+        proxy_config.access_control_config = Some(config);
+      }
+      for listener in access_control.listeners.iter().flatten() {
+        let Ok((_, listen_addr)) = parse_listen_addr_str(&listener.listen_address) else {
+          bail!("Invalid listen address in access control: {}", listener.listen_address);
         };
-        info!("[ODoH] Oblivious DNS over HTTPS is enabled");
+        if !is_listening(&proxy_config, &listen_addr) {
+          bail!(
+            "Access control is given to {} that is not in listen_addresses",
+            listener.listen_address
+          );
+        }
+        let config = parse_access_control(&listener.allow, &listener.deny)?;
         info!(
-          "[ODoH] Nexthop relay URL: {:?}",
-          nexthop_relay_config
-            .odoh_relay_urls
-            .iter()
-            .map(|x| x.as_str())
-            .collect::<Vec<_>>()
+          "Access control for {}: allow {:?}, deny {:?}",
+          listen_addr, config.allow, config.deny
+        );
+        proxy_config.listener_access_control_configs.insert(listen_addr, config);
+      }
+      proxy_config.access_denied_action = match access_control.denied_action.as_deref() {
+        None | Some("drop") => AccessDeniedAction::Drop,
+        Some("refuse") => AccessDeniedAction::Refuse,
+        Some(v) => bail!("Invalid denied_action: {v} (must be \"drop\" or \"refuse\")"),
+      };
+      info!("Action for denied sources: {:?}", proxy_config.access_denied_action);
+    }
+
+    ////////////////////////
+    // PROXY protocol
+    if let Some(proxy_protocol) = &self.config_toml.proxy_protocol {
+      proxy_config.proxy_protocol_trusted_sources = parse_cidr_list(&proxy_protocol.trusted_sources, "proxy_protocol")?;
+      if !proxy_config.proxy_protocol_trusted_sources.is_empty() {
+        info!(
+          "PROXY protocol header is accepted on TCP listeners from: {:?}",
+          proxy_config.proxy_protocol_trusted_sources
+        );
+      }
+    }
+
+    ////////////////////////
+    // Hedged upstream requests
+    if let Some(hedging) = &self.config_toml.hedging {
+      let mut hedging_config = HedgingConfig::default();
+      if let Some(val) = hedging.delay_msec {
+        if val == 0 {
+          bail!("delay_msec of hedging must be positive");
+        }
+        hedging_config.delay = Duration::from_millis(val);
+      }
+      hedging_config.adaptive = hedging.adaptive.unwrap_or(false);
+      if hedging_config.adaptive {
+        info!(
+          "Hedged upstream requests are enabled with adaptive delay (p90 of recent latencies, initially {} msec)",
+          hedging_config.delay.as_millis()
+        );
+      } else {
+        info!(
+          "Hedged upstream requests are enabled with delay of {} msec",
+          hedging_config.delay.as_millis()
         );
+      }
+      proxy_config.hedging_config = Some(hedging_config);
+    }
 
-        if let Some(val) = anon.odoh_relay_randomization {
-          nexthop_relay_config.odoh_relay_randomization = val;
+    ////////////////////////
+    // Passive circuit breaker per path
+    if let Some(circuit_breaker) = &self.config_toml.circuit_breaker {
+      let mut breaker_config = CircuitBreakerConfig::default();
+      if let Some(val) = circuit_breaker.failure_threshold {
+        if val == 0 {
+          bail!("failure_threshold of circuit_breaker must be positive");
         }
-        if nexthop_relay_config.odoh_relay_randomization {
-          info!("ODoH relay randomization is enabled");
+        breaker_config.failure_threshold = val;
+      }
+      if let Some(val) = circuit_breaker.open_duration_sec {
+        if val == 0 {
+          bail!("open_duration_sec of circuit_breaker must be positive");
         }
-        proxy_config.nexthop_relay_config = Some(nexthop_relay_config);
+        breaker_config.open_duration = Duration::from_secs(val);
+      }
+      info!(
+        "Path is taken out for {} secs after {} consecutive failed queries",
+        breaker_config.open_duration.as_secs(),
+        breaker_config.failure_threshold
+      );
+      proxy_config.circuit_breaker_config = breaker_config;
+    }
 
-        /////////////////////////////
-        // modoh
-        if let Some(val) = &anon.mid_relay_urls {
-          if !val.iter().all(|x| verify_target_url(x).is_ok()) {
-            bail!("Invalid mid relay urls");
-          }
-          if val.is_empty() {
-            bail!("mid_relay_urls must specify at least one relay url");
-          }
-          if anon.max_mid_relays.is_some() && anon.max_mid_relays.unwrap_or(1) > val.len() {
-            bail!("max_mid_relays must be equal to or less than # of mid_relay_urls.");
+    ////////////////////////
+    // Upstream profiles
+    for profile in self.config_toml.profiles.iter().flatten() {
+      if profile.name == DEFAULT_UPSTREAM_PROFILE {
+        bail!("Upstream profile name \"{}\" is reserved", DEFAULT_UPSTREAM_PROFILE);
+      }
+      if proxy_config.upstream_profiles.contains_key(&profile.name) {
+        bail!("Duplicate upstream profile: {}", profile.name);
+      }
+      if profile.listen_addresses.is_empty() {
+        bail!(
+          "Upstream profile {} must be bound to at least one listen address",
+          profile.name
+        );
+      }
+      info!(
+        "Upstream profile \"{}\" for listen addresses: {:?}",
+        profile.name, profile.listen_addresses
+      );
+      for listen_address in &profile.listen_addresses {
+        let prev = if is_unix_listen_addr(listen_address) {
+          let Ok(path) = parse_unix_listen_addr_str(listen_address) else {
+            bail!("Invalid Unix domain socket listen address in profile: {listen_address}");
+          };
+          if !proxy_config.unix_listen_paths.contains(&path) {
+            bail!("Upstream profile is given to {listen_address} that is not in listen_addresses");
           }
-          let subseq_relay_config = SubseqRelayConfig {
-            mid_relay_urls: val.iter().map(|v| url::Url::parse(v).unwrap()).collect(),
-            max_mid_relays: anon.max_mid_relays.unwrap_or(1),
+          proxy_config.unix_listener_profiles.insert(path, profile.name.clone())
+        } else {
+          let Ok((_, listen_addr)) = parse_listen_addr_str(listen_address) else {
+            bail!("Invalid listen address in profile: {listen_address}");
           };
-
-          info!("[m-ODoH] Multiple-relay-based Oblivious DNS over HTTPS is enabled");
-          info!(
-            "[m-ODoH] Intermediate relay URLs employed after the next hop: {:?}",
-            subseq_relay_config
-              .mid_relay_urls
-              .iter()
-              .map(|x| x.as_str())
-              .collect::<Vec<_>>()
-          );
-          info!(
-            "[m-ODoH] Maximum number of intermediate relays after the nexthop: {}",
-            subseq_relay_config.max_mid_relays
-          );
-
-          proxy_config.subseq_relay_config = Some(subseq_relay_config);
+          if !is_listening(&proxy_config, &listen_addr) {
+            bail!("Upstream profile is given to {listen_address} that is not in listen_addresses");
+          }
+          proxy_config.listener_profiles.insert(listen_addr, profile.name.clone())
+        };
+        if prev.is_some() {
+          bail!("{listen_address} is bound to multiple upstream profiles");
         }
       }
+      let query_manipulation_config = self.profile_query_manipulation_configs.get(&profile.name).cloned();
+      let upstream = parse_upstream(&profile.upstream, &query_manipulation_config)?;
+      proxy_config.upstream_profiles.insert(profile.name.clone(), upstream);
     }
+    ////////////////////////
+
+    Ok(proxy_config)
+  }
+}
+
+/// Parse upstream settings, i.e., DoH targets, relays for anonymization, authentication, query manipulation plugins and cache size,
+/// which are given at the top level for the default profile and in each `[[profiles]]` entry.
+fn parse_upstream(
+  upstream: &Upstream,
+  query_manipulation_config: &Option<Arc<QueryManipulationConfig>>,
+) -> anyhow::Result<UpstreamProfile> {
+  let mut profile = UpstreamProfile::default();
+
+  /////////////////////////////
+  // cache size
+  if let Some(val) = upstream.max_cache_size {
+    profile.max_cache_size = val;
+  }
+  info!("Max cache size: {} (entries)", profile.max_cache_size);
 
+  /////////////////////////////
+  // DoH target and method
+  if let Some(val) = &upstream.target_urls {
+    if !val.iter().all(|x| verify_target_url(x).is_ok()) {
+      bail!("Invalid target urls");
+    }
+    profile.target_config.doh_target_urls = val.iter().map(|v| url::Url::parse(v).unwrap()).collect();
+  }
+  info!(
+    "Target (O)DoH resolvers: {:?}",
+    profile
+      .target_config
+      .doh_target_urls
+      .iter()
+      .map(|x| x.as_str())
+      .collect::<Vec<_>>()
+  );
+  if let Some(val) = &upstream.target_randomization {
+    if !val {
+      profile.target_config.target_randomization = false;
+      info!("Target randomization is disabled");
+    }
+  }
+  profile.target_config.path_selection = match upstream.path_selection.as_deref() {
+    None | Some("random") => PathSelection::Random,
+    Some("latency") => {
+      let randomness_percent = upstream
+        .path_selection_randomness
+        .unwrap_or(PATH_SELECTION_RANDOMNESS_PERCENT);
+      if randomness_percent > 100 {
+        bail!("path_selection_randomness must be a percentage in [0, 100]");
+      }
+      info!(
+        "Latency-weighted path selection is enabled, where {}% of queries go through a uniformly random path",
+        randomness_percent
+      );
+      PathSelection::LatencyWeighted { randomness_percent }
+    }
+    Some(v) => bail!("Invalid path_selection: {v} (must be \"random\" or \"latency\")"),
+  };
+  if let Some(val) = upstream.use_get_method {
+    if val {
+      profile.target_config.use_get = true;
+      info!("Use GET method for query");
+    }
+  }
+  /////////////////////////////
+  // Anonymization
+  if let Some(anon) = &upstream.anonymization {
     /////////////////////////////
-    // Authentication
-    // If credential exists, authorization header is also enabled.
-    if let Some(auth) = &self.config_toml.authentication {
-      if let (Some(credential_file), Some(token_api)) = (&auth.credential_file, &auth.token_api) {
-        let cred_path = env::current_dir()?.join(credential_file);
-        let env_vars = env_file_reader::read_file(cred_path.clone())?;
-        let Some(username) = env_vars.get(CREDENTIAL_USERNAME_FIELD) else {
-          bail!("No username is given in the credential file.");
-        };
-        let Some(password) = env_vars.get(CREDENTIAL_API_KEY_FIELD) else {
-          bail!("No password is given in the credential file.");
-        };
-        let Some(client_id) = env_vars.get(CREDENTIAL_CLIENT_ID_FIELD) else {
-          bail!("No client_id is given in the credential file.");
-        };
-        if verify_target_url(token_api).is_err() {
-          bail!("Invalid token api urls");
-        }
-        info!("Token API: {}", token_api);
+    // odoh and next hop of modoh
+    if let Some(odoh_relay_urls) = &anon.odoh_relay_urls {
+      if !odoh_relay_urls.iter().all(|x| verify_target_url(x).is_ok()) {
+        bail!("Invalid ODoH relay urls");
+      }
+      let mut nexthop_relay_config = NextHopRelayConfig {
+        odoh_relay_urls: odoh_relay_urls.iter().map(|v| url::Url::parse(v).unwrap()).collect(),
+        odoh_relay_randomization: true,
+      };
+      info!("[ODoH] Oblivious DNS over HTTPS is enabled");
+      info!(
+        "[ODoH] Nexthop relay URL: {:?}",
+        nexthop_relay_config
+          .odoh_relay_urls
+          .iter()
+          .map(|x| x.as_str())
+          .collect::<Vec<_>>()
+      );
 
-        let use_anonymous_token = auth.use_anonymous_token.unwrap_or(false);
-        if use_anonymous_token {
-          info!("Use anonymous token for the secure channel to the nexthop node");
-        } else {
-          info!("Use ID token for the secure channel to the nexthop node");
+      if let Some(val) = anon.odoh_relay_randomization {
+        nexthop_relay_config.odoh_relay_randomization = val;
+      }
+      if nexthop_relay_config.odoh_relay_randomization {
+        info!("ODoH relay randomization is enabled");
+      }
+      profile.nexthop_relay_config = Some(nexthop_relay_config);
+
+      /////////////////////////////
+      // modoh
+      if let Some(val) = &anon.mid_relay_urls {
+        if !val.iter().all(|x| verify_target_url(x).is_ok()) {
+          bail!("Invalid mid relay urls");
         }
-        let token_config = TokenConfig {
-          authentication_config: AuthenticationConfig {
-            username: username.to_string(),
-            password: password.to_string(),
-            client_id: Some(client_id.to_string()),
-            token_api: token_api.parse().unwrap(),
-          },
-          use_anonymous_token,
+        if val.is_empty() {
+          bail!("mid_relay_urls must specify at least one relay url");
+        }
+        if anon.max_mid_relays.is_some() && anon.max_mid_relays.unwrap_or(1) > val.len() {
+          bail!("max_mid_relays must be equal to or less than # of mid_relay_urls.");
+        }
+        let subseq_relay_config = SubseqRelayConfig {
+          mid_relay_urls: val.iter().map(|v| url::Url::parse(v).unwrap()).collect(),
+          max_mid_relays: anon.max_mid_relays.unwrap_or(1),
         };
-        proxy_config.token_config = Some(token_config);
+
+        info!("[m-ODoH] Multiple-relay-based Oblivious DNS over HTTPS is enabled");
+        info!(
+          "[m-ODoH] Intermediate relay URLs employed after the next hop: {:?}",
+          subseq_relay_config
+            .mid_relay_urls
+            .iter()
+            .map(|x| x.as_str())
+            .collect::<Vec<_>>()
+        );
+        info!(
+          "[m-ODoH] Maximum number of intermediate relays after the nexthop: {}",
+          subseq_relay_config.max_mid_relays
+        );
+
+        profile.subseq_relay_config = Some(subseq_relay_config);
       }
-    };
+    }
+  }
 
-    ////////////////////////
-    if proxy_config.token_config.is_some() {
-      if proxy_config.nexthop_relay_config.is_some() {
-        warn!("-----------------------------------");
-        warn!("[NOTE!!!!] Both credential and ODoH nexthop proxy is set up.");
-        warn!("[NOTE!!!!] This means the authorization token (ID or anonymous token) will be sent not to the target but to the proxy.");
-        warn!("[NOTE!!!!] Check if this is your intended behavior.");
-        warn!("-----------------------------------");
+  /////////////////////////////
+  // Authentication
+  // If credential exists, authorization header is also enabled.
+  if let Some(auth) = &upstream.authentication {
+    if let (Some(credential_file), Some(token_api)) = (&auth.credential_file, &auth.token_api) {
+      let cred_path = env::current_dir()?.join(credential_file);
+      let env_vars = env_file_reader::read_file(cred_path.clone())?;
+      let Some(username) = env_vars.get(CREDENTIAL_USERNAME_FIELD) else {
+        bail!("No username is given in the credential file.");
+      };
+      let Some(password) = env_vars.get(CREDENTIAL_API_KEY_FIELD) else {
+        bail!("No password is given in the credential file.");
+      };
+      let Some(client_id) = env_vars.get(CREDENTIAL_CLIENT_ID_FIELD) else {
+        bail!("No client_id is given in the credential file.");
+      };
+      if verify_target_url(token_api).is_err() {
+        bail!("Invalid token api urls");
+      }
+      info!("Token API: {}", token_api);
+
+      let use_anonymous_token = auth.use_anonymous_token.unwrap_or(false);
+      if use_anonymous_token {
+        info!("Use anonymous token for the secure channel to the nexthop node");
       } else {
-        warn!("-----------------------------------");
-        warn!("[NOTE!!!!] Authorization token (ID or anonymous token) will be sent to the target server!");
-        warn!("[NOTE!!!!] Check if this is your intended behavior.");
-        warn!("-----------------------------------");
+        info!("Use ID token for the secure channel to the nexthop node");
       }
+      let token_config = TokenConfig {
+        authentication_config: AuthenticationConfig {
+          username: username.to_string(),
+          password: password.to_string(),
+          client_id: Some(client_id.to_string()),
+          token_api: token_api.parse().unwrap(),
+        },
+        use_anonymous_token,
+      };
+      profile.token_config = Some(token_config);
     }
+  };
 
-    ////////////////////////
-    // Plugins
-    if self.config_toml.plugins.is_some() {
-      info!("Query manipulation plugins are enabled");
-      proxy_config
-        .query_manipulation_config
-        .clone_from(&self.query_manipulation_config);
+  ////////////////////////
+  if profile.token_config.is_some() {
+    if profile.nexthop_relay_config.is_some() {
+      warn!("-----------------------------------");
+      warn!("[NOTE!!!!] Both credential and ODoH nexthop proxy is set up.");
+      warn!(
+        "[NOTE!!!!] This means the authorization token (ID or anonymous token) will be sent not to the target but to the proxy."
+      );
+      warn!("[NOTE!!!!] Check if this is your intended behavior.");
+      warn!("-----------------------------------");
+    } else {
+      warn!("-----------------------------------");
+      warn!("[NOTE!!!!] Authorization token (ID or anonymous token) will be sent to the target server!");
+      warn!("[NOTE!!!!] Check if this is your intended behavior.");
+      warn!("-----------------------------------");
     }
-    ////////////////////////
+  }
 
-    Ok(proxy_config)
+  ////////////////////////
+  // Plugins
+  if upstream.plugins.is_some() {
+    info!("Query manipulation plugins are enabled");
+    profile.query_manipulation_config.clone_from(query_manipulation_config);
   }
+
+  Ok(profile)
+}
+
+/// Check if the address is one of the listen addresses except for Unix domain sockets
+fn is_listening(proxy_config: &ProxyConfig, listen_addr: &SocketAddr) -> bool {
+  [
+    &proxy_config.listen_addresses,
+    &proxy_config.dot_listen_addresses,
+    &proxy_config.doh_listen_addresses,
+    &proxy_config.doq_listen_addresses,
+  ]
+  .iter()
+  .any(|addrs| addrs.contains(listen_addr))
+}
+
+/// Parse allow and deny lists of CIDRs, where a bare IP address is treated as a single host
+fn parse_access_control(allow: &Option<Vec<String>>, deny: &Option<Vec<String>>) -> anyhow::Result<AccessControlConfig> {
+  Ok(AccessControlConfig {
+    allow: parse_cidr_list(allow, "access control")?,
+    deny: parse_cidr_list(deny, "access control")?,
+  })
+}
+
+/// Parse list of CIDRs, where a bare IP address is treated as a single host
+fn parse_cidr_list(list: &Option<Vec<String>>, section: &str) -> anyhow::Result<Vec<IpNet>> {
+  list
+    .iter()
+    .flatten()
+    .map(|v| {
+      v.parse::<IpNet>()
+        .or_else(|_| v.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| anyhow!("Invalid CIDR in {section}: {v}"))
+    })
+    .collect()
 }
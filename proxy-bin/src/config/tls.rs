@@ -0,0 +1,28 @@
+use super::toml::ConfigToml;
+use crate::error::*;
+use doh_auth_proxy_lib::TlsConfig;
+use std::{env, fs};
+
+/// Read TLS certificate chain and private key from paths specified in config toml
+impl TryFrom<&ConfigToml> for Option<TlsConfig> {
+  type Error = anyhow::Error;
+
+  fn try_from(value: &ConfigToml) -> Result<Self, Self::Error> {
+    let Some(tls) = &value.tls else {
+      return Ok(None);
+    };
+    let (Some(certificate_path), Some(private_key_path)) = (&tls.certificate_path, &tls.private_key_path) else {
+      bail!("Both certificate_path and private_key_path must be specified in [tls]");
+    };
+
+    let certificate_chain = fs::read_to_string(env::current_dir()?.join(certificate_path))
+      .map_err(|e| anyhow!("Failed to read TLS certificate chain: {e}"))?;
+    let private_key = fs::read_to_string(env::current_dir()?.join(private_key_path))
+      .map_err(|e| anyhow!("Failed to read TLS private key: {e}"))?;
+
+    Ok(Some(TlsConfig {
+      certificate_chain,
+      private_key,
+    }))
+  }
+}
@@ -5,25 +5,125 @@ use std::fs;
 #[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone)]
 pub struct ConfigToml {
   pub listen_addresses: Option<Vec<String>>,
+  pub doh_server_path: Option<String>,
+  pub unix_socket_mode: Option<String>,
+  pub transparent: Option<bool>,
   pub bootstrap_dns: Option<Vec<String>>,
   pub endpoint_resolution_period: Option<usize>,
   pub healthcheck_period: Option<usize>,
+  pub upstream_retries: Option<usize>,
+  pub upstream_retry_on_servfail: Option<bool>,
+  pub healthcheck: Option<Healthcheck>,
+  pub udp_buffer_size: Option<usize>,
+  pub tcp_idle_timeout: Option<u64>,
+  pub drain_grace_period: Option<u64>,
+  pub user_agent: Option<String>,
+  pub tls: Option<Tls>,
+  pub rate_limit: Option<RateLimit>,
+  pub access_control: Option<AccessControl>,
+  pub proxy_protocol: Option<ProxyProtocol>,
+  pub hedging: Option<Hedging>,
+  pub circuit_breaker: Option<CircuitBreaker>,
+  #[serde(flatten)]
+  pub upstream: Upstream,
+  pub profiles: Option<Vec<Profile>>,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+/// Upstream settings given at the top level for the default profile, and in each named profile
+pub struct Upstream {
   pub max_cache_size: Option<usize>,
   pub target_urls: Option<Vec<String>>,
   pub target_randomization: Option<bool>,
+  pub path_selection: Option<String>,
+  pub path_selection_randomness: Option<u8>,
   pub use_get_method: Option<bool>,
-  pub user_agent: Option<String>,
   pub authentication: Option<Authentication>,
   pub anonymization: Option<Anonymization>,
   pub plugins: Option<Plugins>,
 }
 
+#[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+/// Named upstream profile bound to the listen addresses
+pub struct Profile {
+  pub name: String,
+  pub listen_addresses: Vec<String>,
+  #[serde(flatten)]
+  pub upstream: Upstream,
+}
+
 #[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone)]
 pub struct Plugins {
   pub domains_blocked_file: Option<String>,
   pub domains_overridden_file: Option<String>,
 }
 
+#[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub struct Tls {
+  pub certificate_path: Option<String>,
+  pub private_key_path: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub struct RateLimit {
+  pub queries_per_second: Option<u32>,
+  pub burst: Option<u32>,
+  pub ipv4_prefix_len: Option<u8>,
+  pub ipv6_prefix_len: Option<u8>,
+  pub quota: Option<u64>,
+  pub quota_period: Option<u64>,
+  pub allow_untracked: Option<bool>,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub struct AccessControl {
+  pub allow: Option<Vec<String>>,
+  pub deny: Option<Vec<String>>,
+  pub denied_action: Option<String>,
+  pub listeners: Option<Vec<ListenerAccessControl>>,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub struct ListenerAccessControl {
+  pub listen_address: String,
+  pub allow: Option<Vec<String>>,
+  pub deny: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub struct ProxyProtocol {
+  pub trusted_sources: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub struct Healthcheck {
+  pub max_concurrent_probes: Option<usize>,
+  pub max_all_unhealthy_retries: Option<usize>,
+  pub probes: Option<Vec<HealthcheckProbe>>,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub struct HealthcheckProbe {
+  pub name: String,
+  pub record_type: Option<String>,
+  pub expected_rcode: Option<String>,
+  pub expected_answers: Option<Vec<String>>,
+  pub require_ad: Option<bool>,
+  pub targets: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub struct Hedging {
+  pub delay_msec: Option<u64>,
+  pub adaptive: Option<bool>,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub struct CircuitBreaker {
+  pub failure_threshold: Option<u32>,
+  pub open_duration_sec: Option<u64>,
+}
+
 #[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone)]
 pub struct Anonymization {
   pub odoh_relay_urls: Option<Vec<String>>,
@@ -45,3 +145,39 @@ impl ConfigToml {
     toml::from_str(&config_str).map_err(|e| anyhow!(e))
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn deserialize_profiles() {
+    let config_toml: ConfigToml = toml::from_str(
+      r#"
+listen_addresses = ['127.0.0.1:50053', '127.0.0.1:50054']
+target_urls = ["https://dns.google/dns-query"]
+max_cache_size = 1024
+
+[[profiles]]
+name = "private"
+listen_addresses = ['127.0.0.1:50054']
+target_urls = ["https://odoh.cloudflare-dns.com/dns-query"]
+[profiles.anonymization]
+odoh_relay_urls = ["https://odoh-relay.example.com/proxy"]
+"#,
+    )
+    .unwrap();
+    assert_eq!(config_toml.upstream.max_cache_size, Some(1024));
+    assert_eq!(
+      config_toml.upstream.target_urls,
+      Some(vec!["https://dns.google/dns-query".to_string()])
+    );
+
+    let profiles = config_toml.profiles.unwrap();
+    assert_eq!(profiles.len(), 1);
+    assert_eq!(profiles[0].name, "private");
+    assert_eq!(profiles[0].listen_addresses, vec!["127.0.0.1:50054".to_string()]);
+    assert_eq!(profiles[0].upstream.max_cache_size, None);
+    assert!(profiles[0].upstream.anonymization.is_some());
+  }
+}
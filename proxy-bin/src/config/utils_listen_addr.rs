@@ -0,0 +1,147 @@
+use super::utils_verifier::verify_sock_addr;
+use std::{
+  net::{IpAddr, SocketAddr},
+  path::PathBuf,
+};
+
+const PREFIX_TLS: &str = "tls://";
+const PREFIX_HTTPS: &str = "https://";
+const PREFIX_QUIC: &str = "quic://";
+const PREFIX_UNIX: &str = "unix:";
+const DEFAULT_DOT_PORT: u16 = 853;
+const DEFAULT_DOH_PORT: u16 = 443;
+const DEFAULT_DOQ_PORT: u16 = 853;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Listener protocol specified by the scheme of a listen address
+pub(crate) enum ListenProto {
+  /// Plaintext DNS over UDP and TCP
+  Do53,
+  /// DNS over TLS
+  Dot,
+  /// DNS over HTTPS
+  Doh,
+  /// DNS over QUIC
+  Doq,
+}
+
+/// Parse as string in the form of "<scheme>://<ip_addr>:<port>", where "<scheme>://" can be omitted and then it will be treated as plaintext DNS over UDP and TCP.
+/// - <scheme>: "tls" for DNS over TLS, "https" for DNS over HTTPS, "quic" for DNS over QUIC.
+/// - <ip_addr>: IPv4 or IPv6 address, where IPv6 address must be enclosed in square brackets like "[::1]"
+/// - <port>: port number, which must be explicitly specified for plaintext DNS. For encrypted DNS, ":<port>" can be omitted and then it will be treated as ":853" for TLS and QUIC, and ":443" for HTTPS.
+pub(crate) fn parse_listen_addr_str<T: AsRef<str>>(val: T) -> anyhow::Result<(ListenProto, SocketAddr)> {
+  let val = val.as_ref();
+
+  if let Some(val_rest) = val.strip_prefix(PREFIX_TLS) {
+    return Ok((
+      ListenProto::Dot,
+      parse_sockaddr_with_default_port(val_rest, DEFAULT_DOT_PORT)?,
+    ));
+  }
+  if let Some(val_rest) = val.strip_prefix(PREFIX_HTTPS) {
+    return Ok((
+      ListenProto::Doh,
+      parse_sockaddr_with_default_port(val_rest, DEFAULT_DOH_PORT)?,
+    ));
+  }
+  if let Some(val_rest) = val.strip_prefix(PREFIX_QUIC) {
+    return Ok((
+      ListenProto::Doq,
+      parse_sockaddr_with_default_port(val_rest, DEFAULT_DOQ_PORT)?,
+    ));
+  }
+
+  verify_sock_addr(val).map_err(|e| anyhow::anyhow!(e))?;
+  Ok((ListenProto::Do53, val.parse()?))
+}
+
+/// Check if the listen address is a Unix domain socket path in the form of "unix:<path>"
+pub(crate) fn is_unix_listen_addr<T: AsRef<str>>(val: T) -> bool {
+  val.as_ref().starts_with(PREFIX_UNIX)
+}
+
+/// Parse as string in the form of "unix:<path>" or "unix://<path>", and return the path of Unix domain socket.
+pub(crate) fn parse_unix_listen_addr_str<T: AsRef<str>>(val: T) -> anyhow::Result<PathBuf> {
+  let Some(val_rest) = val.as_ref().strip_prefix(PREFIX_UNIX) else {
+    anyhow::bail!("Not a Unix domain socket address: {}", val.as_ref());
+  };
+  let path = val_rest.strip_prefix("//").unwrap_or(val_rest);
+  if path.is_empty() {
+    anyhow::bail!("Empty Unix domain socket path");
+  }
+  Ok(PathBuf::from(path))
+}
+
+/// Parse socket address, where the port number can be omitted and then it will be treated as the given default port.
+fn parse_sockaddr_with_default_port(val: &str, default_port: u16) -> anyhow::Result<SocketAddr> {
+  if let Ok(socket_addr) = val.parse::<SocketAddr>() {
+    return Ok(socket_addr);
+  }
+  let ip_part = val
+    .strip_prefix('[')
+    .and_then(|v| v.strip_suffix(']'))
+    .unwrap_or(val)
+    .parse::<IpAddr>()?;
+  Ok(SocketAddr::new(ip_part, default_port))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::net::Ipv6Addr;
+
+  #[test]
+  fn test_parse_listen_addr_str() {
+    let (proto, socket_addr) = parse_listen_addr_str("127.0.0.1:50053").unwrap();
+    assert_eq!(proto, ListenProto::Do53);
+    assert_eq!(socket_addr, SocketAddr::from(([127, 0, 0, 1], 50053)));
+
+    let (proto, socket_addr) = parse_listen_addr_str("[::1]:50053").unwrap();
+    assert_eq!(proto, ListenProto::Do53);
+    assert_eq!(socket_addr, SocketAddr::from((Ipv6Addr::LOCALHOST, 50053)));
+
+    assert!(parse_listen_addr_str("127.0.0.1").is_err());
+
+    let (proto, socket_addr) = parse_listen_addr_str("tls://0.0.0.0:8853").unwrap();
+    assert_eq!(proto, ListenProto::Dot);
+    assert_eq!(socket_addr, SocketAddr::from(([0, 0, 0, 0], 8853)));
+
+    let (proto, socket_addr) = parse_listen_addr_str("tls://0.0.0.0").unwrap();
+    assert_eq!(proto, ListenProto::Dot);
+    assert_eq!(socket_addr, SocketAddr::from(([0, 0, 0, 0], 853)));
+
+    let (proto, socket_addr) = parse_listen_addr_str("tls://[::1]").unwrap();
+    assert_eq!(proto, ListenProto::Dot);
+    assert_eq!(socket_addr, SocketAddr::from((Ipv6Addr::LOCALHOST, 853)));
+
+    assert!(parse_listen_addr_str("tls://example.com").is_err());
+
+    let (proto, socket_addr) = parse_listen_addr_str("https://127.0.0.1:8443").unwrap();
+    assert_eq!(proto, ListenProto::Doh);
+    assert_eq!(socket_addr, SocketAddr::from(([127, 0, 0, 1], 8443)));
+
+    let (proto, socket_addr) = parse_listen_addr_str("https://[::1]").unwrap();
+    assert_eq!(proto, ListenProto::Doh);
+    assert_eq!(socket_addr, SocketAddr::from((Ipv6Addr::LOCALHOST, 443)));
+
+    let (proto, socket_addr) = parse_listen_addr_str("quic://0.0.0.0").unwrap();
+    assert_eq!(proto, ListenProto::Doq);
+    assert_eq!(socket_addr, SocketAddr::from(([0, 0, 0, 0], 853)));
+  }
+
+  #[test]
+  fn test_parse_unix_listen_addr_str() {
+    assert!(is_unix_listen_addr("unix:/run/doh-auth-proxy.sock"));
+    assert!(!is_unix_listen_addr("127.0.0.1:50053"));
+
+    let path = parse_unix_listen_addr_str("unix:/run/doh-auth-proxy.sock").unwrap();
+    assert_eq!(path, PathBuf::from("/run/doh-auth-proxy.sock"));
+    let path = parse_unix_listen_addr_str("unix:///run/doh-auth-proxy.sock").unwrap();
+    assert_eq!(path, PathBuf::from("/run/doh-auth-proxy.sock"));
+    let path = parse_unix_listen_addr_str("unix:proxy.sock").unwrap();
+    assert_eq!(path, PathBuf::from("proxy.sock"));
+
+    assert!(parse_unix_listen_addr_str("unix:").is_err());
+    assert!(parse_unix_listen_addr_str("tls://0.0.0.0").is_err());
+  }
+}
@@ -1,4 +1,6 @@
 pub const CONFIG_WATCH_DELAY_SECS: u32 = 30;
+/// Extra time to wait for the proxy service to finish beyond its drain grace period on reload
+pub const DRAIN_WAIT_MARGIN_SECS: u64 = 5;
 
 pub const CREDENTIAL_USERNAME_FIELD: &str = "username";
 pub const CREDENTIAL_API_KEY_FIELD: &str = "password";
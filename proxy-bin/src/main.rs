@@ -8,11 +8,12 @@ mod log;
 
 use crate::{
   config::{parse_opts, ConfigReloader, TargetConfig},
-  constants::CONFIG_WATCH_DELAY_SECS,
+  constants::{CONFIG_WATCH_DELAY_SECS, DRAIN_WAIT_MARGIN_SECS},
   log::*,
 };
 use doh_auth_proxy_lib::{entrypoint, ProxyConfig};
 use hot_reload::{ReloaderReceiver, ReloaderService};
+use std::time::Duration;
 
 fn main() {
   let mut runtime_builder = tokio::runtime::Builder::new_multi_thread();
@@ -102,31 +103,50 @@ async fn proxy_service_with_watcher(
 
   // Continuous monitoring
   loop {
-    tokio::select! {
-      res = entrypoint(&proxy_conf, &runtime_handle, Some(term_notify.clone())) => {
-        error!("proxy entrypoint exited: {}", if res.is_err() { res.unwrap_err().to_string() } else { "".to_string() });
-        break;
-      }
-      _ = config_rx.changed() => {
-        if config_rx.borrow().is_none() {
-          error!("Something wrong in config reloader receiver");
-          break;
-        }
-        let config_toml = config_rx.borrow().clone().unwrap();
-        match (&config_toml).try_into() as Result<ProxyConfig, anyhow::Error> {
-          Ok(p) => {
-            proxy_conf = p
-          },
-          Err(e) => {
-            error!("Invalid configuration. Configuration does not updated: {e}");
-            continue;
+    // the proxy service borrowing the current configuration is dropped before the configuration is replaced
+    let updated_conf = {
+      let proxy_service = entrypoint(&proxy_conf, &runtime_handle, Some(term_notify.clone()));
+      tokio::pin!(proxy_service);
+
+      // wait for a valid configuration update while the proxy service is running
+      let updated_conf = loop {
+        tokio::select! {
+          res = &mut proxy_service => {
+            error!("proxy entrypoint exited: {}", if res.is_err() { res.unwrap_err().to_string() } else { "".to_string() });
+            break None;
+          }
+          res = config_rx.changed() => {
+            if res.is_err() || config_rx.borrow().is_none() {
+              error!("Something wrong in config reloader receiver");
+              break None;
+            }
+            let config_toml = config_rx.borrow().clone().unwrap();
+            match (&config_toml).try_into() as Result<ProxyConfig, anyhow::Error> {
+              Ok(p) => break Some(p),
+              Err(e) => {
+                error!("Invalid configuration. Configuration does not updated: {e}");
+                continue;
+              }
+            };
           }
-        };
-        info!("Configuration updated. Terminate all spawned proxy services and force to re-bind TCP/UDP sockets");
-        term_notify.notify_waiters();
+        }
+      };
+      let Some(updated_conf) = updated_conf else {
+        break;
+      };
+
+      info!("Configuration updated. Terminate all spawned proxy services, drain in-flight queries and force to re-bind TCP/UDP sockets");
+      term_notify.notify_waiters();
+      // the proxy service returns after draining in-flight queries within the grace period
+      let wait_period = proxy_conf.drain_grace_period_sec + Duration::from_secs(DRAIN_WAIT_MARGIN_SECS);
+      match tokio::time::timeout(wait_period, &mut proxy_service).await {
+        Ok(Err(e)) => warn!("proxy entrypoint exited with error on reload: {e}"),
+        Err(_) => warn!("proxy entrypoint did not finish draining in time, and is forcibly dropped"),
+        Ok(Ok(_)) => (),
       }
-      else => break
-    }
+      updated_conf
+    };
+    proxy_conf = updated_conf;
   }
 
   Err(anyhow::anyhow!("proxy or continuous monitoring service exited"))
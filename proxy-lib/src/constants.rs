@@ -3,14 +3,34 @@
 ////////////////////////////////
 // Cannot override by config.toml
 
-/// UDP buffer size TODO: めちゃ適当
-pub const UDP_BUFFER_SIZE: usize = 2048;
+/// Max number of clients tracked by the rate limiter, over which inactive clients are purged
+pub const RATE_LIMIT_MAX_TRACKED_CLIENTS: usize = 65536;
+/// Min interval in secs of purging inactive clients from the rate limiter while the max number of clients are tracked
+pub const RATE_LIMIT_PURGE_INTERVAL_SEC: u64 = 5;
+/// Interval in secs of logging the numbers of queries and connections denied by the access control
+pub const ACCESS_CONTROL_STATS_LOG_INTERVAL_SEC: u64 = 300;
+/// Buffer size for ancillary data (control messages) of received UDP datagrams
+pub const UDP_CMSG_BUFFER_SIZE: usize = 128;
+/// Max number of sockets bound to original destinations kept to send responses in transparent mode
+pub const UDP_REPLY_SOCKET_CACHE_SIZE: usize = 256;
+/// Max UDP payload size for clients without EDNS (RFC 1035)
+pub const MIN_UDP_PAYLOAD_SIZE: usize = 512;
 /// UDP channel Capacity TODO: めちゃ適当
 pub const UDP_CHANNEL_CAPACITY: usize = 1024; // TODO: channelキャパシティめちゃ適当
 /// UDP timeout in secs
 pub const UDP_TIMEOUT_SEC: u64 = 10;
 /// TCP listen backlog
 pub const TCP_LISTEN_BACKLOG: u32 = 1024;
+/// Capacity of the channel for sending out responses to pipelined queries over a single stream connection
+pub const TCP_PIPELINE_CHANNEL_CAPACITY: usize = 64;
+/// TLS handshake timeout in secs for encrypted listeners
+pub const TLS_HANDSHAKE_TIMEOUT_SEC: u64 = 10;
+/// Timeout in secs to receive PROXY protocol header from trusted load balancers
+pub const PROXY_PROTOCOL_HEADER_TIMEOUT_SEC: u64 = 5;
+/// Interval in msecs to check if in-flight queries are drained on termination
+pub const DRAIN_CHECK_INTERVAL_MSEC: u64 = 100;
+/// Name of the upstream profile given by the top-level upstream settings, which is reserved and cannot be used for named profiles
+pub const DEFAULT_UPSTREAM_PROFILE: &str = "default";
 
 /// Max connections via UPD and TCP (total) TODO: めちゃ適当
 pub const MAX_CONNECTIONS: usize = 128;
@@ -27,6 +47,18 @@ pub const MIN_TTL: u32 = 10;
 /// Default listen address
 pub const LISTEN_ADDRESSES: &[&str] = &["127.0.0.1:50053", "[::1]:50053"];
 
+/// Default UDP buffer size, which also limits the UDP payload size of responses
+pub const UDP_BUFFER_SIZE: usize = 2048;
+
+/// Default idle timeout in secs for persistent TCP, DoT and DoH connections
+pub const TCP_IDLE_TIMEOUT_SEC: u64 = 10;
+
+/// Default grace period in secs to drain in-flight queries on termination
+pub const DRAIN_GRACE_PERIOD_SEC: u64 = 5;
+
+/// Default path of DoH server
+pub const DOH_SERVER_PATH: &str = "/dns-query";
+
 /// Bootstrap DNS socket address
 pub const BOOTSTRAP_DNS_ADDRS: &[&str] = &["1.1.1.1:53"];
 /// Bootstrap DNS proto
@@ -40,11 +72,11 @@ pub const ENDPOINT_RESOLUTION_PERIOD_MIN: u64 = 60;
 /// Health check: Check for health of paths and purge cache for every 600 secs
 pub const HEALTHCHECK_PERIOD_MIN: u64 = 10;
 
-/// Health check: Waiting time for health check retry when all possible paths are unhealthy
+/// Health check: Interval to watch if all possible paths are unhealthy
 pub const HEALTHCHECK_RETRY_WAITING_SEC: u64 = 10;
 
-/// Max retry for health check when all possible paths are unhealthy
-pub const MAX_ALL_UNHEALTHY_RETRY: usize = 5;
+/// Health check: Max number of probes executed concurrently
+pub const HEALTHCHECK_MAX_CONCURRENT_PROBES: usize = 8;
 
 /// Default DoH target server
 pub const DOH_TARGET_URL: &[&str] = &["https://dns.google/dns-query"];
@@ -52,6 +84,40 @@ pub const DOH_TARGET_URL: &[&str] = &["https://dns.google/dns-query"];
 /// Max cache size of DNS response messages
 pub const MAX_CACHE_SIZE: usize = 16384;
 
+/// Rate limit: Sustained rate of queries per second for each client
+pub const RATE_LIMIT_QUERIES_PER_SEC: u32 = 20;
+/// Rate limit: Max burst of queries for each client
+pub const RATE_LIMIT_BURST: u32 = 40;
+/// Rate limit: Prefix length to aggregate IPv4 clients (i.e., per address)
+pub const RATE_LIMIT_IPV4_PREFIX_LEN: u8 = 32;
+/// Rate limit: Prefix length to aggregate IPv6 clients (i.e., per /64 subnet)
+pub const RATE_LIMIT_IPV6_PREFIX_LEN: u8 = 64;
+/// Rate limit: Rolling period of query quota in secs (1 day)
+pub const RATE_LIMIT_QUOTA_PERIOD_SEC: u64 = 86400;
+
+/// Health check: Query name of the default probe
+pub const HEALTHCHECK_TARGET_FQDN: &str = "dns.google.";
+/// Health check: Record type of the default probe
+pub const HEALTHCHECK_TARGET_RECORD_TYPE: &str = "A";
+/// Health check: Expected response code of the default probe
+pub const HEALTHCHECK_TARGET_RCODE: &str = "NOERROR";
+/// Health check: IP address that must be in the answers of the default probe
+pub const HEALTHCHECK_TARGET_ADDR: &str = "8.8.8.8";
+
+/// Max number of retries over other paths when an upstream query fails, or is answered with SERVFAIL if enabled
+pub const MAX_UPSTREAM_RETRIES: usize = 2;
+
+/// Hedging: Delay in msecs before sending the same query over another path, which is the initial one in the adaptive mode
+pub const HEDGING_DELAY_MSEC: u64 = 200;
+
+/// Path selection: Percentage of queries sent over a uniformly random path in the latency-weighted selection
+pub const PATH_SELECTION_RANDOMNESS_PERCENT: u8 = 20;
+
+/// Circuit breaker: Number of consecutive failed queries over a path to take it out
+pub const CIRCUIT_BREAKER_FAILURE_THRESHOLD: u32 = 3;
+/// Circuit breaker: Duration in secs for which the path is taken out before a probe query
+pub const CIRCUIT_BREAKER_OPEN_DURATION_SEC: u64 = 30;
+
 ///////////////////////////////
 // Constant Values for Proxy //
 ///////////////////////////////
@@ -85,11 +151,58 @@ pub const MAX_RELOGIN_ATTEMPTS: usize = 5;
 pub const BLIND_JWKS_ENDPOINT_WATCH_DELAY_SEC: u64 = 60 * 5;
 
 // Health check
-
-/// Health check target FQDN
-pub const HEALTHCHECK_TARGET_FQDN: &str = "dns.google.";
-/// Health check target IP address for assertion
-pub const HEALTHCHECK_TARGET_ADDR: &str = "8.8.8.8";
+/// Percentage of random jitter added to the health check interval of each path
+pub const HEALTHCHECK_JITTER_PERCENT: u32 = 10;
+/// Interval in secs to re-probe a path right after its first failure, which is doubled for every consecutive failure up to the health check period
+pub const HEALTHCHECK_FAILURE_RETRY_SEC: u64 = 5;
+
+// Service supervisor
+/// Initial backoff in secs to restart a failed background service, which is doubled for every consecutive failure
+pub const SUPERVISOR_RESTART_BACKOFF_MIN_SEC: u64 = 1;
+/// Max backoff in secs to restart a failed background service, which is also the uptime to regard it as recovered
+pub const SUPERVISOR_RESTART_BACKOFF_MAX_SEC: u64 = 60;
+
+// Hedging
+/// Number of recent upstream latencies kept to derive the adaptive hedging delay
+pub const HEDGING_LATENCY_WINDOW: usize = 128;
+/// Minimum number of latency samples to derive the adaptive hedging delay, under which the initial delay is used
+pub const HEDGING_ADAPTIVE_MIN_SAMPLES: usize = 16;
+/// Percentile of recent upstream latencies used as the adaptive hedging delay
+pub const HEDGING_ADAPTIVE_PERCENTILE: usize = 90;
+
+// Path selection
+/// Smoothing factor of the EWMA of latency and error rate per path
+pub const PATH_STATS_EWMA_ALPHA: f64 = 0.2;
+/// Penalty factor of the error rate in the weight of a path, i.e., weight = 1 / (latency * (1 + penalty * error_rate))
+pub const PATH_STATS_ERROR_PENALTY: f64 = 10.0;
+
+// PROXY protocol
+/// Signature of PROXY protocol v2 header
+pub const PROXY_PROTOCOL_V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Max length of PROXY protocol v1 header including CRLF
+pub const PROXY_PROTOCOL_V1_MAX_LENGTH: usize = 107;
+
+// Socket activation
+/// First file descriptor passed via systemd socket activation (sd_listen_fds(3))
+pub const SD_LISTEN_FDS_START: i32 = 3;
+
+// Encrypted listeners
+
+/// ALPN protocol id for DNS over TLS
+pub const DOT_ALPN: &[u8] = b"dot";
+/// ALPN protocol ids for DoH server in the order of preference
+pub const DOH_ALPN: &[&[u8]] = &[b"h2", b"http/1.1"];
+/// Content type of DoH request and response
+pub const DOH_CONTENT_TYPE: &str = "application/dns-message";
+/// Max size of DNS message accepted by DoH server
+pub const DOH_MAX_MESSAGE_SIZE: usize = 65535;
+/// ALPN protocol id for DNS over QUIC
+pub const DOQ_ALPN: &[u8] = b"doq";
+/// DoQ error codes (RFC 9250 Section 4.3)
+pub const DOQ_NO_ERROR: u32 = 0x0;
+pub const DOQ_INTERNAL_ERROR: u32 = 0x1;
+pub const DOQ_PROTOCOL_ERROR: u32 = 0x2;
+pub const DOQ_EXCESSIVE_LOAD: u32 = 0x4;
 
 // Query manipulation
 /// Block message for query manipulation (HINFO CPU field)
@@ -61,9 +61,7 @@ impl CacheObject {
       record.set_ttl(remained_ttl);
       cached_msg.add_name_server(record);
     }
-    cached_msg.set_id(query_id);
-
-    dns_message::encode(&cached_msg)
+    dns_message::encode_with_id(&mut cached_msg, query_id)
   }
 }
 
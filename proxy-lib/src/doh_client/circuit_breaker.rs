@@ -0,0 +1,142 @@
+use crate::{globals::CircuitBreakerConfig, log::*};
+use std::{
+  sync::Mutex,
+  time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// State of the circuit breaker
+enum BreakerState {
+  /// Path is used for queries, with the number of consecutive failures
+  Closed(u32),
+  /// Path is taken out of the path selection until the instant
+  Open(Instant),
+  /// A probe query is in flight over the path since the instant, and other queries do not use the path
+  HalfOpen(Instant),
+}
+
+/// Transition of the circuit breaker caused by a query result
+pub(super) enum BreakerTransition {
+  /// Circuit is opened, i.e., the path is taken out of the path selection
+  Opened,
+  /// Circuit is closed by a successful probe, i.e., the path is put back
+  Closed,
+}
+
+/// Passive circuit breaker per path fed by the results of queries over the path
+pub(super) struct CircuitBreaker {
+  /// number of consecutive failures to open the circuit
+  failure_threshold: u32,
+  /// duration for which the open circuit takes the path out before a probe
+  open_duration: Duration,
+  /// current state
+  state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+  /// Create a new closed circuit breaker
+  pub(super) fn new(config: &CircuitBreakerConfig) -> Self {
+    Self {
+      failure_threshold: config.failure_threshold,
+      open_duration: config.open_duration,
+      state: Mutex::new(BreakerState::Closed(0)),
+    }
+  }
+
+  /// Check if the path can be chosen for a query.
+  /// An open circuit becomes available after the open duration for a probe,
+  /// and a half-open one becomes available again if its probe has not finished within the open duration, e.g., cancelled.
+  pub(super) fn is_available(&self) -> bool {
+    let Ok(state) = self.state.lock() else {
+      error!("Failed to lock circuit breaker state");
+      return true;
+    };
+    match *state {
+      BreakerState::Closed(_) => true,
+      BreakerState::Open(until) => Instant::now() >= until,
+      BreakerState::HalfOpen(since) => since.elapsed() >= self.open_duration,
+    }
+  }
+
+  /// Notify that the path is chosen for a query, which becomes the probe if the circuit is not closed
+  pub(super) fn on_chosen(&self) {
+    let Ok(mut state) = self.state.lock() else {
+      error!("Failed to lock circuit breaker state");
+      return;
+    };
+    if !matches!(*state, BreakerState::Closed(_)) {
+      *state = BreakerState::HalfOpen(Instant::now());
+    }
+  }
+
+  /// Record a successful query over the path
+  pub(super) fn record_success(&self) -> Option<BreakerTransition> {
+    let Ok(mut state) = self.state.lock() else {
+      error!("Failed to lock circuit breaker state");
+      return None;
+    };
+    let was_closed = matches!(*state, BreakerState::Closed(_));
+    *state = BreakerState::Closed(0);
+    (!was_closed).then_some(BreakerTransition::Closed)
+  }
+
+  /// Record a failed query over the path
+  pub(super) fn record_failure(&self) -> Option<BreakerTransition> {
+    let Ok(mut state) = self.state.lock() else {
+      error!("Failed to lock circuit breaker state");
+      return None;
+    };
+    let open = BreakerState::Open(Instant::now() + self.open_duration);
+    match *state {
+      BreakerState::Closed(failures) => {
+        let failures = failures + 1;
+        if failures >= self.failure_threshold {
+          *state = open;
+          return Some(BreakerTransition::Opened);
+        }
+        *state = BreakerState::Closed(failures);
+        None
+      }
+      BreakerState::HalfOpen(_) => {
+        *state = open;
+        Some(BreakerTransition::Opened)
+      }
+      // failure of a query sent before the circuit was opened
+      BreakerState::Open(_) => None,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn circuit_breaker_opens_and_recovers() {
+    let breaker = CircuitBreaker::new(&CircuitBreakerConfig {
+      failure_threshold: 3,
+      open_duration: Duration::from_millis(50),
+    });
+    assert!(breaker.record_failure().is_none());
+    assert!(breaker.record_failure().is_none());
+    assert!(breaker.record_success().is_none());
+    assert!(breaker.record_failure().is_none());
+    assert!(breaker.record_failure().is_none());
+    assert!(matches!(breaker.record_failure(), Some(BreakerTransition::Opened)));
+    assert!(!breaker.is_available());
+
+    // failed probe opens the circuit again
+    std::thread::sleep(Duration::from_millis(60));
+    assert!(breaker.is_available());
+    breaker.on_chosen();
+    assert!(!breaker.is_available());
+    assert!(matches!(breaker.record_failure(), Some(BreakerTransition::Opened)));
+    assert!(!breaker.is_available());
+
+    // successful probe closes the circuit
+    std::thread::sleep(Duration::from_millis(60));
+    breaker.on_chosen();
+    assert!(matches!(breaker.record_success(), Some(BreakerTransition::Closed)));
+    assert!(breaker.is_available());
+  }
+}
@@ -1,10 +1,10 @@
 // Handle packet buffer of DNS message (encode/decode)
 use anyhow::{anyhow, bail};
 use hickory_proto::{
-  op::{update_message::MAX_PAYLOAD_LEN, Edns, Message, MessageType, OpCode, Query},
+  op::{update_message::MAX_PAYLOAD_LEN, Edns, Message, MessageType, OpCode, Query, ResponseCode},
   rr::{
     domain::Name,
-    rdata::{A, AAAA},
+    rdata::{opt::EdnsOption, A, AAAA},
     DNSClass, RData, Record, RecordType,
   },
   serialize::binary::{BinDecodable, BinEncodable},
@@ -85,10 +85,21 @@ pub fn encode(msg: &Message) -> anyhow::Result<Vec<u8>> {
   msg.to_bytes().map_err(|e| anyhow!("Failed to encode DNS message: {}", e))
 }
 
+/// Encode a response message shared among clients, e.g., cached or coalesced one, with the query ID of the client
+pub fn encode_with_id(msg: &mut Message, query_id: u16) -> anyhow::Result<Vec<u8>> {
+  msg.set_id(query_id);
+  encode(msg)
+}
+
 /// Build a DNS query message for A record
 pub fn build_query_a(fqdn: &str) -> anyhow::Result<Message> {
-  let qname: Name = Name::from_ascii(fqdn).unwrap();
-  let mut query = Query::query(qname, RecordType::A);
+  build_query(fqdn, RecordType::A)
+}
+
+/// Build a DNS query message for the given name and record type
+pub fn build_query(fqdn: &str, record_type: RecordType) -> anyhow::Result<Message> {
+  let qname: Name = Name::from_ascii(fqdn)?;
+  let mut query = Query::query(qname, record_type);
   query.set_query_class(DNSClass::IN);
 
   let options = DnsRequestOptions::default();
@@ -128,6 +139,46 @@ pub fn build_response_refused(msg: &Message) -> Message {
   res
 }
 
+/// EDNS option code of Extended DNS Error (RFC 8914)
+const EDE_OPTION_CODE: u16 = 15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Info-codes of Extended DNS Error (RFC 8914) attached to synthetic responses
+pub enum ExtendedErrorCode {
+  Other = 0,
+  NoReachableAuthority = 22,
+  NetworkError = 23,
+}
+
+/// Build a DNS response message with SERVFAIL, where an Extended DNS Error with the given info-code and extra text
+/// is attached only if the query has EDNS.
+pub fn build_response_servfail(msg: &Message, info_code: ExtendedErrorCode, extra_text: &str) -> Message {
+  let mut res = msg.clone();
+  res.set_message_type(MessageType::Response);
+  res.set_response_code(ResponseCode::ServFail);
+  if msg.extensions().is_some() {
+    let mut edns = Edns::new();
+    edns.set_max_payload(MAX_PAYLOAD_LEN).set_version(0);
+    let mut option_data = (info_code as u16).to_be_bytes().to_vec();
+    option_data.extend_from_slice(extra_text.as_bytes());
+    edns.options_mut().insert(EdnsOption::Unknown(EDE_OPTION_CODE, option_data));
+    res.set_edns(edns);
+  }
+  res
+}
+
+/// Build a truncated DNS response message with TC bit, where answer, authority and additional sections are stripped
+/// so that the client retries over TCP. EDNS OPT record is retained.
+pub fn build_response_truncated(msg: &Message) -> Message {
+  let mut res = msg.clone();
+  res.take_answers();
+  res.take_name_servers();
+  res.take_additionals();
+  res.take_signature();
+  res.set_truncated(true);
+  res
+}
+
 /// Build a DNS response message for given QueryKey and IP address
 pub fn build_response_given_ipaddr(msg: &Message, q_key: &QueryKey, ipaddr: &IpAddr, min_ttl: u32) -> anyhow::Result<Message> {
   let mut res = msg.clone();
@@ -144,3 +195,31 @@ pub fn build_response_given_ipaddr(msg: &Message, q_key: &QueryKey, ipaddr: &IpA
   }
   Ok(res)
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use hickory_proto::rr::rdata::opt::EdnsCode;
+
+  #[test]
+  fn servfail_has_ede_only_with_edns() {
+    let mut query = build_query_a("example.com.").unwrap();
+    query.extensions_mut().get_or_insert_with(Edns::new).set_max_payload(1232);
+    let res = build_response_servfail(&query, ExtendedErrorCode::NoReachableAuthority, "no healthy path");
+    let res = decode(&encode(&res).unwrap()).unwrap();
+    assert_eq!(res.response_code(), ResponseCode::ServFail);
+    assert_eq!(res.message_type(), MessageType::Response);
+    assert_eq!(res.id(), query.id());
+    let Some(EdnsOption::Unknown(code, data)) = res.extensions().as_ref().unwrap().option(EdnsCode::from(EDE_OPTION_CODE)) else {
+      panic!("No extended DNS error");
+    };
+    assert_eq!(*code, EDE_OPTION_CODE);
+    assert_eq!(&data[..2], &22u16.to_be_bytes());
+    assert_eq!(&data[2..], b"no healthy path");
+
+    *query.extensions_mut() = None;
+    let res = build_response_servfail(&query, ExtendedErrorCode::Other, "error");
+    assert_eq!(res.response_code(), ResponseCode::ServFail);
+    assert!(res.extensions().is_none());
+  }
+}
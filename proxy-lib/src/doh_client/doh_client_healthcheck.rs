@@ -1,22 +1,118 @@
 use super::{
   dns_message,
   error::{DohClientError, DohClientResult},
-  path_manage::DoHPath,
+  healthcheck_schedule::HealthcheckSchedule,
+  path_manage::{Component, DoHPath},
   DoHClient,
 };
-use crate::{
-  constants::{HEALTHCHECK_RETRY_WAITING_SEC, HEALTHCHECK_TARGET_ADDR, HEALTHCHECK_TARGET_FQDN, MAX_ALL_UNHEALTHY_RETRY},
-  log::*,
-};
+use crate::{constants::HEALTHCHECK_RETRY_WAITING_SEC, globals::HealthcheckProbe, log::*};
 use futures::future::join_all;
-use hickory_proto::op::response_code::ResponseCode;
-use std::sync::Arc;
-use tokio::sync::Notify;
+use hickory_proto::{
+  op::{response_code::ResponseCode, Message},
+  rr::RecordType,
+};
+use std::{str::FromStr, sync::Arc};
+use tokio::sync::{Notify, Semaphore};
+use url::Url;
+
+/// Health check probe converted from the raw settings
+pub(super) struct Probe {
+  /// query name
+  name: String,
+  /// record type
+  record_type: RecordType,
+  /// expected response code
+  expected_rcode: ResponseCode,
+  /// rdata that must all be contained in the answers, or any non-empty answer for NOERROR if none
+  expected_answers: Option<Vec<String>>,
+  /// require the AD flag in the response
+  require_ad: bool,
+  /// urls of targets to which this probe is applied, or generic if empty
+  target_urls: Vec<Url>,
+}
+
+impl TryFrom<&HealthcheckProbe> for Probe {
+  type Error = DohClientError;
+  fn try_from(probe: &HealthcheckProbe) -> DohClientResult<Self> {
+    let name = if probe.name.ends_with('.') {
+      probe.name.clone()
+    } else {
+      format!("{}.", probe.name)
+    };
+    // check if the query can be built
+    dns_message::build_query_a(&name)
+      .map_err(|_| DohClientError::InvalidHealthcheckProbe(format!("invalid name {}", probe.name)))?;
+    let record_type = RecordType::from_str(&probe.record_type.to_ascii_uppercase())
+      .map_err(|_| DohClientError::InvalidHealthcheckProbe(format!("invalid record type {}", probe.record_type)))?;
+    let expected_rcode = match probe.expected_rcode.to_ascii_uppercase().as_str() {
+      "NOERROR" => ResponseCode::NoError,
+      "FORMERR" => ResponseCode::FormErr,
+      "SERVFAIL" => ResponseCode::ServFail,
+      "NXDOMAIN" => ResponseCode::NXDomain,
+      "NOTIMP" => ResponseCode::NotImp,
+      "REFUSED" => ResponseCode::Refused,
+      _ => {
+        return Err(DohClientError::InvalidHealthcheckProbe(format!(
+          "invalid response code {}",
+          probe.expected_rcode
+        )))
+      }
+    };
+    Ok(Self {
+      name,
+      record_type,
+      expected_rcode,
+      expected_answers: probe.expected_answers.clone(),
+      require_ad: probe.require_ad,
+      target_urls: probe.target_urls.clone(),
+    })
+  }
+}
+
+impl HealthcheckProbe {
+  /// Check if the probe can be converted to the actual one, so that invalid settings are rejected when loaded
+  pub fn validate(&self) -> Result<(), DohClientError> {
+    Probe::try_from(self).map(|_| ())
+  }
+}
+
+impl Probe {
+  /// Check the response to the probe, and return the reason if it is not as expected
+  fn check(&self, res_msg: &Message) -> Result<(), String> {
+    let rcode = res_msg.header().response_code();
+    if rcode != self.expected_rcode {
+      return Err(format!("Response code is {rcode} instead of {}", self.expected_rcode));
+    }
+    if self.require_ad && !res_msg.header().authentic_data() {
+      return Err("Response is not validated by DNSSEC".to_string());
+    }
+    let answers = res_msg.answers();
+    match &self.expected_answers {
+      Some(expected) => {
+        let rdata = answers
+          .iter()
+          .filter_map(|answer| answer.data())
+          .map(|v| v.to_string())
+          .collect::<Vec<_>>();
+        if let Some(missing) = expected.iter().find(|v| !rdata.contains(v)) {
+          return Err(format!(
+            "Response has no {missing} in the answers. Maybe suspicious and polluted target"
+          ));
+        }
+      }
+      None if self.expected_rcode == ResponseCode::NoError && answers.is_empty() => {
+        return Err("Response has no answer".to_string());
+      }
+      None => (),
+    }
+    Ok(())
+  }
+}
 
 impl DoHClient {
   /// Start health check service
   pub async fn start_healthcheck_service(&self, term_notify: Option<Arc<Notify>>) -> DohClientResult<()> {
-    info!("Start periodic path health check service with cache purge");
+    info!("Start path health check service with cache purge");
     match term_notify {
       Some(term) => {
         tokio::select! {
@@ -38,90 +134,255 @@ impl DoHClient {
     }
   }
 
-  /// Health check service periodically executes
-  /// - health of every path;
-  /// - purge expired DNS cache
+  /// Health check service executes
+  /// - health check of every path on its own schedule;
+  /// - periodic purge of expired DNS cache;
+  /// - watch of the case where all possible paths are unhealthy
   async fn healthcheck_service(&self) -> DohClientResult<()> {
-    let mut all_unhealthy_cnt = 0;
-    // purge expired DNS cache
+    // cap of concurrent probes shared among paths
+    let semaphore = Semaphore::new(self.healthcheck_max_concurrency.max(1));
+    // wake up the schedules backing off when all paths are found unhealthy
+    let all_unhealthy_notify = Notify::new();
+    let schedules = self
+      .path_manager
+      .components()
+      .iter()
+      .map(|component| self.healthcheck_component_service(component, &semaphore, &all_unhealthy_notify));
+
+    tokio::select! {
+      _ = join_all(schedules) => Ok(()),
+      _ = self.cache_purge_service() => Ok(()),
+      res = self.all_unhealthy_watch_service(&all_unhealthy_notify) => res,
+    }
+  }
+
+  /// Probe the target or relay repeatedly according to its schedule, via a path containing it.
+  /// A successful probe makes every component of the path healthy, and a failed one makes the component unhealthy
+  /// if the other components of the path are healthy and the failure is confirmed by another probe,
+  /// which takes out all paths containing the component at once.
+  /// The first probe is executed immediately, where concurrent probes are capped by the semaphore.
+  /// While all paths are unhealthy, the backoff is capped low, and the schedule is woken up when the state begins.
+  async fn healthcheck_component_service(&self, component: &Component, semaphore: &Semaphore, all_unhealthy_notify: &Notify) {
+    let mut schedule = HealthcheckSchedule::new(self.healthcheck_period_sec);
+    loop {
+      let is_healthy = {
+        let Ok(_permit) = semaphore.acquire().await else {
+          error!("Health check semaphore is closed");
+          return;
+        };
+        self.healthcheck_component(component).await
+      };
+      let interval = schedule.next_interval(is_healthy, self.path_manager.is_all_unhealthy());
+      debug!("Next health check of {component} in {:?}", interval);
+      tokio::select! {
+        _ = tokio::time::sleep(interval) => (),
+        _ = all_unhealthy_notify.notified(), if !is_healthy => {
+          debug!("All paths are unhealthy, check health of {component} now");
+        }
+      }
+    }
+  }
+
+  /// Check health of the target or relay via a path containing it, and return if the probe succeeded
+  async fn healthcheck_component(&self, component: &Component) -> bool {
+    let Some(path) = self.path_manager.get_probe_path(component) else {
+      warn!("No path to check health of {component}");
+      return component.is_healthy();
+    };
+    let url = path.as_url().map(|v| v.to_string()).unwrap_or_default();
+    // failure is attributed to the component only if the other components of the path are healthy
+    let is_isolated = path.is_healthy_except(component);
+
+    match self.healthcheck(&path).await {
+      Ok(()) => {
+        if !component.is_healthy() {
+          info!("{component} is healthy again via {url}");
+        }
+        path.make_healthy();
+        debug!("Path {url} is healthy");
+        true
+      }
+      Err(reason) if is_isolated => {
+        // other components of the path may have gone down after their last health checks
+        let confirmed = self
+          .path_manager
+          .confirm_failure(component, &path, |path| async move {
+            match self.healthcheck(&path).await {
+              Ok(()) => {
+                path.make_healthy();
+                true
+              }
+              Err(reason) => {
+                debug!("{reason}. Confirmation probe of {component} failed");
+                false
+              }
+            }
+          })
+          .await;
+        if confirmed {
+          component.make_unhealthy();
+          warn!("{reason}. Health check via {url} failed, and {component} is unhealthy");
+        } else {
+          warn!("{reason}. Health check via {url} failed, which is not attributed to {component} by another probe");
+        }
+        false
+      }
+      Err(reason) => {
+        warn!("{reason}. Health check via {url} failed, where other components of the path are also unhealthy");
+        false
+      }
+    }
+  }
+
+  /// Purge expired DNS cache every health check period
+  async fn cache_purge_service(&self) {
     loop {
-      info!("Execute periodic health check");
       let cache_clone = self.cache.clone();
       self.runtime_handle.spawn(async move {
         let purged = cache_clone.purge_expired_entries().await;
         debug!("Purged {} expired entries from cache", purged);
       });
+      tokio::time::sleep(self.healthcheck_period_sec).await;
+    }
+  }
 
-      // health check for every path
-      let futures = self.path_manager.paths.iter().flatten().flatten().map(|path| async move {
-        if let Err(e) = self.healthcheck(path).await {
-          warn!("Healthcheck fails for {}: {e}", path.as_url()?)
+  /// Watch if all possible paths are unhealthy, and log the transition from and to the state.
+  /// While all paths are unhealthy, e.g., the Internet connection is lost, health checks keep retrying with short backoff
+  /// and queries are answered from cache or with SERVFAIL. The schedules of failing components are woken up when the state begins.
+  /// Return error only if the state continues over the max number of retries if given.
+  async fn all_unhealthy_watch_service(&self, all_unhealthy_notify: &Notify) -> DohClientResult<()> {
+    let mut all_unhealthy_cnt = 0;
+    let mut all_unhealthy_since = None;
+    loop {
+      tokio::time::sleep(tokio::time::Duration::from_secs(HEALTHCHECK_RETRY_WAITING_SEC)).await;
+      let paths = self.path_manager.paths.iter().flatten().flatten();
+      let healthy_cnt = paths.clone().filter(|v| v.is_healthy()).count();
+      if healthy_cnt > 0 {
+        if let Some(since) = all_unhealthy_since.take() {
+          info!(
+            "Connectivity recovered after {:?}: {} of {} paths are healthy",
+            std::time::Instant::now().duration_since(since),
+            healthy_cnt,
+            paths.count()
+          );
         }
-        Ok(()) as DohClientResult<()>
-      });
-      let _ = join_all(futures).await;
+        all_unhealthy_cnt = 0;
+        continue;
+      }
 
-      if !self.path_manager.paths.iter().flatten().flatten().any(|v| v.is_healthy()) {
-        all_unhealthy_cnt += 1;
-        error!("All possible paths are unhealthy. Should check the Internet connection");
-        if all_unhealthy_cnt > MAX_ALL_UNHEALTHY_RETRY {
+      all_unhealthy_cnt += 1;
+      if all_unhealthy_since.is_none() {
+        all_unhealthy_since = Some(std::time::Instant::now());
+        all_unhealthy_notify.notify_waiters();
+        error!(
+          "All possible paths are unhealthy. Should check the Internet connection. Keep serving from cache or with SERVFAIL"
+        );
+      } else {
+        debug!(
+          "All possible paths are still unhealthy ({} consecutive watches)",
+          all_unhealthy_cnt
+        );
+      }
+      if let Some(max) = self.max_all_unhealthy_retry {
+        if all_unhealthy_cnt > max {
           return Err(DohClientError::AllPathsUnhealthy);
         }
-        tokio::time::sleep(tokio::time::Duration::from_secs(HEALTHCHECK_RETRY_WAITING_SEC)).await;
-        continue;
       }
-      tokio::time::sleep(self.healthcheck_period_sec).await;
     }
   }
 
-  /// Check health for a given path, and update health status for the path.
-  async fn healthcheck(&self, path: &Arc<DoHPath>) -> DohClientResult<()> {
-    let q_msg = dns_message::build_query_a(HEALTHCHECK_TARGET_FQDN)?;
-    let packet_buf = dns_message::encode(&q_msg)?;
-
-    let res_msg = match self.make_doh_query_inner(&packet_buf, path).await {
-      Ok((_, res_msg)) => res_msg,
-      Err(e) => {
-        path.make_unhealthy();
-        warn!(
-          "Failed to query or invalid response. Path {} is unhealthy: {}",
-          path.as_url()?,
-          e
-        );
-        return Ok(());
-      }
+  /// Check health for a given path with the probes applied to its target, and return the reason if unhealthy
+  async fn healthcheck(&self, path: &Arc<DoHPath>) -> Result<(), String> {
+    let target_specific = self
+      .healthcheck_probes
+      .iter()
+      .filter(|probe| probe.target_urls.iter().any(|url| path.target().is_url_of(url)))
+      .collect::<Vec<_>>();
+    let probes = if target_specific.is_empty() {
+      self
+        .healthcheck_probes
+        .iter()
+        .filter(|probe| probe.target_urls.is_empty())
+        .collect::<Vec<_>>()
+    } else {
+      target_specific
     };
 
-    if res_msg.header().response_code() != ResponseCode::NoError {
-      path.make_unhealthy();
-      warn!("Response is not Ok. Path {} is unhealthy", path.as_url()?);
-      return Ok(());
-    }
+    for probe in probes {
+      let mut q_msg = dns_message::build_query(&probe.name, probe.record_type).map_err(|e| e.to_string())?;
+      if probe.require_ad {
+        // signal that the AD flag is understood (RFC 6840)
+        q_msg.set_authentic_data(true);
+      }
+      let packet_buf = dns_message::encode(&q_msg).map_err(|e| e.to_string())?;
 
-    let answers = res_msg.answers();
-    if answers.is_empty() {
-      path.make_unhealthy();
-      warn!("Response has no answer. Path {} is unhealthy", path.as_url()?);
+      let (_, res_msg) = self
+        .make_doh_query_inner(&packet_buf, path)
+        .await
+        .map_err(|e| format!("Failed to query or invalid response: {e}"))?;
 
-      return Ok(());
+      probe
+        .check(&res_msg)
+        .map_err(|reason| format!("{reason} for probe {} {}", probe.name, probe.record_type))?;
     }
+    Ok(())
+  }
+}
 
-    let target_addr_contains = answers
-      .iter()
-      .filter_map(|answer| answer.data())
-      .any(|v| v.to_string() == HEALTHCHECK_TARGET_ADDR);
-
-    if !target_addr_contains {
-      path.make_unhealthy();
-      warn!(
-        "Response has no or wrong rdata. Maybe suspicious and polluted target. Path {} is unhealthy.",
-        path.as_url()?
-      );
-      return Ok(());
-    }
+#[cfg(test)]
+mod tests {
+  use super::*;
 
-    path.make_healthy();
-    debug!("Path {} is healthy", path.as_url()?);
-    Ok(())
+  #[test]
+  fn probe_checks_response() {
+    let probe = Probe::try_from(&HealthcheckProbe::default()).unwrap();
+    let query = dns_message::build_query_a("dns.google.").unwrap();
+    let q_key = &dns_message::Request::try_from(&query).unwrap().0[0];
+    let mut res_msg = dns_message::build_response_given_ipaddr(&query, q_key, &"8.8.8.8".parse().unwrap(), 60).unwrap();
+    assert!(probe.check(&res_msg).is_ok());
+
+    let polluted = dns_message::build_response_given_ipaddr(&query, q_key, &"1.1.1.1".parse().unwrap(), 60).unwrap();
+    assert!(probe.check(&polluted).is_err());
+
+    let probe_ad = Probe::try_from(&HealthcheckProbe {
+      expected_answers: None,
+      require_ad: true,
+      ..Default::default()
+    })
+    .unwrap();
+    assert!(probe_ad.check(&res_msg).is_err());
+    res_msg.set_authentic_data(true);
+    assert!(probe_ad.check(&res_msg).is_ok());
+
+    let probe_nx = Probe::try_from(&HealthcheckProbe {
+      name: "nonexistent.example".to_string(),
+      expected_rcode: "nxdomain".to_string(),
+      expected_answers: None,
+      ..Default::default()
+    })
+    .unwrap();
+    assert_eq!(probe_nx.name, "nonexistent.example.");
+    assert!(probe_nx.check(&res_msg).is_err());
+    let nx = dns_message::build_response_nx(&query);
+    assert!(probe_nx.check(&nx).is_ok());
+
+    assert!(Probe::try_from(&HealthcheckProbe {
+      record_type: "INVALID".to_string(),
+      ..Default::default()
+    })
+    .is_err());
+    assert!(HealthcheckProbe::default().validate().is_ok());
+    assert!(HealthcheckProbe {
+      expected_rcode: "NOTANRCODE".to_string(),
+      ..Default::default()
+    }
+    .validate()
+    .is_err());
+    assert!(HealthcheckProbe {
+      name: "invalid..example".to_string(),
+      ..Default::default()
+    }
+    .validate()
+    .is_err());
   }
 }
@@ -1,15 +1,19 @@
 use super::{
   cache::Cache,
   dns_message::{self, Request},
+  doh_client_healthcheck::Probe,
   error::{DohClientError, DohClientResult},
+  hedging::Hedging,
+  in_flight::{InFlightQueries, InFlightRole},
   manipulation::{QueryManipulationResult, QueryManipulators},
   odoh_config_store::ODoHConfigStore,
-  path_manage::{DoHPath, DoHPathManager},
+  path_manage::{DoHPath, DoHPathManager, PathQueryRecorder},
+  retry::{UpstreamResponse, UpstreamRetry},
   DoHMethod, DoHResponseType, DoHType,
 };
 use crate::{
   auth::Authenticator,
-  globals::Globals,
+  globals::{Globals, UpstreamProfile},
   http_client::{HttpClientInner, ResolveIpResponse, ResolveIps},
   log::*,
   proxy::ProxyProtocol,
@@ -34,6 +38,14 @@ pub struct DoHClient {
   odoh_configs: Option<Arc<ODoHConfigStore>>,
   /// DNS cache
   pub(super) cache: Arc<Cache>,
+  /// upstream queries in flight shared among identical queries missing the cache
+  in_flight: InFlightQueries,
+  /// hedging policy of upstream queries, which is disabled if none
+  hedging: Option<Hedging>,
+  /// retry policy of upstream queries over other paths
+  retry: UpstreamRetry,
+  /// overall deadline of an upstream query including retries
+  http_timeout_sec: tokio::time::Duration,
   /// DoH type
   doh_type: DoHType,
   /// DoH method
@@ -44,6 +56,12 @@ pub struct DoHClient {
   pub(super) runtime_handle: tokio::runtime::Handle,
   /// health check interval
   pub(super) healthcheck_period_sec: tokio::time::Duration,
+  /// health check probes
+  pub(super) healthcheck_probes: Vec<Probe>,
+  /// max number of health check probes executed concurrently
+  pub(super) healthcheck_max_concurrency: usize,
+  /// max number of consecutive watches finding all paths unhealthy before giving up, which is unlimited if none
+  pub(super) max_all_unhealthy_retry: Option<usize>,
   /// Query manipulation pulugins
   query_manipulators: QueryManipulators,
   /// Query logging sender
@@ -51,17 +69,18 @@ pub struct DoHClient {
 }
 
 impl DoHClient {
-  /// Create a new DoH client
+  /// Create a new DoH client for the upstream profile
   pub async fn new(
     globals: Arc<Globals>,
+    profile: &UpstreamProfile,
     http_client: Arc<RwLock<HttpClientInner>>,
     auth_client: Option<Arc<Authenticator>>,
   ) -> DohClientResult<Self> {
-    // 1. build all path candidates from globals
-    let path_manager = Arc::new(DoHPathManager::new(&globals)?);
+    // 1. build all path candidates from the profile
+    let path_manager = Arc::new(DoHPathManager::new(profile, &globals.proxy_config.circuit_breaker_config)?);
 
     // 2. spawn odoh config service if odoh or modoh are enabled
-    let odoh_configs = match &globals.proxy_config.nexthop_relay_config {
+    let odoh_configs = match &profile.nexthop_relay_config {
       Some(nexthop_relay_config) => {
         if nexthop_relay_config.odoh_relay_urls.is_empty() {
           return Err(DohClientError::ODoHNoRelayUrl);
@@ -78,7 +97,7 @@ impl DoHClient {
     };
 
     // doh type
-    let doh_type = match &globals.proxy_config.nexthop_relay_config {
+    let doh_type = match &profile.nexthop_relay_config {
       Some(nexthop_relay_config) => {
         if nexthop_relay_config.odoh_relay_urls.is_empty() {
           DoHType::Standard
@@ -100,7 +119,7 @@ impl DoHClient {
     // doh method
     let doh_method = match doh_type {
       DoHType::Standard => {
-        if globals.proxy_config.target_config.use_get {
+        if profile.target_config.use_get {
           DoHMethod::Get
         } else {
           DoHMethod::Post
@@ -110,16 +129,22 @@ impl DoHClient {
     };
 
     // cache
-    let cache = Arc::new(Cache::new(globals.proxy_config.max_cache_size));
+    let cache = Arc::new(Cache::new(profile.max_cache_size));
 
     // runtime handle
     let runtime_handle = globals.runtime_handle.clone();
 
-    // health check period
+    // health check period and probes
     let healthcheck_period_sec = globals.proxy_config.healthcheck_period_sec;
+    let healthcheck_probes = globals
+      .proxy_config
+      .healthcheck_probes
+      .iter()
+      .map(Probe::try_from)
+      .collect::<DohClientResult<Vec<_>>>()?;
 
     // query manipulators
-    let query_manipulators: QueryManipulators = if let Some(q) = &globals.proxy_config.query_manipulation_config {
+    let query_manipulators: QueryManipulators = if let Some(q) = &profile.query_manipulation_config {
       q.as_ref().try_into().unwrap_or_default()
     } else {
       QueryManipulators::default()
@@ -131,18 +156,28 @@ impl DoHClient {
       path_manager,
       odoh_configs,
       cache,
+      in_flight: InFlightQueries::default(),
+      hedging: globals.proxy_config.hedging_config.as_ref().map(Hedging::new),
+      retry: UpstreamRetry::new(
+        globals.proxy_config.max_upstream_retries,
+        globals.proxy_config.retry_upstream_on_servfail,
+      ),
+      http_timeout_sec: globals.proxy_config.http_timeout_sec,
       doh_type,
       doh_method,
       headers,
       runtime_handle,
       healthcheck_period_sec,
+      healthcheck_probes,
+      healthcheck_max_concurrency: globals.proxy_config.healthcheck_max_concurrency,
+      max_all_unhealthy_retry: globals.proxy_config.max_all_unhealthy_retry,
       query_manipulators,
       query_log_tx: globals.query_log_tx.clone(),
     })
   }
 
   /// Log DNS message
-  fn log_dns_message(
+  pub(crate) fn log_dns_message(
     &self,
     raw_packet: &[u8],
     proto: ProxyProtocol,
@@ -223,29 +258,151 @@ impl DoHClient {
       }
     }
 
-    // choose path
-    let Some(path) = self.path_manager.get_path() else {
-      return Err(DohClientError::NoPathAvailable);
+    // wait for the identical query in flight if any, instead of making another upstream query
+    let leader = match self.in_flight.join(&req) {
+      InFlightRole::Leader(leader) => leader,
+      InFlightRole::Follower(mut rx) => {
+        let Ok(response_message) = rx.recv().await else {
+          return Err(DohClientError::CoalescedQueryFailed);
+        };
+        debug!("Coalesced with in-flight query: {:?}", response_message.queries());
+        let response_buf = dns_message::encode_with_id(&mut response_message.as_ref().clone(), query_id)?;
+        self.log_dns_message(&response_buf, proto, src, DoHResponseType::Coalesced, None, start);
+        return Ok(response_buf);
+      }
     };
 
-    // make doh query with the given path
-    let (response_buf, response_message) = self.make_doh_query_inner(packet_buf, &path).await?;
+    // make doh query with automatically chosen path, retried over other paths on failure
+    let (response_buf, response_message, path) = self.make_doh_query_with_retry(packet_buf).await?;
 
     self.log_dns_message(&response_buf, proto, src, DoHResponseType::Normal, Some(path), start);
 
-    // put message to cache
+    // put message to cache, and then share it with the identical queries waiting for it
     if (self.cache.put(req, &response_message).await).is_err() {
       error!("Failed to cache a DNS response");
     };
+    leader.finish(&response_message);
 
     // should rebuild buffer from decoded dns response_msg? -> no need to do that.
     Ok(response_buf)
   }
 
+  /// Make DoH query over automatically chosen paths, and return the response with the path that answered.
+  /// If the query fails, or is answered with SERVFAIL if configured, it is retried over a path not tried yet within the retry budget,
+  /// as long as the overall deadline of http timeout is not exceeded.
+  async fn make_doh_query_with_retry(&self, packet_buf: &[u8]) -> DohClientResult<UpstreamResponse> {
+    let deadline = tokio::time::Instant::now() + self.http_timeout_sec;
+    self
+      .retry
+      .run(deadline, |mut tried| async move {
+        let res = self
+          .make_doh_query_with_path_selection(packet_buf, &mut tried, deadline)
+          .await;
+        (tried, res)
+      })
+      .await
+  }
+
+  /// Make DoH query over a path chosen by the path manager among those not tried yet,
+  /// and return the response with the path that answered. Paths used for the query are added to the tried ones.
+  /// In hedging mode, if the path has not answered within the hedging delay, the same query is sent over another path,
+  /// and the first answer is taken while the other request is cancelled.
+  /// The latency of the request that answered, measured from its own start, is fed to the adaptive hedging delay.
+  async fn make_doh_query_with_path_selection(
+    &self,
+    packet_buf: &[u8],
+    tried: &mut Vec<Arc<DoHPath>>,
+    deadline: tokio::time::Instant,
+  ) -> DohClientResult<UpstreamResponse> {
+    let Some(path) = self.path_manager.get_path_excluding(tried) else {
+      return Err(DohClientError::NoPathAvailable);
+    };
+    tried.push(path.clone());
+    let Some(hedging) = &self.hedging else {
+      let (response_buf, response_message) = self.make_doh_query_until(packet_buf, &path, Some(deadline)).await?;
+      return Ok((response_buf, response_message, path));
+    };
+
+    let start = tokio::time::Instant::now();
+    let delay = hedging.delay();
+    let primary = self.make_doh_query_until(packet_buf, &path, Some(deadline));
+    tokio::pin!(primary);
+    let hedge_path = match tokio::time::timeout(delay, &mut primary).await {
+      Ok(res) => {
+        let (response_buf, response_message) = res?;
+        hedging.record(start.elapsed());
+        return Ok((response_buf, response_message, path.clone()));
+      }
+      Err(_) => self.path_manager.get_path_excluding(tried),
+    };
+    let Some(hedge_path) = hedge_path else {
+      debug!("No other path to hedge the query, keep waiting for the first path");
+      let (response_buf, response_message) = primary.await?;
+      hedging.record(start.elapsed());
+      return Ok((response_buf, response_message, path.clone()));
+    };
+
+    debug!(
+      "First path has not answered within {:?}, hedge the query over another path",
+      delay
+    );
+    tried.push(hedge_path.clone());
+    let hedge_start = tokio::time::Instant::now();
+    let hedge = self.make_doh_query_until(packet_buf, &hedge_path, Some(deadline));
+    tokio::pin!(hedge);
+    // the first answer is taken, where the other request is awaited only if the first one fails
+    let (res, (winner, winner_start), loser) = tokio::select! {
+      res = &mut primary => match res {
+        Ok(res) => (res, (&path, start), &hedge_path),
+        Err(e) => {
+          debug!("First path failed while hedging: {e}");
+          (hedge.await?, (&hedge_path, hedge_start), &path)
+        }
+      },
+      res = &mut hedge => match res {
+        Ok(res) => (res, (&hedge_path, hedge_start), &path),
+        Err(e) => {
+          debug!("Hedged path failed: {e}");
+          (primary.await?, (&path, start), &hedge_path)
+        }
+      },
+    };
+    hedging.record(winner_start.elapsed());
+    if let (Ok(winner_url), Ok(loser_url)) = (winner.as_url(), loser.as_url()) {
+      info!("Hedged query answered via {} (cancelled {})", winner_url, loser_url);
+    }
+    let (response_buf, response_message) = res;
+    Ok((response_buf, response_message, winner.clone()))
+  }
+
   /// Make DoH query with a specifically given path.
   /// Note cache and plugins are disabled to be used for health check
   pub(super) async fn make_doh_query_inner(&self, packet_buf: &[u8], path: &Arc<DoHPath>) -> DohClientResult<(Vec<u8>, Message)> {
+    self.make_doh_query_until(packet_buf, path, None).await
+  }
+
+  /// Make DoH query with a specifically given path, which may be cancelled at the deadline.
+  /// The latency and error rate of the path are fed to the path selection, including those of the cancelled query.
+  async fn make_doh_query_until(
+    &self,
+    packet_buf: &[u8],
+    path: &Arc<DoHPath>,
+    deadline: Option<tokio::time::Instant>,
+  ) -> DohClientResult<(Vec<u8>, Message)> {
     let headers = self.build_headers().await?;
+    let recorder = PathQueryRecorder::start(path, deadline);
+    let res = self.make_doh_query_over_path(packet_buf, path, headers).await;
+    recorder.finish(res.is_ok());
+    res
+  }
+
+  /// Make DoH query over the given path with the given headers, and check the response
+  async fn make_doh_query_over_path(
+    &self,
+    packet_buf: &[u8],
+    path: &Arc<DoHPath>,
+    headers: HeaderMap,
+  ) -> DohClientResult<(Vec<u8>, Message)> {
     let response_buf = match self.doh_type {
       DoHType::Standard => self.serve_doh_query(packet_buf, path, headers).await,
       DoHType::Oblivious => self.serve_oblivious_doh_query(packet_buf, path, headers).await,
@@ -1,3 +1,4 @@
+use super::dns_message::ExtendedErrorCode;
 use thiserror::Error;
 
 pub(super) type DohClientResult<T> = std::result::Result<T, DohClientError>;
@@ -41,6 +42,12 @@ pub enum DohClientError {
   NoPathAvailable,
   #[error("DoH query error")]
   DoHQueryError,
+  #[error("Upstream query timed out")]
+  UpstreamTimeout,
+  #[error("Invalid health check probe: {0}")]
+  InvalidHealthcheckProbe(String),
+  #[error("Identical upstream query in flight failed")]
+  CoalescedQueryFailed,
   #[error("Failed to resolve ips via DoH for HTTP client")]
   FailedToResolveIpsForHttpClient,
 
@@ -53,3 +60,22 @@ pub enum DohClientError {
   #[error(transparent)]
   Other(#[from] anyhow::Error),
 }
+
+impl DohClientError {
+  /// Extended DNS Error (RFC 8914) info-code and extra text explaining the error in the synthetic SERVFAIL response
+  pub(crate) fn extended_dns_error(&self) -> (ExtendedErrorCode, &'static str) {
+    match self {
+      DohClientError::NoPathAvailable | DohClientError::AllPathsUnhealthy => {
+        (ExtendedErrorCode::NoReachableAuthority, "no healthy path")
+      }
+      DohClientError::HttpClientError(e) if e.is_timeout() => (ExtendedErrorCode::NoReachableAuthority, "upstream timeout"),
+      DohClientError::UpstreamTimeout => (ExtendedErrorCode::NoReachableAuthority, "upstream timeout"),
+      DohClientError::HttpClientError(_) | DohClientError::DoHQueryError | DohClientError::CoalescedQueryFailed => {
+        (ExtendedErrorCode::NetworkError, "upstream query failed")
+      }
+      DohClientError::InvalidDnsResponse => (ExtendedErrorCode::Other, "invalid upstream response"),
+      DohClientError::AuthenticatorError(_) => (ExtendedErrorCode::Other, "authentication failed"),
+      _ => (ExtendedErrorCode::Other, "proxy error"),
+    }
+  }
+}
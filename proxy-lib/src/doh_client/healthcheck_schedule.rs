@@ -0,0 +1,102 @@
+use crate::constants::{HEALTHCHECK_FAILURE_RETRY_SEC, HEALTHCHECK_JITTER_PERCENT, HEALTHCHECK_RETRY_WAITING_SEC};
+use rand::Rng;
+use tokio::time::Duration;
+
+/// Health check schedule of a path.
+/// A healthy path is probed every health check period, and an unhealthy one is re-probed soon after the failure
+/// with exponential backoff up to the period. While all paths are unhealthy, e.g., the Internet connection is lost,
+/// the backoff is capped much lower so that the recovery is detected soon.
+/// Every interval is jittered so that probes of paths are not synchronized.
+pub(super) struct HealthcheckSchedule {
+  /// health check period for healthy paths, which also caps the backoff
+  period: Duration,
+  /// number of consecutive failed probes
+  consecutive_failures: u32,
+}
+
+impl HealthcheckSchedule {
+  /// Create a new schedule
+  pub(super) fn new(period: Duration) -> Self {
+    Self {
+      period,
+      consecutive_failures: 0,
+    }
+  }
+
+  /// Update the schedule with the result of the probe and whether all paths are unhealthy,
+  /// and get the interval until the next probe
+  pub(super) fn next_interval(&mut self, is_healthy: bool, all_unhealthy: bool) -> Duration {
+    let base = if is_healthy {
+      self.consecutive_failures = 0;
+      self.period
+    } else {
+      self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+      let backoff = 1u32.checked_shl(self.consecutive_failures - 1).unwrap_or(u32::MAX);
+      let cap = if all_unhealthy {
+        self.period.min(Duration::from_secs(HEALTHCHECK_RETRY_WAITING_SEC))
+      } else {
+        self.period
+      };
+      Duration::from_secs(HEALTHCHECK_FAILURE_RETRY_SEC)
+        .saturating_mul(backoff)
+        .min(cap)
+    };
+    jitter(base)
+  }
+}
+
+/// Add random jitter of HEALTHCHECK_JITTER_PERCENT to the interval
+fn jitter(interval: Duration) -> Duration {
+  let percent = rand::thread_rng().gen_range(100 - HEALTHCHECK_JITTER_PERCENT..=100 + HEALTHCHECK_JITTER_PERCENT);
+  interval * percent / 100
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn assert_jittered(actual: Duration, base: Duration) {
+    assert!(actual >= base * (100 - HEALTHCHECK_JITTER_PERCENT) / 100);
+    assert!(actual <= base * (100 + HEALTHCHECK_JITTER_PERCENT) / 100);
+  }
+
+  #[test]
+  fn schedule_backs_off_failing_path() {
+    let period = Duration::from_secs(600);
+    let retry = Duration::from_secs(HEALTHCHECK_FAILURE_RETRY_SEC);
+    let mut schedule = HealthcheckSchedule::new(period);
+    assert_jittered(schedule.next_interval(true, false), period);
+
+    assert_jittered(schedule.next_interval(false, false), retry);
+    assert_jittered(schedule.next_interval(false, false), retry * 2);
+    assert_jittered(schedule.next_interval(false, false), retry * 4);
+    (0..64).for_each(|_| {
+      schedule.next_interval(false, false);
+    });
+    assert_jittered(schedule.next_interval(false, false), period);
+
+    // recovered path goes back to the period, and its next failure is re-probed soon
+    assert_jittered(schedule.next_interval(true, false), period);
+    assert_jittered(schedule.next_interval(false, false), retry);
+  }
+
+  #[test]
+  fn schedule_recovers_soon_from_all_unhealthy() {
+    let period = Duration::from_secs(600);
+    let retry = Duration::from_secs(HEALTHCHECK_FAILURE_RETRY_SEC);
+    let waiting = Duration::from_secs(HEALTHCHECK_RETRY_WAITING_SEC);
+    let mut schedule = HealthcheckSchedule::new(period);
+    (0..64).for_each(|_| {
+      schedule.next_interval(false, false);
+    });
+    assert_jittered(schedule.next_interval(false, false), period);
+
+    // once all paths are unhealthy, the long backoff is capped to re-probe soon
+    assert_jittered(schedule.next_interval(false, true), waiting);
+    (0..64).for_each(|_| assert_jittered(schedule.next_interval(false, true), waiting));
+
+    // connectivity is recovered, and the schedule goes back to the period
+    assert_jittered(schedule.next_interval(true, false), period);
+    assert_jittered(schedule.next_interval(false, true), retry);
+  }
+}
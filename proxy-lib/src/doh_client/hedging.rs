@@ -0,0 +1,85 @@
+use crate::{
+  constants::{HEDGING_ADAPTIVE_MIN_SAMPLES, HEDGING_ADAPTIVE_PERCENTILE, HEDGING_LATENCY_WINDOW},
+  globals::HedgingConfig,
+  log::*,
+};
+use std::{collections::VecDeque, sync::Mutex};
+use tokio::time::Duration;
+
+/// Hedging policy of upstream requests, which decides how long to wait for the first path before sending the same query over another one
+pub(super) struct Hedging {
+  /// hedging settings
+  config: HedgingConfig,
+  /// recent latencies of successful upstream requests, used in the adaptive mode
+  latencies: Mutex<VecDeque<Duration>>,
+}
+
+impl Hedging {
+  /// Create a new hedging policy
+  pub(super) fn new(config: &HedgingConfig) -> Self {
+    Self {
+      config: config.clone(),
+      latencies: Mutex::new(VecDeque::with_capacity(HEDGING_LATENCY_WINDOW)),
+    }
+  }
+
+  /// Get the current hedging delay
+  pub(super) fn delay(&self) -> Duration {
+    let initial = self.config.delay;
+    if !self.config.adaptive {
+      return initial;
+    }
+    let Ok(latencies) = self.latencies.lock() else {
+      error!("Failed to lock latencies for hedging, use the initial delay");
+      return initial;
+    };
+    if latencies.len() < HEDGING_ADAPTIVE_MIN_SAMPLES {
+      return initial;
+    }
+    let mut sorted = latencies.iter().copied().collect::<Vec<_>>();
+    sorted.sort_unstable();
+    let idx = (sorted.len() * HEDGING_ADAPTIVE_PERCENTILE / 100).min(sorted.len() - 1);
+    sorted[idx]
+  }
+
+  /// Record the latency of a successful upstream request
+  pub(super) fn record(&self, latency: Duration) {
+    if !self.config.adaptive {
+      return;
+    }
+    let Ok(mut latencies) = self.latencies.lock() else {
+      return;
+    };
+    if latencies.len() >= HEDGING_LATENCY_WINDOW {
+      latencies.pop_front();
+    }
+    latencies.push_back(latency);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn adaptive_delay_follows_percentile() {
+    let initial = Duration::from_millis(200);
+    let hedging = Hedging::new(&HedgingConfig {
+      delay: initial,
+      adaptive: true,
+    });
+    (1..HEDGING_ADAPTIVE_MIN_SAMPLES as u64).for_each(|v| hedging.record(Duration::from_millis(v)));
+    assert_eq!(hedging.delay(), initial);
+
+    (0..HEDGING_LATENCY_WINDOW as u64).for_each(|v| hedging.record(Duration::from_millis(v + 1)));
+    let expected = (HEDGING_LATENCY_WINDOW * HEDGING_ADAPTIVE_PERCENTILE / 100 + 1) as u64;
+    assert_eq!(hedging.delay(), Duration::from_millis(expected));
+
+    let fixed = Hedging::new(&HedgingConfig {
+      delay: initial,
+      adaptive: false,
+    });
+    fixed.record(Duration::from_millis(1));
+    assert_eq!(fixed.delay(), initial);
+  }
+}
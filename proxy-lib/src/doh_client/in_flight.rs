@@ -0,0 +1,122 @@
+use super::dns_message::Request;
+use crate::log::*;
+use ahash::HashMap;
+use hickory_proto::op::Message;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+/// Role of a query that missed the cache in the single-flight deduplication of upstream queries
+pub(super) enum InFlightRole<'a> {
+  /// The first query for the request, which makes the upstream query and shares its response
+  Leader(InFlightLeader<'a>),
+  /// Query waiting for the response to the leader's upstream query
+  Follower(broadcast::Receiver<Arc<Message>>),
+}
+
+#[derive(Default)]
+/// Upstream queries in flight keyed by requests, where concurrent identical queries wait on the single upstream query
+pub(super) struct InFlightQueries {
+  inner: Mutex<HashMap<Request, broadcast::Sender<Arc<Message>>>>,
+}
+
+impl InFlightQueries {
+  /// Join the upstream query in flight for the request, or lead a new one if there is none
+  pub(super) fn join(&self, req: &Request) -> InFlightRole<'_> {
+    let Ok(mut inner) = self.inner.lock() else {
+      error!("Failed to lock in-flight queries, make upstream query without coalescing");
+      return InFlightRole::Leader(InFlightLeader {
+        queries: self,
+        req: req.clone(),
+        tx: None,
+      });
+    };
+    if let Some(tx) = inner.get(req) {
+      return InFlightRole::Follower(tx.subscribe());
+    }
+    let (tx, _) = broadcast::channel(1);
+    inner.insert(req.clone(), tx.clone());
+    InFlightRole::Leader(InFlightLeader {
+      queries: self,
+      req: req.clone(),
+      tx: Some(tx),
+    })
+  }
+
+  /// Remove the upstream query from in-flight ones, and return its sender if registered
+  fn remove(&self, req: &Request) -> Option<broadcast::Sender<Arc<Message>>> {
+    self.inner.lock().ok().and_then(|mut inner| inner.remove(req))
+  }
+}
+
+/// Leader of an upstream query in flight.
+/// If dropped without the response, e.g., on upstream failure or cancellation, followers are notified by the closed channel.
+pub(super) struct InFlightLeader<'a> {
+  queries: &'a InFlightQueries,
+  req: Request,
+  tx: Option<broadcast::Sender<Arc<Message>>>,
+}
+
+impl InFlightLeader<'_> {
+  /// Share the response message with the followers
+  pub(super) fn finish(mut self, response_message: &Message) {
+    if self.tx.take().is_none() {
+      return;
+    }
+    // removed before sending so that no follower joins after the response is sent
+    if let Some(tx) = self.queries.remove(&self.req) {
+      let _ = tx.send(Arc::new(response_message.clone()));
+    }
+  }
+}
+
+impl Drop for InFlightLeader<'_> {
+  fn drop(&mut self) {
+    if self.tx.take().is_some() {
+      self.queries.remove(&self.req);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::doh_client::dns_message::build_query_a;
+
+  #[tokio::test]
+  async fn coalesce_identical_queries() {
+    let queries = InFlightQueries::default();
+    let query_msg = build_query_a("www.example.com.").unwrap();
+    let req = Request::try_from(&query_msg).unwrap();
+    let other_req = Request::try_from(&build_query_a("www.example.net.").unwrap()).unwrap();
+
+    let InFlightRole::Leader(leader) = queries.join(&req) else {
+      panic!("first query must lead");
+    };
+    let InFlightRole::Follower(mut rx) = queries.join(&req) else {
+      panic!("identical query must follow");
+    };
+    assert!(matches!(queries.join(&other_req), InFlightRole::Leader(_)));
+
+    let mut response_message = query_msg.clone();
+    response_message.set_id(1234);
+    leader.finish(&response_message);
+    assert_eq!(rx.recv().await.unwrap().id(), 1234);
+
+    // finished query is no longer in flight
+    assert!(matches!(queries.join(&req), InFlightRole::Leader(_)));
+  }
+
+  #[tokio::test]
+  async fn followers_notified_of_failed_leader() {
+    let queries = InFlightQueries::default();
+    let req = Request::try_from(&build_query_a("www.example.com.").unwrap()).unwrap();
+
+    let leader = queries.join(&req);
+    let InFlightRole::Follower(mut rx) = queries.join(&req) else {
+      panic!("identical query must follow");
+    };
+    drop(leader);
+    assert!(rx.recv().await.is_err());
+    assert!(matches!(queries.join(&req), InFlightRole::Leader(_)));
+  }
+}
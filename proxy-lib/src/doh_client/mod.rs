@@ -1,12 +1,17 @@
 mod cache;
-mod dns_message;
+mod circuit_breaker;
+pub(crate) mod dns_message;
 mod doh_client_healthcheck;
 mod doh_client_main;
 mod error;
+mod healthcheck_schedule;
+mod hedging;
+mod in_flight;
 mod manipulation;
 mod odoh;
 mod odoh_config_store;
 mod path_manage;
+mod retry;
 
 pub use doh_client_main::DoHClient;
 pub use error::DohClientError;
@@ -24,8 +29,16 @@ pub enum DoHResponseType {
   DefaultHost,
   /// Cached response
   Cached,
+  /// Response shared with the identical query in flight
+  Coalesced,
   /// Standard response fetched from upstream
   Normal,
+  /// Refused response due to the per-client rate limit or quota
+  RateLimited,
+  /// Refused response due to the source address access control
+  AccessDenied,
+  /// Synthetic SERVFAIL response due to the failure of upstream query
+  ServFail,
 }
 
 impl std::fmt::Display for DoHResponseType {
@@ -36,7 +49,11 @@ impl std::fmt::Display for DoHResponseType {
       DoHResponseType::NotForwarded => write!(f, "NotForwarded"),
       DoHResponseType::DefaultHost => write!(f, "DefaultHost"),
       DoHResponseType::Cached => write!(f, "Cached"),
+      DoHResponseType::Coalesced => write!(f, "Coalesced"),
       DoHResponseType::Normal => write!(f, "Normal"),
+      DoHResponseType::RateLimited => write!(f, "RateLimited"),
+      DoHResponseType::AccessDenied => write!(f, "AccessDenied"),
+      DoHResponseType::ServFail => write!(f, "ServFail"),
     }
   }
 }
@@ -21,6 +21,8 @@ pub struct ODoHConfigStore {
   http_client: Arc<RwLock<HttpClientInner>>,
 }
 
+// DoHTarget is hashed only by its endpoint, not by its health flag with interior mutability
+#[allow(clippy::mutable_key_type)]
 impl ODoHConfigStore {
   /// Create a new ODoHConfigStore
   pub async fn new(http_client: Arc<RwLock<HttpClientInner>>, targets: &[Arc<DoHTarget>]) -> Result<Self, DohClientError> {
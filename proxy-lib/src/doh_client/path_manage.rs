@@ -1,13 +1,22 @@
 use super::{
+  circuit_breaker::{BreakerTransition, CircuitBreaker},
   error::{DohClientError, DohClientResult},
   DoHType,
 };
-use crate::globals::Globals;
+use crate::{
+  constants::{PATH_STATS_ERROR_PENALTY, PATH_STATS_EWMA_ALPHA},
+  globals::{CircuitBreakerConfig, PathSelection, UpstreamProfile},
+  log::*,
+};
 use itertools::Itertools;
 use rand::Rng;
-use std::sync::{
-  atomic::{AtomicBool, Ordering},
-  Arc,
+use std::{
+  future::Future,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+  },
+  time::Duration,
 };
 use url::Url;
 
@@ -35,7 +44,6 @@ impl TryFrom<&str> for Scheme {
     }
   }
 }
-#[derive(Eq, PartialEq, Hash)]
 /// DoH target resolver
 pub struct DoHTarget {
   /// authority like "dns.google:443"
@@ -44,6 +52,21 @@ pub struct DoHTarget {
   path: String,
   /// scheme
   scheme: Scheme,
+  /// health flag shared among paths to the target
+  is_healthy: IsHealthy,
+}
+impl PartialEq for DoHTarget {
+  fn eq(&self, other: &Self) -> bool {
+    self.authority == other.authority && self.path == other.path && self.scheme == other.scheme
+  }
+}
+impl Eq for DoHTarget {}
+impl std::hash::Hash for DoHTarget {
+  fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+    self.authority.hash(state);
+    self.path.hash(state);
+    self.scheme.hash(state);
+  }
 }
 impl DoHTarget {
   /// get authority
@@ -54,10 +77,14 @@ impl DoHTarget {
   pub fn scheme(&self) -> &str {
     self.scheme.as_str()
   }
+  /// check if the target is the one given by the url
+  pub fn is_url_of(&self, url: &Url) -> bool {
+    self.authority == url.authority() && self.path == url.path()
+  }
 }
 
 /// ODoH and MODoH relay
-struct DoHRelay {
+pub(super) struct DoHRelay {
   /// authority like "dns.google:443"
   authority: String,
   /// path like "/proxy" that must start from "/"
@@ -66,6 +93,45 @@ struct DoHRelay {
   scheme: Scheme,
   /// can be the next hop relay of a client
   can_be_next_hop: bool,
+  /// health flag shared among paths through the relay
+  is_healthy: IsHealthy,
+}
+
+/// Component of paths, i.e., target or relay, whose health is shared among paths containing it
+pub(super) enum Component {
+  Target(Arc<DoHTarget>),
+  Relay(Arc<DoHRelay>),
+}
+impl Component {
+  /// check if the component is healthy
+  pub(super) fn is_healthy(&self) -> bool {
+    match self {
+      Component::Target(target) => target.is_healthy.get(),
+      Component::Relay(relay) => relay.is_healthy.get(),
+    }
+  }
+  /// flag healthy off, which takes out all paths containing the component at once
+  pub(super) fn make_unhealthy(&self) {
+    match self {
+      Component::Target(target) => target.is_healthy.make_unhealthy(),
+      Component::Relay(relay) => relay.is_healthy.make_unhealthy(),
+    }
+  }
+  /// check if the given path contains the component
+  fn is_contained_in(&self, path: &DoHPath) -> bool {
+    match self {
+      Component::Target(target) => Arc::ptr_eq(target, &path.target),
+      Component::Relay(relay) => path.relays.iter().any(|v| Arc::ptr_eq(relay, v)),
+    }
+  }
+}
+impl std::fmt::Display for Component {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Component::Target(target) => write!(f, "target {}{}", target.authority, target.path),
+      Component::Relay(relay) => write!(f, "relay {}{}", relay.authority, relay.path),
+    }
+  }
 }
 
 /// struct representing a specific path to the target resolver
@@ -74,8 +140,10 @@ pub struct DoHPath {
   target: Arc<DoHTarget>,
   /// ordered list of relays, the first one must be flagged as can_be_next_hop
   relays: Vec<Arc<DoHRelay>>,
-  /// health flag
-  is_healthy: IsHealthy,
+  /// EWMA of latency and error rate
+  stats: PathStats,
+  /// passive circuit breaker
+  breaker: CircuitBreaker,
   /// doh type
   doh_type: DoHType,
 }
@@ -127,28 +195,170 @@ impl DoHPath {
     false
   }
 
-  /// check if the path is healthy
+  /// check if the path is healthy, i.e., its target and all relays are healthy
   pub fn is_healthy(&self) -> bool {
-    self.is_healthy.get()
+    self.target.is_healthy.get() && self.relays.iter().all(|relay| relay.is_healthy.get())
   }
 
-  /// flag healthy on
-  pub fn make_healthy(&self) {
-    self.is_healthy.make_healthy();
+  /// check if the components of the path other than the given one are healthy
+  pub(super) fn is_healthy_except(&self, component: &Component) -> bool {
+    let target_healthy = matches!(component, Component::Target(_)) || self.target.is_healthy.get();
+    target_healthy
+      && self
+        .relays
+        .iter()
+        .all(|relay| relay.is_healthy.get() || matches!(component, Component::Relay(v) if Arc::ptr_eq(v, relay)))
+  }
+
+  /// check if the path contains components other than the given one
+  fn has_other_components(&self, component: &Component) -> bool {
+    matches!(component, Component::Relay(_)) || !self.relays.is_empty()
+  }
+
+  /// check if the path shares any component other than the given one with another path
+  fn shares_other_components(&self, other: &DoHPath, component: &Component) -> bool {
+    let target_shared = matches!(component, Component::Relay(_)) && Arc::ptr_eq(&self.target, &other.target);
+    target_shared
+      || self.relays.iter().any(|relay| {
+        !matches!(component, Component::Relay(v) if Arc::ptr_eq(v, relay)) && other.relays.iter().any(|v| Arc::ptr_eq(v, relay))
+      })
+  }
+
+  /// check if the path contains all the components of another path other than the given one
+  fn contains_other_components_of(&self, other: &DoHPath, component: &Component) -> bool {
+    let target_contained = matches!(component, Component::Target(_)) || Arc::ptr_eq(&self.target, &other.target);
+    target_contained
+      && other
+        .relays
+        .iter()
+        .filter(|relay| !matches!(component, Component::Relay(v) if Arc::ptr_eq(v, relay)))
+        .all(|relay| self.relays.iter().any(|v| Arc::ptr_eq(v, relay)))
+  }
+
+  /// check if the path is healthy and not taken out by the circuit breaker
+  pub fn is_available(&self) -> bool {
+    self.is_healthy() && self.breaker.is_available()
   }
 
-  /// flag healthy off
-  pub fn make_unhealthy(&self) {
-    self.is_healthy.make_unhealthy();
+  /// flag healthy on for the target and all relays of the path
+  pub fn make_healthy(&self) {
+    self.target.is_healthy.make_healthy();
+    self.relays.iter().for_each(|relay| relay.is_healthy.make_healthy());
   }
 
   /// Get target
   pub fn target(&self) -> &Arc<DoHTarget> {
     &self.target
   }
+
+  /// record the latency of a successful query over the path, which also closes the circuit breaker
+  pub fn record_success(&self, latency: Duration) {
+    self.stats.record(Some(latency));
+    if let Some(BreakerTransition::Closed) = self.breaker.record_success() {
+      if let Ok(url) = self.as_url() {
+        info!("Probe query succeeded over {url}, put the path back");
+      }
+    }
+  }
+
+  /// record a failed query over the path, which may open the circuit breaker
+  pub fn record_failure(&self) {
+    self.stats.record(None);
+    if let Some(BreakerTransition::Opened) = self.breaker.record_failure() {
+      if let Ok(url) = self.as_url() {
+        warn!("Queries over {url} repeatedly failed, take the path out for a while");
+      }
+    }
+  }
+
+  /// record a query over the path cancelled before completion, e.g., as the loser of hedging,
+  /// which has taken at least the elapsed time and is fed as a slow sample without affecting the circuit breaker
+  pub fn record_cancelled(&self, elapsed: Duration) {
+    self.stats.record(Some(elapsed));
+  }
 }
 
-/// represents the health of a path
+/// Recorder of a query over the path, which records the result on finish, or the cancellation when dropped before finish.
+/// A query cancelled at or after the deadline is regarded as hung and recorded as a failure.
+pub(super) struct PathQueryRecorder<'a> {
+  path: &'a DoHPath,
+  start: tokio::time::Instant,
+  deadline: Option<tokio::time::Instant>,
+  finished: bool,
+}
+
+impl<'a> PathQueryRecorder<'a> {
+  /// start recording a query over the path
+  pub(super) fn start(path: &'a DoHPath, deadline: Option<tokio::time::Instant>) -> Self {
+    Self {
+      path,
+      start: tokio::time::Instant::now(),
+      deadline,
+      finished: false,
+    }
+  }
+
+  /// record the result of the finished query
+  pub(super) fn finish(mut self, success: bool) {
+    self.finished = true;
+    if success {
+      self.path.record_success(self.start.elapsed());
+    } else {
+      self.path.record_failure();
+    }
+  }
+}
+
+impl Drop for PathQueryRecorder<'_> {
+  fn drop(&mut self) {
+    if self.finished {
+      return;
+    }
+    if self.deadline.is_some_and(|deadline| tokio::time::Instant::now() >= deadline) {
+      self.path.record_failure();
+    } else {
+      self.path.record_cancelled(self.start.elapsed());
+    }
+  }
+}
+
+#[derive(Default)]
+/// EWMA of latency and error rate of queries over a path
+struct PathStats(Mutex<PathStatsInner>);
+
+#[derive(Default, Clone, Copy)]
+struct PathStatsInner {
+  /// EWMA of latency in msecs, which is none until the first success
+  latency_msec: Option<f64>,
+  /// EWMA of error rate in [0, 1]
+  error_rate: f64,
+}
+
+impl PathStats {
+  /// update EWMAs with the latency of a success, or a failure if none
+  fn record(&self, latency: Option<Duration>) {
+    let Ok(mut inner) = self.0.lock() else {
+      error!("Failed to lock path stats");
+      return;
+    };
+    let error = if latency.is_some() { 0.0 } else { 1.0 };
+    inner.error_rate += PATH_STATS_EWMA_ALPHA * (error - inner.error_rate);
+    if let Some(latency) = latency {
+      let latency = latency.as_secs_f64() * 1000.0;
+      inner.latency_msec = Some(match inner.latency_msec {
+        Some(v) => v + PATH_STATS_EWMA_ALPHA * (latency - v),
+        None => latency,
+      });
+    }
+  }
+
+  /// get a snapshot of EWMAs
+  fn get(&self) -> PathStatsInner {
+    self.0.lock().map(|inner| *inner).unwrap_or_default()
+  }
+}
+
+/// represents the health of a path component
 struct IsHealthy(AtomicBool);
 impl IsHealthy {
   fn new() -> Self {
@@ -176,6 +386,10 @@ pub struct DoHPathManager {
   target_randomization: bool,
   /// next-hop randomization
   nexthop_randomization: bool,
+  /// strategy to choose a path among healthy ones
+  path_selection: PathSelection,
+  /// all targets and relays contained in the paths
+  components: Vec<Component>,
 }
 impl DoHPathManager {
   /// get target list
@@ -188,6 +402,11 @@ impl DoHPathManager {
   }
   /// get a healthy path according to the randomization policy
   pub fn get_path(&self) -> Option<Arc<DoHPath>> {
+    self.get_path_excluding(&[])
+  }
+
+  /// get a healthy path other than the excluded ones according to the randomization policy
+  pub fn get_path_excluding(&self, excluded: &[Arc<DoHPath>]) -> Option<Arc<DoHPath>> {
     let healthy_paths = self
       .paths
       .iter()
@@ -197,7 +416,7 @@ impl DoHPathManager {
           .map(|per_next_hop| {
             per_next_hop
               .iter()
-              .filter(|path| path.is_healthy())
+              .filter(|path| path.is_available() && !excluded.iter().any(|v| Arc::ptr_eq(v, path)))
               .cloned()
               .collect::<Vec<_>>()
           })
@@ -211,6 +430,19 @@ impl DoHPathManager {
       return None;
     }
     let mut rng = rand::thread_rng();
+    let path = match self.path_selection {
+      PathSelection::LatencyWeighted { randomness_percent } if rng.gen_range(0..100) >= randomness_percent => {
+        self.get_weighted_path(&healthy_paths, &mut rng)?
+      }
+      _ => self.get_random_path(&healthy_paths, &mut rng),
+    };
+    // the chosen path serves as the probe if its circuit breaker is not closed
+    path.breaker.on_chosen();
+    Some(path)
+  }
+
+  /// get a healthy path uniformly at random according to the randomization policy
+  fn get_random_path(&self, healthy_paths: &[Vec<Vec<Arc<DoHPath>>>], rng: &mut impl Rng) -> Arc<DoHPath> {
     let target_idx = if self.target_randomization {
       rng.gen_range(0..healthy_paths.len())
     } else {
@@ -222,67 +454,221 @@ impl DoHPathManager {
       0
     };
     let path_idx = rng.gen_range(0..healthy_paths[target_idx][nexthop_idx].len());
-    Some(healthy_paths[target_idx][nexthop_idx][path_idx].clone())
+    healthy_paths[target_idx][nexthop_idx][path_idx].clone()
   }
 
-  /// build all possible paths without loop
-  pub fn new(globals: &Arc<Globals>) -> DohClientResult<Self> {
-    let targets = globals.proxy_config.target_config.doh_target_urls.iter().map(|url| {
-      Arc::new(DoHTarget {
-        authority: url.authority().to_string(),
-        path: url.path().to_string(),
-        scheme: Scheme::try_from(url.scheme()).unwrap_or(Scheme::Https),
+  /// get a healthy path weighted by the EWMA of latency and error rate among candidates allowed by the randomization policy
+  fn get_weighted_path(&self, healthy_paths: &[Vec<Vec<Arc<DoHPath>>>], rng: &mut impl Rng) -> Option<Arc<DoHPath>> {
+    let per_targets = if self.target_randomization {
+      healthy_paths
+    } else {
+      &healthy_paths[..1]
+    };
+    let candidates = per_targets
+      .iter()
+      .flat_map(|per_target| {
+        let per_next_hops = if self.nexthop_randomization {
+          per_target.as_slice()
+        } else {
+          &per_target[..1]
+        };
+        per_next_hops.iter().flatten()
       })
+      .map(|path| (path, path.stats.get()))
+      .collect::<Vec<_>>();
+
+    // paths not yet observed are regarded as fast as the fastest one in order to be explored
+    let fastest = candidates
+      .iter()
+      .filter_map(|(_, stats)| stats.latency_msec)
+      .fold(None, |acc: Option<f64>, v| Some(acc.map_or(v, |acc| acc.min(v))));
+    let Some(fastest) = fastest else {
+      let idx = rng.gen_range(0..candidates.len());
+      return Some(candidates[idx].0.clone());
+    };
+    let weights = candidates
+      .iter()
+      .map(|(_, stats)| {
+        let latency = stats.latency_msec.unwrap_or(fastest).max(1.0);
+        1.0 / (latency * (1.0 + PATH_STATS_ERROR_PENALTY * stats.error_rate))
+      })
+      .collect::<Vec<_>>();
+
+    let mut point = rng.gen_range(0.0..weights.iter().sum::<f64>());
+    for ((path, _), weight) in candidates.iter().zip(weights.iter()) {
+      if point < *weight {
+        return Some((*path).clone());
+      }
+      point -= weight;
+    }
+    candidates.last().map(|(path, _)| (*path).clone())
+  }
+
+  /// check if all paths are unhealthy
+  pub(super) fn is_all_unhealthy(&self) -> bool {
+    !self.paths.iter().flatten().flatten().any(|path| path.is_healthy())
+  }
+
+  /// get all targets and relays contained in the paths
+  pub(super) fn components(&self) -> &[Component] {
+    &self.components
+  }
+
+  /// get a path to probe the given component, preferring paths whose other components are healthy
+  /// so that the failure of the probe is attributed to the component
+  pub(super) fn get_probe_path(&self, component: &Component) -> Option<Arc<DoHPath>> {
+    let containing = self
+      .paths
+      .iter()
+      .flatten()
+      .flatten()
+      .filter(|path| component.is_contained_in(path))
+      .collect::<Vec<_>>();
+    Self::choose_preferring(containing, |path| path.is_healthy_except(component))
+  }
+
+  /// Confirm that the failed probe via the path is attributed to the component, since the other components of the path
+  /// may have gone down after their last health checks. The component is re-probed via a path sharing only the component
+  /// with the failed one, whose failure confirms it. If no such path exists, the other components of the failed path
+  /// are probed via a path without the component, whose success confirms it. Otherwise, the failure is not attributed.
+  /// The probe returns if it succeeded.
+  pub(super) async fn confirm_failure<F, Fut>(&self, component: &Component, failed: &DoHPath, probe: F) -> bool
+  where
+    F: Fn(Arc<DoHPath>) -> Fut,
+    Fut: Future<Output = bool>,
+  {
+    if !failed.has_other_components(component) {
+      return true;
+    }
+    let paths = self.paths.iter().flatten().flatten();
+    let disjoint = paths
+      .clone()
+      .filter(|path| component.is_contained_in(path) && !path.shares_other_components(failed, component))
+      .collect::<Vec<_>>();
+    if let Some(path) = Self::choose_preferring(disjoint, |path| path.is_healthy_except(component)) {
+      return !probe(path).await;
+    }
+    let control = paths
+      .filter(|path| !component.is_contained_in(path) && path.contains_other_components_of(failed, component))
+      .collect::<Vec<_>>();
+    if let Some(path) = Self::choose_preferring(control, |path| path.is_healthy()) {
+      return probe(path).await;
+    }
+    false
+  }
+
+  /// choose a path randomly among the candidates, preferring those satisfying the condition
+  fn choose_preferring(candidates: Vec<&Arc<DoHPath>>, prefer: impl Fn(&DoHPath) -> bool) -> Option<Arc<DoHPath>> {
+    let preferred = candidates.iter().filter(|path| prefer(path)).cloned().collect::<Vec<_>>();
+    let candidates = if preferred.is_empty() { candidates } else { preferred };
+    if candidates.is_empty() {
+      return None;
+    }
+    let idx = rand::thread_rng().gen_range(0..candidates.len());
+    Some(candidates[idx].clone())
+  }
+
+  /// collect targets and relays shared among paths without duplicates
+  fn collect_components(paths: &[Vec<Vec<Arc<DoHPath>>>]) -> Vec<Component> {
+    let mut targets: Vec<Arc<DoHTarget>> = vec![];
+    let mut relays: Vec<Arc<DoHRelay>> = vec![];
+    paths.iter().flatten().flatten().for_each(|path| {
+      if !targets.iter().any(|v| Arc::ptr_eq(v, &path.target)) {
+        targets.push(path.target.clone());
+      }
+      path.relays.iter().for_each(|relay| {
+        if !relays.iter().any(|v| Arc::ptr_eq(v, relay)) {
+          relays.push(relay.clone());
+        }
+      });
     });
+    targets
+      .into_iter()
+      .map(Component::Target)
+      .chain(relays.into_iter().map(Component::Relay))
+      .collect()
+  }
+
+  /// build all possible paths without loop
+  pub fn new(profile: &UpstreamProfile, breaker_config: &CircuitBreakerConfig) -> DohClientResult<Self> {
+    // targets and relays are shared among paths to track their health
+    let targets = profile
+      .target_config
+      .doh_target_urls
+      .iter()
+      .map(|url| {
+        Arc::new(DoHTarget {
+          authority: url.authority().to_string(),
+          path: url.path().to_string(),
+          scheme: Scheme::try_from(url.scheme()).unwrap_or(Scheme::Https),
+          is_healthy: IsHealthy::new(),
+        })
+      })
+      .collect::<Vec<_>>();
 
     // standard doh
-    if globals.proxy_config.nexthop_relay_config.is_none() {
+    if profile.nexthop_relay_config.is_none() {
       let paths = targets
+        .into_iter()
         .map(|target| {
           vec![vec![Arc::new(DoHPath {
             target,
             relays: vec![],
-            is_healthy: IsHealthy::new(),
+            stats: PathStats::default(),
+            breaker: CircuitBreaker::new(breaker_config),
             doh_type: DoHType::Standard,
           })]]
         })
         .collect::<Vec<_>>();
+      let components = Self::collect_components(&paths);
       return Ok(Self {
         paths,
-        target_randomization: globals.proxy_config.target_config.target_randomization,
+        target_randomization: profile.target_config.target_randomization,
         nexthop_randomization: false,
+        path_selection: profile.target_config.path_selection,
+        components,
       });
     }
 
     // odoh and modoh
-    let nexthop_relay_config = globals.proxy_config.nexthop_relay_config.as_ref().unwrap();
-    let nexthops = nexthop_relay_config.odoh_relay_urls.iter().map(|url| {
-      Arc::new(DoHRelay {
-        authority: url.authority().to_string(),
-        path: url.path().to_string(),
-        scheme: Scheme::try_from(url.scheme()).unwrap_or(Scheme::Https),
-        can_be_next_hop: true,
-      })
-    });
-    let subseq_relay_config = globals.proxy_config.subseq_relay_config.as_ref();
-    let subseq_relay_paths = subseq_relay_config.map(|v| {
-      let subseq_relays = v.mid_relay_urls.iter().map(|url| {
+    let nexthop_relay_config = profile.nexthop_relay_config.as_ref().unwrap();
+    let nexthops = nexthop_relay_config
+      .odoh_relay_urls
+      .iter()
+      .map(|url| {
         Arc::new(DoHRelay {
           authority: url.authority().to_string(),
           path: url.path().to_string(),
           scheme: Scheme::try_from(url.scheme()).unwrap_or(Scheme::Https),
-          can_be_next_hop: false,
+          can_be_next_hop: true,
+          is_healthy: IsHealthy::new(),
         })
-      });
+      })
+      .collect::<Vec<_>>();
+    let subseq_relay_config = profile.subseq_relay_config.as_ref();
+    let subseq_relay_paths = subseq_relay_config.map(|v| {
+      let subseq_relays = v
+        .mid_relay_urls
+        .iter()
+        .map(|url| {
+          Arc::new(DoHRelay {
+            authority: url.authority().to_string(),
+            path: url.path().to_string(),
+            scheme: Scheme::try_from(url.scheme()).unwrap_or(Scheme::Https),
+            can_be_next_hop: false,
+            is_healthy: IsHealthy::new(),
+          })
+        })
+        .collect::<Vec<_>>();
       let max = v.max_mid_relays.min(subseq_relays.len());
       let mut paths_after_nexthop = vec![];
       (0..max + 1).for_each(|num| {
-        let x: Vec<_> = subseq_relays.clone().permutations(num).collect();
+        let x: Vec<_> = subseq_relays.iter().cloned().permutations(num).collect();
         paths_after_nexthop.extend(x);
       });
       paths_after_nexthop
     });
-    let relay_paths = nexthops.clone().map(|nexthop| {
+    let relay_paths = nexthops.iter().map(|nexthop| {
       let relays = match &subseq_relay_paths {
         None => vec![vec![nexthop.clone()]],
         Some(subseq_relay_paths) => subseq_relay_paths
@@ -298,7 +684,7 @@ impl DoHPathManager {
     });
 
     // build path object
-    let maybe_looped_paths = targets.map(|target| {
+    let maybe_looped_paths = targets.iter().map(|target| {
       relay_paths
         .clone()
         .map(|relay_path| {
@@ -308,7 +694,8 @@ impl DoHPathManager {
               Arc::new(DoHPath {
                 target: target.clone(),
                 relays: relays.clone(),
-                is_healthy: IsHealthy::new(),
+                stats: PathStats::default(),
+                breaker: CircuitBreaker::new(breaker_config),
                 doh_type: DoHType::Oblivious,
               })
             })
@@ -332,10 +719,13 @@ impl DoHPathManager {
       .filter(|per_target| !per_target.is_empty())
       .collect::<Vec<_>>();
 
+    let components = Self::collect_components(&loop_free_paths);
     Ok(Self {
       paths: loop_free_paths,
-      target_randomization: globals.proxy_config.target_config.target_randomization,
+      target_randomization: profile.target_config.target_randomization,
       nexthop_randomization: nexthop_relay_config.odoh_relay_randomization,
+      path_selection: profile.target_config.path_selection,
+      components,
     })
   }
 }
@@ -351,29 +741,34 @@ mod tests {
       authority: "dns.google".to_string(),
       path: "/dns-query".to_string(),
       scheme: Scheme::Https,
+      is_healthy: IsHealthy::new(),
     });
     let relay1 = Arc::new(DoHRelay {
       authority: "relay1.dns.google".to_string(),
       path: "/proxy".to_string(),
       scheme: Scheme::Https,
       can_be_next_hop: true,
+      is_healthy: IsHealthy::new(),
     });
     let relay2 = Arc::new(DoHRelay {
       authority: "relay2.dns.google".to_string(),
       path: "/proxy".to_string(),
       scheme: Scheme::Https,
       can_be_next_hop: false,
+      is_healthy: IsHealthy::new(),
     });
     let relay3 = Arc::new(DoHRelay {
       authority: "relay3.dns.google".to_string(),
       path: "/proxy".to_string(),
       scheme: Scheme::Https,
       can_be_next_hop: false,
+      is_healthy: IsHealthy::new(),
     });
     let path = Arc::new(DoHPath {
       target,
       relays: vec![relay1, relay2, relay3],
-      is_healthy: IsHealthy::new(),
+      stats: PathStats::default(),
+      breaker: CircuitBreaker::new(&CircuitBreakerConfig::default()),
       doh_type: DoHType::Oblivious,
     });
     let url = path.as_url().unwrap();
@@ -388,29 +783,34 @@ mod tests {
       authority: "dns.google".to_string(),
       path: "/dns-query".to_string(),
       scheme: Scheme::Https,
+      is_healthy: IsHealthy::new(),
     });
     let relay1 = Arc::new(DoHRelay {
       authority: "relay1.dns.google".to_string(),
       path: "/proxy".to_string(),
       scheme: Scheme::Https,
       can_be_next_hop: true,
+      is_healthy: IsHealthy::new(),
     });
     let relay2 = Arc::new(DoHRelay {
       authority: "relay2.dns.google".to_string(),
       path: "/proxy".to_string(),
       scheme: Scheme::Https,
       can_be_next_hop: false,
+      is_healthy: IsHealthy::new(),
     });
     let relay3 = Arc::new(DoHRelay {
       authority: "relay3.dns.google".to_string(),
       path: "/proxy".to_string(),
       scheme: Scheme::Https,
       can_be_next_hop: false,
+      is_healthy: IsHealthy::new(),
     });
     let mut path = DoHPath {
       target,
       relays: vec![relay1, relay2, relay3],
-      is_healthy: IsHealthy::new(),
+      stats: PathStats::default(),
+      breaker: CircuitBreaker::new(&CircuitBreakerConfig::default()),
       doh_type: DoHType::Oblivious,
     };
     assert!(!path.is_looped());
@@ -420,9 +820,189 @@ mod tests {
       path: "/proxy".to_string(),
       scheme: Scheme::Https,
       can_be_next_hop: true,
+      is_healthy: IsHealthy::new(),
     });
 
     path.relays.push(relay4);
     assert!(path.is_looped());
   }
+
+  #[test]
+  fn weighted_path_selection_prefers_fast_path() {
+    let mut profile = UpstreamProfile::default();
+    profile.target_config.doh_target_urls = vec![
+      "https://fast.example.com/dns-query".parse().unwrap(),
+      "https://slow.example.com/dns-query".parse().unwrap(),
+    ];
+    profile.target_config.path_selection = PathSelection::LatencyWeighted { randomness_percent: 0 };
+    // circuit breaker never opens to see the weights only
+    let breaker_config = CircuitBreakerConfig {
+      failure_threshold: u32::MAX,
+      ..Default::default()
+    };
+    let manager = DoHPathManager::new(&profile, &breaker_config).unwrap();
+    let fast = manager.paths[0][0][0].clone();
+    let slow = manager.paths[1][0][0].clone();
+    fast.record_success(Duration::from_millis(100));
+    slow.record_success(Duration::from_millis(300));
+
+    let fast_cnt = (0..1000).filter(|_| Arc::ptr_eq(&manager.get_path().unwrap(), &fast)).count();
+    assert!(fast_cnt > 650);
+
+    // failing path is avoided even if it is fast
+    (0..10).for_each(|_| fast.record_failure());
+    let fast_cnt = (0..1000).filter(|_| Arc::ptr_eq(&manager.get_path().unwrap(), &fast)).count();
+    assert!(fast_cnt < 500);
+  }
+
+  #[test]
+  fn dead_relay_takes_out_its_paths() {
+    let mut profile = UpstreamProfile::default();
+    profile.target_config.doh_target_urls = vec![
+      "https://target1.example.com/dns-query".parse().unwrap(),
+      "https://target2.example.com/dns-query".parse().unwrap(),
+    ];
+    profile.nexthop_relay_config = Some(crate::globals::NextHopRelayConfig {
+      odoh_relay_urls: vec![
+        "https://relay1.example.com/proxy".parse().unwrap(),
+        "https://relay2.example.com/proxy".parse().unwrap(),
+      ],
+      odoh_relay_randomization: true,
+    });
+    let manager = DoHPathManager::new(&profile, &CircuitBreakerConfig::default()).unwrap();
+    // 2 targets and 2 relays shared among 4 paths
+    assert_eq!(manager.components().len(), 4);
+
+    let relay1 = manager
+      .components()
+      .iter()
+      .find(|c| c.to_string() == "relay relay1.example.com/proxy")
+      .unwrap();
+    relay1.make_unhealthy();
+    let healthy = manager.paths.iter().flatten().flatten().filter(|p| p.is_healthy()).count();
+    assert_eq!(healthy, 2);
+    (0..100).for_each(|_| assert!(!relay1.is_contained_in(&manager.get_path().unwrap())));
+
+    // target is probed via the healthy relay so that a failure is attributed to the target
+    let target1 = &manager.components()[0];
+    (0..100).for_each(|_| {
+      let probe_path = manager.get_probe_path(target1).unwrap();
+      assert!(target1.is_contained_in(&probe_path));
+      assert!(!relay1.is_contained_in(&probe_path));
+    });
+
+    // successful probe via the relay makes it healthy again
+    manager.get_probe_path(relay1).unwrap().make_healthy();
+    assert!(relay1.is_healthy());
+  }
+
+  #[tokio::test]
+  async fn failure_via_dead_relay_is_not_attributed_to_target() {
+    let mut profile = UpstreamProfile::default();
+    profile.target_config.doh_target_urls = vec![
+      "https://target1.example.com/dns-query".parse().unwrap(),
+      "https://target2.example.com/dns-query".parse().unwrap(),
+    ];
+    profile.nexthop_relay_config = Some(crate::globals::NextHopRelayConfig {
+      odoh_relay_urls: vec![
+        "https://relay1.example.com/proxy".parse().unwrap(),
+        "https://relay2.example.com/proxy".parse().unwrap(),
+      ],
+      odoh_relay_randomization: true,
+    });
+    let manager = DoHPathManager::new(&profile, &CircuitBreakerConfig::default()).unwrap();
+    let target1 = &manager.components()[0];
+    let relay1 = manager
+      .components()
+      .iter()
+      .find(|c| c.to_string() == "relay relay1.example.com/proxy")
+      .unwrap();
+    let failed = manager
+      .paths
+      .iter()
+      .flatten()
+      .flatten()
+      .find(|p| target1.is_contained_in(p) && relay1.is_contained_in(p))
+      .unwrap();
+
+    // relay1 went down but is still flagged healthy, and target1 is re-probed via relay2
+    let probed = Mutex::new(vec![]);
+    let confirmed = manager
+      .confirm_failure(target1, failed, |path| {
+        probed.lock().unwrap().push(path.clone());
+        std::future::ready(!relay1.is_contained_in(&path))
+      })
+      .await;
+    assert!(!confirmed);
+    let probed = probed.into_inner().unwrap();
+    assert_eq!(probed.len(), 1);
+    assert!(target1.is_contained_in(&probed[0]) && !relay1.is_contained_in(&probed[0]));
+
+    // target1 is really down
+    let confirmed = manager
+      .confirm_failure(target1, failed, |path| std::future::ready(!target1.is_contained_in(&path)))
+      .await;
+    assert!(confirmed);
+  }
+
+  #[tokio::test]
+  async fn failure_is_confirmed_via_control_path() {
+    let mut profile = UpstreamProfile::default();
+    profile.target_config.doh_target_urls = vec![
+      "https://target1.example.com/dns-query".parse().unwrap(),
+      "https://target2.example.com/dns-query".parse().unwrap(),
+    ];
+    profile.nexthop_relay_config = Some(crate::globals::NextHopRelayConfig {
+      odoh_relay_urls: vec!["https://relay1.example.com/proxy".parse().unwrap()],
+      odoh_relay_randomization: true,
+    });
+    let manager = DoHPathManager::new(&profile, &CircuitBreakerConfig::default()).unwrap();
+    let target1 = &manager.components()[0];
+    let relay1 = &manager.components()[2];
+    let failed = manager.get_probe_path(target1).unwrap();
+
+    // no other relay, so that the failure is attributed to target1 only if the relay works with target2
+    let confirmed = manager
+      .confirm_failure(target1, &failed, |path| std::future::ready(!target1.is_contained_in(&path)))
+      .await;
+    assert!(confirmed);
+    let confirmed = manager
+      .confirm_failure(target1, &failed, |path| std::future::ready(!relay1.is_contained_in(&path)))
+      .await;
+    assert!(!confirmed);
+
+    // failure of a direct path is attributed to the target without confirmation
+    let mut profile = UpstreamProfile::default();
+    profile.target_config.doh_target_urls = vec!["https://target1.example.com/dns-query".parse().unwrap()];
+    let manager = DoHPathManager::new(&profile, &CircuitBreakerConfig::default()).unwrap();
+    let target1 = &manager.components()[0];
+    let failed = manager.get_probe_path(target1).unwrap();
+    assert!(manager.confirm_failure(target1, &failed, |_| std::future::ready(true)).await);
+  }
+
+  #[tokio::test]
+  async fn cancelled_query_is_recorded() {
+    let mut profile = UpstreamProfile::default();
+    profile.target_config.doh_target_urls = vec!["https://target.example.com/dns-query".parse().unwrap()];
+    let breaker_config = CircuitBreakerConfig {
+      failure_threshold: 1,
+      ..Default::default()
+    };
+    let manager = DoHPathManager::new(&profile, &breaker_config).unwrap();
+    let path = manager.get_path().unwrap();
+
+    // cancelled before the deadline, e.g., by hedging, as a slow sample
+    let recorder = PathQueryRecorder::start(&path, Some(tokio::time::Instant::now() + Duration::from_secs(10)));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    drop(recorder);
+    assert!(path.stats.get().latency_msec.unwrap() >= 50.0);
+    assert_eq!(path.stats.get().error_rate, 0.0);
+    assert!(path.is_available());
+
+    // cancelled at the deadline as a failure, which opens the circuit breaker
+    let recorder = PathQueryRecorder::start(&path, Some(tokio::time::Instant::now()));
+    drop(recorder);
+    assert!(path.stats.get().error_rate > 0.0);
+    assert!(!path.is_available());
+  }
 }
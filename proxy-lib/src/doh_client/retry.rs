@@ -0,0 +1,220 @@
+use super::{
+  error::{DohClientError, DohClientResult},
+  path_manage::DoHPath,
+};
+use crate::log::*;
+use hickory_proto::op::{Message, ResponseCode};
+use std::{future::Future, sync::Arc};
+use tokio::time::Instant;
+
+/// Response to an upstream query with the path that answered
+pub(super) type UpstreamResponse = (Vec<u8>, Message, Arc<DoHPath>);
+
+/// Retry policy of upstream queries over paths not tried yet
+pub(super) struct UpstreamRetry {
+  /// max number of retries after the first attempt
+  max_retries: usize,
+  /// retry the query answered with SERVFAIL as well as the failed one
+  on_servfail: bool,
+}
+
+impl UpstreamRetry {
+  /// Create a new retry policy
+  pub(super) fn new(max_retries: usize, on_servfail: bool) -> Self {
+    Self {
+      max_retries,
+      on_servfail,
+    }
+  }
+
+  /// Run attempts of an upstream query within the retry budget as long as the deadline is not exceeded.
+  /// Each attempt is given the paths tried so far, and returns them with the paths it used added, so that retries go over other paths.
+  /// If every attempt fails, the last SERVFAIL response is returned if any, and otherwise the last error.
+  pub(super) async fn run<F, Fut>(&self, deadline: Instant, mut attempt: F) -> DohClientResult<UpstreamResponse>
+  where
+    F: FnMut(Vec<Arc<DoHPath>>) -> Fut,
+    Fut: Future<Output = (Vec<Arc<DoHPath>>, DohClientResult<UpstreamResponse>)>,
+  {
+    let mut tried = vec![];
+    let mut last_servfail = None;
+    let mut last_error = None;
+
+    for cnt in 0..=self.max_retries {
+      if cnt > 0 {
+        debug!("Retry upstream query over another path ({}/{})", cnt, self.max_retries);
+      }
+      let Ok((paths, res)) = tokio::time::timeout_at(deadline, attempt(tried)).await else {
+        warn!("Upstream query timed out after {} attempt(s)", cnt + 1);
+        last_error = Some(DohClientError::UpstreamTimeout);
+        break;
+      };
+      tried = paths;
+      match res {
+        Ok((response_buf, response_message, path)) if response_message.response_code() == ResponseCode::ServFail => {
+          if let Ok(url) = path.as_url() {
+            debug!("Upstream query answered with SERVFAIL via {url}");
+          }
+          if !self.on_servfail {
+            return Ok((response_buf, response_message, path));
+          }
+          last_servfail = Some((response_buf, response_message, path));
+        }
+        Ok(res) => return Ok(res),
+        Err(DohClientError::NoPathAvailable) => {
+          // no more path to try, where the error of the previous attempt is preferred
+          last_error.get_or_insert(DohClientError::NoPathAvailable);
+          break;
+        }
+        Err(e) => {
+          debug!("Upstream query failed: {e}");
+          last_error = Some(e);
+        }
+      }
+    }
+
+    match (last_servfail, last_error) {
+      (Some(res), _) => Ok(res),
+      (None, Some(e)) => Err(e),
+      (None, None) => Err(DohClientError::NoPathAvailable),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    doh_client::path_manage::DoHPathManager,
+    globals::{CircuitBreakerConfig, UpstreamProfile},
+  };
+  use std::collections::VecDeque;
+  use tokio::time::Duration;
+
+  /// path manager with three targets, i.e., three paths
+  fn path_manager() -> DoHPathManager {
+    let mut profile = UpstreamProfile::default();
+    profile.target_config.doh_target_urls = vec![
+      "https://target1.example.com/dns-query".parse().unwrap(),
+      "https://target2.example.com/dns-query".parse().unwrap(),
+      "https://target3.example.com/dns-query".parse().unwrap(),
+    ];
+    DoHPathManager::new(&profile, &CircuitBreakerConfig::default()).unwrap()
+  }
+
+  fn response(rcode: ResponseCode) -> Message {
+    let mut msg = Message::new();
+    msg.set_response_code(rcode);
+    msg
+  }
+
+  /// Run the retry policy over the path manager, where attempts are answered with the given response codes in order,
+  /// or fail if none. Return the result and the paths used by the attempts.
+  async fn run_scripted(
+    retry: &UpstreamRetry,
+    manager: &DoHPathManager,
+    script: Vec<Option<ResponseCode>>,
+  ) -> (DohClientResult<UpstreamResponse>, Vec<Arc<DoHPath>>) {
+    let mut script = VecDeque::from(script);
+    let mut used = vec![];
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let res = retry
+      .run(deadline, |mut tried| {
+        let path = manager.get_path_excluding(&tried);
+        if let Some(path) = &path {
+          assert!(!tried.iter().any(|v| Arc::ptr_eq(v, path)));
+          tried.push(path.clone());
+          used.push(path.clone());
+        }
+        let rcode = script.pop_front().flatten();
+        async move {
+          let res = match (path, rcode) {
+            (None, _) => Err(DohClientError::NoPathAvailable),
+            (Some(path), Some(rcode)) => Ok((vec![], response(rcode), path)),
+            (Some(_), None) => Err(DohClientError::DoHQueryError),
+          };
+          (tried, res)
+        }
+      })
+      .await;
+    (res, used)
+  }
+
+  #[tokio::test]
+  async fn retry_within_budget_over_other_paths() {
+    let manager = path_manager();
+
+    let (res, used) = run_scripted(&UpstreamRetry::new(1, false), &manager, vec![None, None, None]).await;
+    assert!(matches!(res, Err(DohClientError::DoHQueryError)));
+    assert_eq!(used.len(), 2);
+    assert!(!Arc::ptr_eq(&used[0], &used[1]));
+
+    let (res, used) = run_scripted(
+      &UpstreamRetry::new(2, false),
+      &manager,
+      vec![None, Some(ResponseCode::NoError)],
+    )
+    .await;
+    assert_eq!(res.unwrap().1.response_code(), ResponseCode::NoError);
+    assert_eq!(used.len(), 2);
+
+    // all paths are tried, where the error of the last attempt is preferred to no path available
+    let (res, used) = run_scripted(&UpstreamRetry::new(5, false), &manager, vec![None; 6]).await;
+    assert!(matches!(res, Err(DohClientError::DoHQueryError)));
+    assert_eq!(used.len(), 3);
+
+    let (res, used) = run_scripted(&UpstreamRetry::new(0, false), &manager, vec![None, None]).await;
+    assert!(matches!(res, Err(DohClientError::DoHQueryError)));
+    assert_eq!(used.len(), 1);
+  }
+
+  #[tokio::test]
+  async fn servfail_is_retried_only_if_enabled() {
+    let manager = path_manager();
+    let servfail = Some(ResponseCode::ServFail);
+
+    let (res, used) = run_scripted(&UpstreamRetry::new(2, false), &manager, vec![servfail, None]).await;
+    assert_eq!(res.unwrap().1.response_code(), ResponseCode::ServFail);
+    assert_eq!(used.len(), 1);
+
+    let (res, used) = run_scripted(
+      &UpstreamRetry::new(2, true),
+      &manager,
+      vec![servfail, Some(ResponseCode::NXDomain)],
+    )
+    .await;
+    assert_eq!(res.unwrap().1.response_code(), ResponseCode::NXDomain);
+    assert_eq!(used.len(), 2);
+
+    // SERVFAIL is preferred to the error of a later attempt
+    let (res, used) = run_scripted(&UpstreamRetry::new(2, true), &manager, vec![servfail, None, None]).await;
+    let (_, msg, path) = res.unwrap();
+    assert_eq!(msg.response_code(), ResponseCode::ServFail);
+    assert!(Arc::ptr_eq(&path, &used[0]));
+    assert_eq!(used.len(), 3);
+  }
+
+  #[tokio::test]
+  async fn retry_stops_at_deadline() {
+    let manager = path_manager();
+    let retry = UpstreamRetry::new(2, true);
+    let mut cnt = 0;
+    let deadline = Instant::now() + Duration::from_millis(50);
+    let res = retry
+      .run(deadline, |mut tried| {
+        cnt += 1;
+        let path = manager.get_path_excluding(&tried).unwrap();
+        tried.push(path.clone());
+        async move {
+          // first attempt fails quickly, and the second one hangs
+          if tried.len() > 1 {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+          }
+          (tried, Err(DohClientError::DoHQueryError))
+        }
+      })
+      .await;
+    assert!(matches!(res, Err(DohClientError::UpstreamTimeout)));
+    assert_eq!(cnt, 2);
+    assert!(Instant::now() < deadline + Duration::from_secs(1));
+  }
+}
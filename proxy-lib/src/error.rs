@@ -1,6 +1,3 @@
-use std::net::SocketAddr;
-use tokio::sync::mpsc::error::SendError;
-
 pub use crate::auth::AuthenticatorError;
 pub use crate::doh_client::DohClientError;
 pub use crate::http_client::HttpClientError;
@@ -26,8 +23,8 @@ pub enum Error {
   ServiceDown(String),
   #[error("Proxy service exited: {0}")]
   ProxyServiceError(String),
-  #[error("Query log service exited")]
-  QueryLogServiceError,
+  #[error("Unknown upstream profile: {0}")]
+  UnknownUpstreamProfile(String),
 
   /* -- bootstarp dns -- */
   #[error("Bootstrap dns client error: {0}")]
@@ -49,11 +46,27 @@ pub enum Error {
   #[error("Udp channel send timeout")]
   UdpChannelSendTimeout,
   #[error("Udp channel send error")]
-  UdpChannelSendError(#[from] SendError<(Vec<u8>, SocketAddr)>),
+  UdpChannelSendError,
   #[error("Invalid DNS response size")]
   InvalidDnsResponseSize,
   #[error("Too many connections")]
   TooManyConnections,
   #[error("Failed to make DoH query")]
   FailedToMakeDohQuery,
+  #[error("Invalid TLS configuration: {0}")]
+  InvalidTlsConfig(String),
+  #[error("TLS error: {0}")]
+  TlsError(#[from] tokio_rustls::rustls::Error),
+  #[error("TLS handshake timeout")]
+  TlsHandshakeTimeout,
+  #[error("DoH server error: {0}")]
+  DohServerError(String),
+  #[error("DoQ error: {0}")]
+  DoqError(String),
+  #[error("Invalid DoQ stream")]
+  InvalidDoqStream,
+  #[error("Invalid PROXY protocol header")]
+  InvalidProxyProtocolHeader,
+  #[error("PROXY protocol header timeout")]
+  ProxyProtocolHeaderTimeout,
 }
@@ -1,6 +1,10 @@
 use crate::{bootstrap::BootstrapDnsInner, constants::*, QueryLoggingBase};
-use std::{net::SocketAddr, sync::Arc};
-use tokio::{sync::Notify, time::Duration};
+use ipnet::IpNet;
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc};
+use tokio::{
+  sync::{watch, Notify},
+  time::Duration,
+};
 use url::Url;
 
 #[derive(Debug)]
@@ -17,16 +21,30 @@ pub struct Globals {
 
   /// query logger sender
   pub query_log_tx: crossbeam_channel::Sender<QueryLoggingBase>,
+
+  /// receiver of the signal to drain in-flight queries on termination,
+  /// which makes persistent connections stop receiving further queries
+  pub drain_rx: watch::Receiver<bool>,
 }
 
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub struct ProxyConfig {
   /// listen addresses
   pub listen_addresses: Vec<SocketAddr>,
+  /// listen addresses for DNS over TLS (DoT)
+  pub dot_listen_addresses: Vec<SocketAddr>,
+  /// listen addresses for DNS over HTTPS (DoH) server
+  pub doh_listen_addresses: Vec<SocketAddr>,
+  /// listen addresses for DNS over QUIC (DoQ)
+  pub doq_listen_addresses: Vec<SocketAddr>,
+  /// paths of Unix domain sockets to listen on
+  pub unix_listen_paths: Vec<PathBuf>,
+  /// permissions of Unix domain socket files like 0o660, which are left as created if not specified
+  pub unix_socket_mode: Option<u32>,
+  /// path of DoH server like "/dns-query"
+  pub doh_server_path: String,
   /// maximum number of connections
   pub max_connections: usize,
-  /// maximum cache size
-  pub max_cache_size: usize,
 
   /// bootstrap DNS
   pub bootstrap_dns: BootstrapDns,
@@ -34,9 +52,16 @@ pub struct ProxyConfig {
   pub endpoint_resolution_period_sec: Duration,
   /// health check period
   pub healthcheck_period_sec: Duration,
+  /// probes of health check, where paths to targets not covered by target-specific probes are checked by generic ones
+  pub healthcheck_probes: Vec<HealthcheckProbe>,
+  /// max number of health check probes executed concurrently
+  pub healthcheck_max_concurrency: usize,
+  /// max number of consecutive watches finding all paths unhealthy before the DoH client gives up, which is unlimited if none.
+  /// until then, queries are answered from cache or with SERVFAIL.
+  pub max_all_unhealthy_retry: Option<usize>,
 
   // udp and tcp proxy setting
-  /// UDP buffer size
+  /// UDP buffer size, which also limits the UDP payload size of responses
   pub udp_buffer_size: usize,
   /// UDP channel capacity
   pub udp_channel_capacity: usize,
@@ -44,13 +69,59 @@ pub struct ProxyConfig {
   pub udp_timeout_sec: Duration,
   /// TCP listen backlog
   pub tcp_listen_backlog: u32,
+  /// idle timeout of persistent TCP, DoT and DoH connections
+  pub tcp_idle_timeout_sec: Duration,
+  /// grace period to drain in-flight queries on termination
+  pub drain_grace_period_sec: Duration,
+  /// transparent mode for plaintext UDP and TCP listeners to intercept queries redirected by TPROXY rules,
+  /// where responses are sent back from the original destination addresses
+  pub transparent_mode: bool,
+  /// source networks of load balancers trusted to send PROXY protocol header over TCP, which is disabled if empty
+  pub proxy_protocol_trusted_sources: Vec<IpNet>,
+
+  /// TLS server settings for encrypted listeners
+  pub tls_config: Option<TlsConfig>,
 
   /// timeout for HTTP requests (DoH, ODoH, and authentication requests)
   pub http_timeout_sec: Duration,
 
+  /// max number of retries over other paths when an upstream query fails, or is answered with SERVFAIL if enabled
+  pub max_upstream_retries: usize,
+  /// retry upstream queries answered with SERVFAIL as well as failed ones,
+  /// which is disabled by default since it sends the query to more targets and relays, e.g., in anonymization
+  pub retry_upstream_on_servfail: bool,
+  /// hedging settings to send the same query over another path when the first one has not answered, which is disabled if none
+  pub hedging_config: Option<HedgingConfig>,
+  /// passive circuit breaker settings per path fed by the results of queries
+  pub circuit_breaker_config: CircuitBreakerConfig,
+
   /// http user agent
   pub http_user_agent: String,
 
+  /// upstream settings of listeners not bound to any named upstream profile
+  pub upstream: UpstreamProfile,
+  /// named upstream profiles
+  pub upstream_profiles: HashMap<String, UpstreamProfile>,
+  /// names of upstream profiles bound to specific listen addresses
+  pub listener_profiles: HashMap<SocketAddr, String>,
+  /// names of upstream profiles bound to specific Unix domain socket paths
+  pub unix_listener_profiles: HashMap<PathBuf, String>,
+
+  /// per-client rate limit settings
+  pub rate_limit_config: Option<RateLimitConfig>,
+
+  /// source address access control list applied to all listeners
+  pub access_control_config: Option<AccessControlConfig>,
+  /// source address access control lists for specific listen addresses, applied in addition to the global one
+  pub listener_access_control_configs: HashMap<SocketAddr, AccessControlConfig>,
+  /// action for queries from denied sources
+  pub access_denied_action: AccessDeniedAction,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+/// Upstream settings of the DoH client, i.e., targets, relays, authentication, query manipulation plugins and cache.
+/// A DoH client is built for each profile, and listeners are bound to one of them.
+pub struct UpstreamProfile {
   /// doh, odoh, modoh target settings
   pub target_config: TargetConfig,
 
@@ -65,6 +136,105 @@ pub struct ProxyConfig {
 
   /// query manipulation settings
   pub query_manipulation_config: Option<Arc<QueryManipulationConfig>>,
+
+  /// maximum cache size
+  pub max_cache_size: usize,
+}
+
+impl ProxyConfig {
+  /// Get the name of the upstream profile bound to the listen address, where `DEFAULT_UPSTREAM_PROFILE` means `upstream`
+  pub(crate) fn profile_name_for(&self, listening_on: &SocketAddr) -> &str {
+    self
+      .listener_profiles
+      .get(listening_on)
+      .map(String::as_str)
+      .unwrap_or(DEFAULT_UPSTREAM_PROFILE)
+  }
+
+  /// Get the name of the upstream profile bound to the Unix domain socket path
+  pub(crate) fn profile_name_for_unix(&self, path: &PathBuf) -> &str {
+    self
+      .unix_listener_profiles
+      .get(path)
+      .map(String::as_str)
+      .unwrap_or(DEFAULT_UPSTREAM_PROFILE)
+  }
+
+  /// Get the upstream profile by name
+  pub(crate) fn upstream_profile(&self, name: &str) -> Option<&UpstreamProfile> {
+    if name == DEFAULT_UPSTREAM_PROFILE {
+      return Some(&self.upstream);
+    }
+    self.upstream_profiles.get(name)
+  }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+/// Hedged upstream request settings
+pub struct HedgingConfig {
+  /// delay before sending the same query over another path, which is the initial one in the adaptive mode
+  pub delay: Duration,
+  /// use the 90th percentile of recent upstream latencies as the delay once enough latencies are observed
+  pub adaptive: bool,
+}
+
+impl Default for HedgingConfig {
+  fn default() -> Self {
+    Self {
+      delay: Duration::from_millis(HEDGING_DELAY_MSEC),
+      adaptive: false,
+    }
+  }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+/// Health check probe. For reloading from source, this struct is based on raw strings,
+/// which are converted to the actual probe when the DoH client is built.
+pub struct HealthcheckProbe {
+  /// query name like "dns.google."
+  pub name: String,
+  /// record type like "A"
+  pub record_type: String,
+  /// expected response code like "NOERROR"
+  pub expected_rcode: String,
+  /// rdata that must all be contained in the answers like "8.8.8.8".
+  /// if none, any non-empty answer is accepted for NOERROR, and answers are not checked for other response codes.
+  pub expected_answers: Option<Vec<String>>,
+  /// require the AD flag in the response, i.e., the answer is validated by DNSSEC
+  pub require_ad: bool,
+  /// urls of targets to which this probe is applied instead of the generic ones. if empty, this is a generic probe.
+  pub target_urls: Vec<Url>,
+}
+
+impl Default for HealthcheckProbe {
+  fn default() -> Self {
+    Self {
+      name: HEALTHCHECK_TARGET_FQDN.to_string(),
+      record_type: HEALTHCHECK_TARGET_RECORD_TYPE.to_string(),
+      expected_rcode: HEALTHCHECK_TARGET_RCODE.to_string(),
+      expected_answers: Some(vec![HEALTHCHECK_TARGET_ADDR.to_string()]),
+      require_ad: false,
+      target_urls: vec![],
+    }
+  }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+/// Passive circuit breaker settings per path
+pub struct CircuitBreakerConfig {
+  /// number of consecutive failed queries over a path to take it out of the path selection
+  pub failure_threshold: u32,
+  /// duration for which the path is taken out before a probe query is sent over it
+  pub open_duration: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+  fn default() -> Self {
+    Self {
+      failure_threshold: CIRCUIT_BREAKER_FAILURE_THRESHOLD,
+      open_duration: Duration::from_secs(CIRCUIT_BREAKER_OPEN_DURATION_SEC),
+    }
+  }
 }
 
 #[derive(PartialEq, Eq, Debug, Clone)]
@@ -73,6 +243,18 @@ pub struct TargetConfig {
   pub use_get: bool,
   pub doh_target_urls: Vec<Url>,
   pub target_randomization: bool,
+  pub path_selection: PathSelection,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+/// Strategy to choose a path among healthy ones
+pub enum PathSelection {
+  /// Choose uniformly at random
+  #[default]
+  Random,
+  /// Weight paths by the EWMA of latency and error rate, where a path is chosen uniformly at random
+  /// for the given percentage of queries to keep the randomization of targets and relays
+  LatencyWeighted { randomness_percent: u8 },
 }
 
 #[derive(PartialEq, Eq, Debug, Clone)]
@@ -111,12 +293,99 @@ pub struct QueryManipulationConfig {
   pub min_ttl: u32,
 }
 
+#[derive(Clone, PartialEq, Eq)]
+/// TLS server settings for encrypted listeners. For reloading from source, this struct is based on raw PEM strings.
+/// They are converted to the actual TLS server configuration when the listeners are spawned.
+pub struct TlsConfig {
+  /// PEM-encoded certificate chain
+  pub certificate_chain: String,
+  /// PEM-encoded private key
+  pub private_key: String,
+}
+
+impl std::fmt::Debug for TlsConfig {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    // never print the private key
+    f.debug_struct("TlsConfig")
+      .field("certificate_chain", &self.certificate_chain)
+      .field("private_key", &"<redacted>")
+      .finish()
+  }
+}
+
+impl Default for UpstreamProfile {
+  fn default() -> Self {
+    Self {
+      target_config: TargetConfig::default(),
+      nexthop_relay_config: None,
+      subseq_relay_config: None,
+      token_config: None,
+      query_manipulation_config: None,
+      max_cache_size: MAX_CACHE_SIZE,
+    }
+  }
+}
+
 impl Default for TargetConfig {
   fn default() -> Self {
     Self {
       use_get: false,
       doh_target_urls: DOH_TARGET_URL.iter().map(|v| v.parse().unwrap()).collect(),
       target_randomization: true,
+      path_selection: PathSelection::default(),
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+/// Source address access control list, where the deny list takes precedence over the allow list.
+/// If the allow list is empty, any source not in the deny list is allowed.
+pub struct AccessControlConfig {
+  /// allowed source networks
+  pub allow: Vec<IpNet>,
+  /// denied source networks
+  pub deny: Vec<IpNet>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// Action for queries from denied sources
+pub enum AccessDeniedAction {
+  /// Silently drop queries, and close connections for stream transports
+  #[default]
+  Drop,
+  /// Answer queries with REFUSED
+  Refuse,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Per-client rate limit settings, where clients are identified by source addresses aggregated with the prefix lengths.
+pub struct RateLimitConfig {
+  /// sustained rate of queries per second for each client, i.e., refill rate of the token bucket
+  pub queries_per_sec: u32,
+  /// max burst of queries for each client, i.e., size of the token bucket
+  pub burst: u32,
+  /// prefix length to aggregate IPv4 clients
+  pub ipv4_prefix_len: u8,
+  /// prefix length to aggregate IPv6 clients
+  pub ipv6_prefix_len: u8,
+  /// max number of queries for each client within the rolling quota period
+  pub quota: Option<u64>,
+  /// rolling period of the query quota
+  pub quota_period_sec: Duration,
+  /// allow queries from new clients without limit while the max number of clients are tracked, or refuse them
+  pub allow_untracked: bool,
+}
+
+impl Default for RateLimitConfig {
+  fn default() -> Self {
+    RateLimitConfig {
+      queries_per_sec: RATE_LIMIT_QUERIES_PER_SEC,
+      burst: RATE_LIMIT_BURST,
+      ipv4_prefix_len: RATE_LIMIT_IPV4_PREFIX_LEN,
+      ipv6_prefix_len: RATE_LIMIT_IPV6_PREFIX_LEN,
+      quota: None,
+      quota_period_sec: Duration::from_secs(RATE_LIMIT_QUOTA_PERIOD_SEC),
+      allow_untracked: false,
     }
   }
 }
@@ -186,28 +455,49 @@ impl Default for ProxyConfig {
   fn default() -> Self {
     Self {
       listen_addresses: LISTEN_ADDRESSES.iter().map(|v| v.parse().unwrap()).collect(),
+      dot_listen_addresses: vec![],
+      doh_listen_addresses: vec![],
+      doq_listen_addresses: vec![],
+      unix_listen_paths: vec![],
+      unix_socket_mode: None,
+      doh_server_path: DOH_SERVER_PATH.to_string(),
       max_connections: MAX_CONNECTIONS,
-      max_cache_size: MAX_CACHE_SIZE,
 
       bootstrap_dns: BootstrapDns::default(),
       endpoint_resolution_period_sec: Duration::from_secs(ENDPOINT_RESOLUTION_PERIOD_MIN * 60),
       healthcheck_period_sec: Duration::from_secs(HEALTHCHECK_PERIOD_MIN * 60),
+      healthcheck_probes: vec![HealthcheckProbe::default()],
+      healthcheck_max_concurrency: HEALTHCHECK_MAX_CONCURRENT_PROBES,
+      max_all_unhealthy_retry: None,
 
       udp_buffer_size: UDP_BUFFER_SIZE,
       udp_channel_capacity: UDP_CHANNEL_CAPACITY,
       udp_timeout_sec: Duration::from_secs(UDP_TIMEOUT_SEC),
       tcp_listen_backlog: TCP_LISTEN_BACKLOG,
+      tcp_idle_timeout_sec: Duration::from_secs(TCP_IDLE_TIMEOUT_SEC),
+      drain_grace_period_sec: Duration::from_secs(DRAIN_GRACE_PERIOD_SEC),
+      transparent_mode: false,
+      proxy_protocol_trusted_sources: vec![],
+
+      tls_config: None,
 
       http_timeout_sec: Duration::from_secs(HTTP_TIMEOUT_SEC),
+      max_upstream_retries: MAX_UPSTREAM_RETRIES,
+      retry_upstream_on_servfail: false,
+      hedging_config: None,
+      circuit_breaker_config: CircuitBreakerConfig::default(),
       http_user_agent: format!("{}/{}", HTTP_USER_AGENT, env!("CARGO_PKG_VERSION")),
 
-      target_config: TargetConfig::default(),
-      nexthop_relay_config: None,
-      subseq_relay_config: None,
+      upstream: UpstreamProfile::default(),
+      upstream_profiles: HashMap::new(),
+      listener_profiles: HashMap::new(),
+      unix_listener_profiles: HashMap::new(),
 
-      token_config: None,
+      rate_limit_config: None,
 
-      query_manipulation_config: None,
+      access_control_config: None,
+      listener_access_control_configs: HashMap::new(),
+      access_denied_action: AccessDeniedAction::default(),
     }
   }
 }
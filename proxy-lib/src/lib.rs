@@ -7,152 +7,343 @@ mod globals;
 mod http_client;
 mod log;
 mod proxy;
+mod supervisor;
 
-use crate::{doh_client::DoHClient, error::*, globals::Globals, http_client::HttpClient, log::*, proxy::Proxy};
+#[cfg(unix)]
+use crate::proxy::UNIX_PEER_ADDR;
+use crate::{
+  bootstrap::BootstrapDnsResolver,
+  constants::{DOH_ALPN, DOQ_ALPN, DOT_ALPN},
+  doh_client::DoHClient,
+  error::*,
+  globals::Globals,
+  http_client::HttpClient,
+  log::*,
+  proxy::{build_tls_server_config, Proxy, RateLimiter},
+  supervisor::{AbortOnDrop, Supervisor},
+};
 use futures::{
-  future::{select_all, FutureExt},
+  future::{join_all, select_all, FutureExt},
   select,
 };
-use std::sync::Arc;
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use tokio_rustls::rustls::ServerConfig;
 
 pub use auth_client::AuthenticationConfig;
+pub use constants::{DEFAULT_UPSTREAM_PROFILE, PATH_SELECTION_RANDOMNESS_PERCENT};
 pub use error::{AuthenticatorError, DohClientError, Error, HttpClientError};
 pub use globals::{
-  BootstrapDns, NextHopRelayConfig, ProxyConfig, QueryManipulationConfig, SubseqRelayConfig, TargetConfig, TokenConfig,
+  AccessControlConfig, AccessDeniedAction, BootstrapDns, CircuitBreakerConfig, HealthcheckProbe, HedgingConfig,
+  NextHopRelayConfig, PathSelection, ProxyConfig, QueryManipulationConfig, RateLimitConfig, SubseqRelayConfig, TargetConfig,
+  TlsConfig, TokenConfig, UpstreamProfile,
 };
+pub use supervisor::{ServiceState, ServiceStates};
 
 /// entrypoint of DoH w/ Auth Proxy
-/// This spawns UDP and TCP listeners and spawns the following services
+/// This spawns UDP, TCP, DoT, DoH, DoQ and Unix domain socket listeners and spawns the following services
+/// for each upstream profile bound to the listeners, where each profile has its own DoH client
 /// - Authentication refresh/re-login service loop (Done)
 /// - HTTP client update service loop, changing DNS resolver to the self when it works (Done)
 /// - Health check service checking every path, flag unreachable patterns as unhealthy (as individual service inside doh_client?),
 ///   which also needs ODoH config refresh.
+///
+/// These background services and the query logger are supervised, where a failed one is restarted individually with backoff.
+/// The proxy is shut down only by fatal errors of listeners, e.g., bind failure, fatal errors of the services,
+/// i.e., all paths are unhealthy over the max retries or authentication has failed over the max attempts, or term notified.
+/// Listeners and services still running are aborted when this returns.
 pub async fn entrypoint(
   proxy_config: &ProxyConfig,
   runtime_handle: &tokio::runtime::Handle,
   term_notify: Option<Arc<tokio::sync::Notify>>,
+) -> Result<()> {
+  entrypoint_with_service_states(proxy_config, runtime_handle, term_notify, ServiceStates::default()).await
+}
+
+/// entrypoint of DoH w/ Auth Proxy exposing the states of supervised background services via the given `ServiceStates`
+pub async fn entrypoint_with_service_states(
+  proxy_config: &ProxyConfig,
+  runtime_handle: &tokio::runtime::Handle,
+  term_notify: Option<Arc<tokio::sync::Notify>>,
+  service_states: ServiceStates,
 ) -> Result<()> {
   info!("Start DoH w/ Auth Proxy");
+  let mut supervisor = Supervisor::new(runtime_handle, &term_notify, service_states);
 
   // build query logger
-  let (query_log_tx, query_log_service) = {
-    let (tx, mut logger) = QueryLogger::new(term_notify.clone());
-    let service = runtime_handle.spawn(async move {
-      logger.start().await;
+  let query_log_tx = {
+    let (tx, logger) = QueryLogger::new(term_notify.clone());
+    let logger = Arc::new(tokio::sync::Mutex::new(logger));
+    supervisor.spawn("query-log", move || {
+      let logger = logger.clone();
+      async move {
+        logger.lock().await.start().await;
+        Ok(()) as Result<()>
+      }
     });
-    (tx, service)
+    tx
   };
 
   // build global
+  let (drain_tx, drain_rx) = tokio::sync::watch::channel(false);
   let globals = Arc::new(Globals {
     proxy_config: proxy_config.clone(),
     runtime_handle: runtime_handle.clone(),
     term_notify: term_notify.clone(),
     query_log_tx,
+    drain_rx,
   });
 
+  // build TLS server configurations for encrypted listeners, where ALPN protocols differ by listener type
+  let build_tls = |addresses: &[SocketAddr], alpn_protocols: &[&[u8]]| -> Result<Option<Arc<ServerConfig>>> {
+    if addresses.is_empty() {
+      return Ok(None);
+    }
+    let Some(tls_config) = &proxy_config.tls_config else {
+      return Err(Error::InvalidTlsConfig(
+        "TLS certificate and private key are required for encrypted listeners".to_string(),
+      ));
+    };
+    build_tls_server_config(tls_config, alpn_protocols).map(Some)
+  };
+  let dot_tls_server_config = build_tls(&proxy_config.dot_listen_addresses, &[DOT_ALPN])?;
+  let doh_tls_server_config = build_tls(&proxy_config.doh_listen_addresses, DOH_ALPN)?;
+  let doq_tls_server_config = build_tls(&proxy_config.doq_listen_addresses, &[DOQ_ALPN])?;
+
   // build bootstrap DNS resolver
   let bootstrap_dns_resolver =
-    Arc::new(bootstrap::BootstrapDnsResolver::try_new(&proxy_config.bootstrap_dns, runtime_handle.clone()).await?);
+    Arc::new(BootstrapDnsResolver::try_new(&proxy_config.bootstrap_dns, runtime_handle.clone()).await?);
+
+  // build DoH client and spawn its services for each upstream profile bound to listeners
+  let mut profile_names = [
+    &proxy_config.listen_addresses,
+    &proxy_config.dot_listen_addresses,
+    &proxy_config.doh_listen_addresses,
+    &proxy_config.doq_listen_addresses,
+  ]
+  .into_iter()
+  .flatten()
+  .map(|addr| proxy_config.profile_name_for(addr))
+  .chain(
+    proxy_config
+      .unix_listen_paths
+      .iter()
+      .map(|path| proxy_config.profile_name_for_unix(path)),
+  )
+  .collect::<Vec<_>>();
+  profile_names.sort_unstable();
+  profile_names.dedup();
+  let mut doh_clients = HashMap::new();
+  for profile_name in profile_names {
+    let Some(profile) = proxy_config.upstream_profile(profile_name) else {
+      return Err(Error::UnknownUpstreamProfile(profile_name.to_string()));
+    };
+    let doh_client = spawn_upstream(&globals, profile_name, profile, &bootstrap_dns_resolver, &mut supervisor).await?;
+    doh_clients.insert(profile_name.to_string(), doh_client);
+  }
+
+  // build rate limiter shared among all listeners
+  let rate_limiter = proxy_config
+    .rate_limit_config
+    .as_ref()
+    .map(|config| Arc::new(RateLimiter::new(config)));
+
+  // Start proxy for each listen address, where proxies are kept to drain in-flight queries on termination
+  let mut proxies = vec![];
+  let addresses = globals.proxy_config.listen_addresses.clone();
+  let mut proxy_services = addresses
+    .into_iter()
+    .map(|addr| {
+      let doh_client = &doh_clients[globals.proxy_config.profile_name_for(&addr)];
+      let proxy = Proxy::new(globals.clone(), &addr, doh_client, &rate_limiter);
+      proxies.push(proxy.clone());
+      globals.runtime_handle.spawn(async move { proxy.start().await })
+    })
+    .collect::<Vec<_>>();
+  if let Some(tls_server_config) = dot_tls_server_config {
+    let dot_addresses = globals.proxy_config.dot_listen_addresses.clone();
+    proxy_services.extend(dot_addresses.into_iter().map(|addr| {
+      let doh_client = &doh_clients[globals.proxy_config.profile_name_for(&addr)];
+      let proxy = Proxy::new(globals.clone(), &addr, doh_client, &rate_limiter);
+      proxies.push(proxy.clone());
+      let tls_server_config = tls_server_config.clone();
+      globals
+        .runtime_handle
+        .spawn(async move { proxy.start_dot(tls_server_config).await })
+    }));
+  }
+  if let Some(tls_server_config) = doh_tls_server_config {
+    let doh_addresses = globals.proxy_config.doh_listen_addresses.clone();
+    proxy_services.extend(doh_addresses.into_iter().map(|addr| {
+      let doh_client = &doh_clients[globals.proxy_config.profile_name_for(&addr)];
+      let proxy = Proxy::new(globals.clone(), &addr, doh_client, &rate_limiter);
+      proxies.push(proxy.clone());
+      let tls_server_config = tls_server_config.clone();
+      globals
+        .runtime_handle
+        .spawn(async move { proxy.start_doh(tls_server_config).await })
+    }));
+  }
+  if let Some(tls_server_config) = doq_tls_server_config {
+    let doq_addresses = globals.proxy_config.doq_listen_addresses.clone();
+    proxy_services.extend(doq_addresses.into_iter().map(|addr| {
+      let doh_client = &doh_clients[globals.proxy_config.profile_name_for(&addr)];
+      let proxy = Proxy::new(globals.clone(), &addr, doh_client, &rate_limiter);
+      proxies.push(proxy.clone());
+      let tls_server_config = tls_server_config.clone();
+      globals
+        .runtime_handle
+        .spawn(async move { proxy.start_doq(tls_server_config).await })
+    }));
+  }
+  #[cfg(unix)]
+  proxy_services.extend(globals.proxy_config.unix_listen_paths.clone().into_iter().map(|path| {
+    // clients over unix domain sockets are identified as localhost
+    let doh_client = &doh_clients[globals.proxy_config.profile_name_for_unix(&path)];
+    let proxy = Proxy::new(globals.clone(), &UNIX_PEER_ADDR, doh_client, &rate_limiter);
+    proxies.push(proxy.clone());
+    globals.runtime_handle.spawn(async move { proxy.start_unix(path).await })
+  }));
+  #[cfg(not(unix))]
+  if !globals.proxy_config.unix_listen_paths.is_empty() {
+    return Err(Error::ProxyServiceError(
+      "Unix domain socket listener is not supported on this platform".to_string(),
+    ));
+  }
+  let _abort_proxy_services = AbortOnDrop(proxy_services.iter().map(|v| v.abort_handle()).collect());
+  let proxy_service = select_all(proxy_services);
+
+  // log statistics of the access control of each listener
+  for proxy in proxies.iter().filter(|proxy| proxy.has_access_control()) {
+    let proxy = proxy.clone();
+    supervisor.spawn(&format!("{}/access-control-stats", proxy.listening_on()), move || {
+      proxy.clone().start_access_control_stats_service()
+    });
+  }
+
+  // supervised services finish only when term notified or a service fails with a fatal error
+  let supervisor_service = supervisor.wait();
+
+  // wait for all future
+  let select_res = select! {
+    proxy_res = proxy_service.fuse() => {
+      warn!("Proxy services are down, or term notified");
+      proxy_res.0
+    },
+    supervisor_res = supervisor_service.fuse() => {
+      match &supervisor_res {
+        Ok(()) => warn!("Supervised services are stopped by term notification"),
+        Err(e) => error!("Supervised service is down: {e}"),
+      }
+      Ok(supervisor_res)
+    },
+  };
+
+  let Ok(res_inner) = select_res else {
+    return Err(Error::ServiceDown("Something went wrong in the service loop".to_string()));
+  };
+  // services finish without error only when term notified, then listeners have stopped accepting queries
+  if res_inner.is_ok() {
+    drain(&proxies, &drain_tx, proxy_config.drain_grace_period_sec).await;
+  }
+  res_inner
+}
+
+/// Build the DoH client for the upstream profile, and spawn the following services for it under the supervisor.
+/// - Authentication refresh/re-login service loop
+/// - HTTP client update service loop, changing DNS resolver to the DoH client when it works
+/// - Health check service checking every path and purging expired DNS cache
+async fn spawn_upstream(
+  globals: &Arc<Globals>,
+  profile_name: &str,
+  profile: &UpstreamProfile,
+  bootstrap_dns_resolver: &Arc<BootstrapDnsResolver>,
+  supervisor: &mut Supervisor,
+) -> Result<Arc<DoHClient>> {
+  info!("Build upstream profile: {}", profile_name);
+  let term_notify = &globals.term_notify;
 
   // build http client that is used commonly by DoH client and authentication client
   let mut endpoint_candidates = vec![];
-  if let Some(nexthop_relay_config) = &proxy_config.nexthop_relay_config {
+  if let Some(nexthop_relay_config) = &profile.nexthop_relay_config {
     endpoint_candidates.extend(nexthop_relay_config.odoh_relay_urls.clone());
   } else {
-    endpoint_candidates.extend(proxy_config.target_config.doh_target_urls.clone());
+    endpoint_candidates.extend(profile.target_config.doh_target_urls.clone());
   }
-  if let Some(auth) = &proxy_config.token_config {
+  if let Some(auth) = &profile.token_config {
     endpoint_candidates.push(auth.authentication_config.token_api.clone());
   }
-  let http_client = HttpClient::new(proxy_config, &endpoint_candidates, None, bootstrap_dns_resolver.clone()).await?;
+  let http_client = HttpClient::new(
+    &globals.proxy_config,
+    &endpoint_candidates,
+    None,
+    bootstrap_dns_resolver.clone(),
+  )
+  .await?;
   let http_client = Arc::new(http_client);
 
   // spawn authentication service
-  let term_notify_clone = term_notify.clone();
   let mut authenticator = None;
-  let mut auth_service = None;
-  if let Some(token_config) = &proxy_config.token_config {
+  if let Some(token_config) = &profile.token_config {
     let auth = Arc::new(auth::Authenticator::new(token_config, http_client.inner()).await?);
     let auth_clone = auth.clone();
-    let auth_service_inner = runtime_handle.spawn(async move { auth_clone.start_service(term_notify_clone).await });
+    let term_notify_clone = term_notify.clone();
+    // authentication service has already given up after its own retries
+    supervisor.spawn_with_fatal(
+      &format!("{profile_name}/auth"),
+      move || {
+        let auth = auth_clone.clone();
+        let term_notify = term_notify_clone.clone();
+        async move { auth.start_service(term_notify).await }
+      },
+      |e| matches!(e, AuthenticatorError::FailedAllAttemptsOfLoginAndRefresh),
+    );
     authenticator = Some(auth);
-    auth_service = Some(auth_service_inner);
   }
 
   // build doh_client
-  let doh_client = Arc::new(DoHClient::new(globals.clone(), http_client.inner(), authenticator).await?);
+  let doh_client = Arc::new(DoHClient::new(globals.clone(), profile, http_client.inner(), authenticator).await?);
 
   // spawn endpoint ip update service with bootstrap dns resolver and doh_client
   let doh_client_clone = doh_client.clone();
   let term_notify_clone = term_notify.clone();
-  let http_client_clone = http_client.clone();
-  let ip_resolution_service = runtime_handle.spawn(async move {
-    http_client_clone
-      .start_endpoint_ip_update_service(doh_client_clone, bootstrap_dns_resolver, term_notify_clone)
-      .await
+  let bootstrap_dns_resolver = bootstrap_dns_resolver.clone();
+  supervisor.spawn(&format!("{profile_name}/ip-resolution"), move || {
+    let http_client = http_client.clone();
+    let doh_client = doh_client_clone.clone();
+    let bootstrap_dns_resolver = bootstrap_dns_resolver.clone();
+    let term_notify = term_notify_clone.clone();
+    async move {
+      http_client
+        .start_endpoint_ip_update_service(doh_client, bootstrap_dns_resolver, term_notify)
+        .await
+    }
   });
 
   // spawn health check service for checking every possible path and purging expired DNS cache
   let doh_client_clone = doh_client.clone();
   let term_notify_clone = term_notify.clone();
-  let healthcheck_service =
-    runtime_handle.spawn(async move { doh_client_clone.start_healthcheck_service(term_notify_clone).await });
+  // all paths have been unhealthy over the max retries if configured
+  supervisor.spawn_with_fatal(
+    &format!("{profile_name}/healthcheck"),
+    move || {
+      let doh_client = doh_client_clone.clone();
+      let term_notify = term_notify_clone.clone();
+      async move { doh_client.start_healthcheck_service(term_notify).await }
+    },
+    |e| matches!(e, DohClientError::AllPathsUnhealthy),
+  );
 
-  // Start proxy for each listen address
-  let addresses = globals.proxy_config.listen_addresses.clone();
-  let proxy_service = select_all(addresses.into_iter().map(|addr| {
-    let proxy = Proxy::new(globals.clone(), &addr, &doh_client);
-    globals.runtime_handle.spawn(async move { proxy.start().await })
-  }));
-
-  // wait for all future
-  let select_res = if let Some(auth_service) = auth_service {
-    select! {
-      auth_res = auth_service.fuse() => {
-        warn!("Auth service is down, or term notified");
-        auth_res.map(|res| res.map_err(Error::AuthenticatorError))
-      }
-      proxy_res = proxy_service.fuse() => {
-        warn!("Proxy services are down, or term notified");
-        proxy_res.0
-      },
-      ip_res = ip_resolution_service.fuse() => {
-        warn!("Ip resolution service is down, or term notified");
-        ip_res.map(|res| res.map_err(Error::HttpClientError))
-      },
-      health_res = healthcheck_service.fuse() => {
-        warn!("Health check service is down, or term notified");
-        health_res.map(|res| res.map_err(Error::DohClientError))
-      }
-      query_log_res = query_log_service.fuse() => {
-        warn!("Query log service is down, or term notified");
-        query_log_res.map(|_| Err(Error::QueryLogServiceError))
-      }
-    }
-  } else {
-    select! {
-      proxy_res = proxy_service.fuse() => {
-        warn!("Proxy services are down, or term notified");
-        proxy_res.0
-      },
-      ip_res = ip_resolution_service.fuse() => {
-        warn!("Ip resolution service is down, or term notified");
-        ip_res.map(|res| res.map_err(Error::HttpClientError))
-      },
-      health_res = healthcheck_service.fuse() => {
-        warn!("Health check service is down, or term notified");
-        health_res.map(|res| res.map_err(Error::DohClientError))
-      }
-      query_log_res = query_log_service.fuse() => {
-        warn!("Query log service is down, or term notified");
-        query_log_res.map(|_| Err(Error::QueryLogServiceError))
-      }
-    }
-  };
+  Ok(doh_client)
+}
 
-  let Ok(res_inner) = select_res else {
-    return Err(Error::ServiceDown("Something went wrong in the service loop".to_string()));
-  };
-  res_inner
+/// Drain in-flight queries of all the listeners within the grace period.
+/// Persistent connections are signaled to stop receiving further queries, and the remaining queries are abandoned after the grace period.
+async fn drain(proxies: &[Proxy], drain_tx: &tokio::sync::watch::Sender<bool>, grace_period: std::time::Duration) {
+  info!("Drain in-flight queries (grace period: {} secs)", grace_period.as_secs());
+  let _ = drain_tx.send(true);
+  let wait_all = join_all(proxies.iter().map(|proxy| proxy.wait_for_drain()));
+  match tokio::time::timeout(grace_period, wait_all).await {
+    Ok(_) => info!("All in-flight queries are drained"),
+    Err(_) => warn!("Grace period elapsed, and remaining in-flight queries are abandoned"),
+  }
 }
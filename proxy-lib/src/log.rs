@@ -73,6 +73,10 @@ impl QueryLoggingBase {
       DoHResponseType::NotForwarded => "not_forwarded".to_owned(),
       DoHResponseType::DefaultHost => "default_host".to_owned(),
       DoHResponseType::Cached => "cached".to_owned(),
+      DoHResponseType::Coalesced => "coalesced".to_owned(),
+      DoHResponseType::RateLimited => "rate_limited".to_owned(),
+      DoHResponseType::AccessDenied => "access_denied".to_owned(),
+      DoHResponseType::ServFail => "servfail".to_owned(),
       DoHResponseType::Normal => {
         if let Some(dst_url) = &self.dst_url {
           dst_url.to_string()
@@ -0,0 +1,173 @@
+use crate::globals::{AccessControlConfig, AccessDeniedAction};
+use std::{
+  net::IpAddr,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex,
+  },
+};
+
+impl AccessControlConfig {
+  /// Check if the source address is allowed by this list
+  fn is_allowed(&self, src_ip: &IpAddr) -> bool {
+    if self.deny.iter().any(|net| net.contains(src_ip)) {
+      return false;
+    }
+    self.allow.is_empty() || self.allow.iter().any(|net| net.contains(src_ip))
+  }
+}
+
+#[derive(Debug)]
+/// Source address access control for a listener, which combines the global list and the list for the listen address.
+/// Sources must be allowed by both lists.
+pub(crate) struct AccessControl {
+  /// list applied to all listeners
+  global: Option<AccessControlConfig>,
+  /// list specific to the listen address
+  listener: Option<AccessControlConfig>,
+  /// action for queries from denied sources
+  action: AccessDeniedAction,
+  /// total number of dropped queries or connections
+  cnt_dropped: AtomicUsize,
+  /// total number of refused queries
+  cnt_refused: AtomicUsize,
+  /// totals at the last time the statistics were taken
+  last_stats: Mutex<(usize, usize)>,
+}
+
+impl AccessControl {
+  /// Create a new access control if either list is given
+  pub(crate) fn new(
+    global: Option<&AccessControlConfig>,
+    listener: Option<&AccessControlConfig>,
+    action: AccessDeniedAction,
+  ) -> Option<Self> {
+    if global.is_none() && listener.is_none() {
+      return None;
+    }
+    Some(Self {
+      global: global.cloned(),
+      listener: listener.cloned(),
+      action,
+      cnt_dropped: AtomicUsize::new(0),
+      cnt_refused: AtomicUsize::new(0),
+      last_stats: Mutex::new((0, 0)),
+    })
+  }
+
+  /// Check if the source address is allowed, where IPv4-mapped IPv6 addresses are treated as IPv4 ones
+  pub(crate) fn is_allowed(&self, src_ip: &IpAddr) -> bool {
+    let src_ip = src_ip.to_canonical();
+    [&self.global, &self.listener]
+      .into_iter()
+      .flatten()
+      .all(|acl| acl.is_allowed(&src_ip))
+  }
+
+  /// Action for denied sources
+  pub(crate) fn action(&self) -> AccessDeniedAction {
+    self.action
+  }
+
+  /// Count a dropped query or connection and output the total number
+  pub(crate) fn count_dropped(&self) -> usize {
+    self.cnt_dropped.fetch_add(1, Ordering::Relaxed) + 1
+  }
+
+  /// Count a refused query and output the total number
+  pub(crate) fn count_refused(&self) -> usize {
+    self.cnt_refused.fetch_add(1, Ordering::Relaxed) + 1
+  }
+
+  /// Take the statistics of denied queries if increased since the last time,
+  /// and output the numbers of dropped and refused ones since the last time and in total
+  pub(crate) fn take_stats(&self) -> Option<AccessControlStats> {
+    let dropped = self.cnt_dropped.load(Ordering::Relaxed);
+    let refused = self.cnt_refused.load(Ordering::Relaxed);
+    let Ok(mut last) = self.last_stats.lock() else {
+      return None;
+    };
+    if (dropped, refused) == *last {
+      return None;
+    }
+    let stats = AccessControlStats {
+      dropped: dropped - last.0,
+      refused: refused - last.1,
+      total_dropped: dropped,
+      total_refused: refused,
+    };
+    *last = (dropped, refused);
+    Some(stats)
+  }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+/// Statistics of queries and connections denied by the access control
+pub(crate) struct AccessControlStats {
+  /// number of dropped queries or connections since the last statistics
+  pub(crate) dropped: usize,
+  /// number of refused queries since the last statistics
+  pub(crate) refused: usize,
+  /// total number of dropped queries or connections
+  pub(crate) total_dropped: usize,
+  /// total number of refused queries
+  pub(crate) total_refused: usize,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn acl(allow: &[&str], deny: &[&str]) -> AccessControlConfig {
+    AccessControlConfig {
+      allow: allow.iter().map(|v| v.parse().unwrap()).collect(),
+      deny: deny.iter().map(|v| v.parse().unwrap()).collect(),
+    }
+  }
+
+  #[test]
+  fn deny_takes_precedence() {
+    let config = acl(&["10.0.0.0/8"], &["10.0.5.0/24"]);
+    assert!(config.is_allowed(&"10.0.0.1".parse().unwrap()));
+    assert!(!config.is_allowed(&"10.0.5.1".parse().unwrap()));
+    assert!(!config.is_allowed(&"192.168.0.1".parse().unwrap()));
+
+    let config = acl(&[], &["192.168.0.0/16"]);
+    assert!(config.is_allowed(&"10.0.0.1".parse().unwrap()));
+    assert!(!config.is_allowed(&"192.168.1.1".parse().unwrap()));
+  }
+
+  #[test]
+  fn global_and_listener_lists_are_combined() {
+    let global = acl(&["10.0.0.0/8", "::1/128"], &[]);
+    let listener = acl(&[], &["10.1.0.0/16"]);
+    let access_control = AccessControl::new(Some(&global), Some(&listener), AccessDeniedAction::Refuse).unwrap();
+    assert!(access_control.is_allowed(&"10.0.0.1".parse().unwrap()));
+    assert!(!access_control.is_allowed(&"10.1.0.1".parse().unwrap()));
+    assert!(!access_control.is_allowed(&"172.16.0.1".parse().unwrap()));
+    assert!(access_control.is_allowed(&"::1".parse().unwrap()));
+    // ipv4-mapped ipv6 address
+    assert!(access_control.is_allowed(&"::ffff:10.0.0.1".parse().unwrap()));
+
+    assert!(AccessControl::new(None, None, AccessDeniedAction::Drop).is_none());
+  }
+
+  #[test]
+  fn stats_are_taken_when_increased() {
+    let config = acl(&["10.0.0.0/8"], &[]);
+    let access_control = AccessControl::new(Some(&config), None, AccessDeniedAction::Drop).unwrap();
+    assert_eq!(access_control.take_stats(), None);
+
+    access_control.count_dropped();
+    access_control.count_dropped();
+    access_control.count_refused();
+    let stats = access_control.take_stats().unwrap();
+    assert_eq!((stats.dropped, stats.refused), (2, 1));
+    assert_eq!(access_control.take_stats(), None);
+
+    access_control.count_dropped();
+    let stats = access_control.take_stats().unwrap();
+    assert_eq!((stats.dropped, stats.refused), (1, 0));
+    assert_eq!((stats.total_dropped, stats.total_refused), (3, 1));
+  }
+}
@@ -2,15 +2,18 @@ use crate::log::*;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
 #[derive(Debug, Clone)]
+/// Counter type, where `Tcp` and `Udp` count in-flight queries and `Connection` counts open stream connections
 pub enum CounterType {
   Tcp,
   Udp,
+  Connection,
 }
 impl CounterType {
   fn as_str(&self) -> &'static str {
     match self {
       CounterType::Tcp => "TCP",
       CounterType::Udp => "UDP",
+      CounterType::Connection => "Stream connection",
     }
   }
 }
@@ -47,9 +50,11 @@ impl CounterInner {
 pub struct ConnCounter {
   cnt_udp: CounterInner,
   cnt_tcp: CounterInner,
+  cnt_conn: CounterInner,
 }
 
 impl ConnCounter {
+  /// output the total number of in-flight queries over UDP and TCP, excluding idle connections
   pub fn get_current_total(&self) -> isize {
     self.cnt_tcp.get_current() + self.cnt_udp.get_current()
   }
@@ -58,6 +63,7 @@ impl ConnCounter {
     match ctype {
       CounterType::Tcp => self.cnt_tcp.get_current(),
       CounterType::Udp => self.cnt_udp.get_current(),
+      CounterType::Connection => self.cnt_conn.get_current(),
     }
   }
 
@@ -65,6 +71,7 @@ impl ConnCounter {
     let c = match ctype {
       CounterType::Tcp => self.cnt_tcp.increment(),
       CounterType::Udp => self.cnt_udp.increment(),
+      CounterType::Connection => self.cnt_conn.increment(),
     };
 
     debug!(
@@ -80,6 +87,7 @@ impl ConnCounter {
     let c = match ctype {
       CounterType::Tcp => self.cnt_tcp.decrement(),
       CounterType::Udp => self.cnt_udp.decrement(),
+      CounterType::Connection => self.cnt_conn.decrement(),
     };
 
     debug!(
@@ -141,4 +149,20 @@ mod tests {
     assert_eq!(counter.get_current(CounterType::Tcp), 0);
     assert_eq!(counter.get_current(CounterType::Udp), 0);
   }
+
+  #[test]
+  fn test_conn_counter_connection() {
+    let counter = ConnCounter::default();
+    assert_eq!(counter.increment(CounterType::Connection), 1);
+    assert_eq!(counter.increment(CounterType::Tcp), 1);
+    assert_eq!(counter.increment(CounterType::Tcp), 2);
+    // connections are not counted as in-flight queries
+    assert_eq!(counter.get_current_total(), 2);
+    assert_eq!(counter.get_current(CounterType::Connection), 1);
+    assert_eq!(counter.decrement(CounterType::Tcp), 1);
+    assert_eq!(counter.decrement(CounterType::Tcp), 0);
+    assert_eq!(counter.get_current(CounterType::Connection), 1);
+    assert_eq!(counter.decrement(CounterType::Connection), 0);
+    assert_eq!(counter.get_current_total(), 0);
+  }
 }
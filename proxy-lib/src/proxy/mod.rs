@@ -1,18 +1,41 @@
+mod access_control;
 mod counter;
+mod proxy_doh;
+mod proxy_doq;
+mod proxy_dot;
 mod proxy_main;
+mod proxy_protocol;
 mod proxy_tcp;
 mod proxy_udp;
+#[cfg(unix)]
+mod proxy_unix;
+mod rate_limit;
 mod socket;
+mod tls;
+#[cfg(target_os = "linux")]
+mod udp_cmsg;
 
 pub use proxy_main::Proxy;
+#[cfg(unix)]
+pub(crate) use proxy_unix::UNIX_PEER_ADDR;
+pub(crate) use rate_limit::RateLimiter;
+pub(crate) use tls::build_tls_server_config;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 /// Proxy protocol
 pub(crate) enum ProxyProtocol {
   /// Tcp proxy
   Tcp,
   /// Udp proxy
   Udp,
+  /// DNS over TLS proxy
+  Dot,
+  /// DNS over HTTPS server
+  Doh,
+  /// DNS over QUIC proxy
+  Doq,
+  /// Unix domain socket proxy
+  Unix,
 }
 
 impl std::fmt::Display for ProxyProtocol {
@@ -20,6 +43,10 @@ impl std::fmt::Display for ProxyProtocol {
     match self {
       ProxyProtocol::Tcp => write!(f, "TCP"),
       ProxyProtocol::Udp => write!(f, "UDP"),
+      ProxyProtocol::Dot => write!(f, "DoT"),
+      ProxyProtocol::Doh => write!(f, "DoH"),
+      ProxyProtocol::Doq => write!(f, "DoQ"),
+      ProxyProtocol::Unix => write!(f, "Unix"),
     }
   }
 }
@@ -0,0 +1,305 @@
+use super::{counter::CounterType, proxy_main::Proxy, socket::bind_tcp_socket, ProxyProtocol};
+use crate::{
+  constants::{DOH_CONTENT_TYPE, DOH_MAX_MESSAGE_SIZE, TLS_HANDSHAKE_TIMEOUT_SEC},
+  error::*,
+  log::*,
+  DohClientError,
+};
+use bytes::Bytes;
+use data_encoding::BASE64URL_NOPAD;
+use hickory_proto::op::Message;
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::{body::Incoming, header, service::service_fn, Method, Request, Response, StatusCode};
+use hyper_util::{
+  rt::{TokioExecutor, TokioIo, TokioTimer},
+  server::conn::auto::Builder,
+};
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+use tokio::{
+  io::{AsyncRead, AsyncWrite},
+  net::TcpStream,
+  sync::watch,
+  time::Duration,
+};
+use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+
+impl Proxy {
+  /// Start DoH (DNS over HTTPS, RFC 8484) server listener serving HTTP/2 and HTTP/1.1
+  pub async fn start_doh_listener(&self, tls_server_config: Arc<ServerConfig>) -> Result<()> {
+    let tcp_socket = bind_tcp_socket(&self.listening_on, false)?;
+    let tcp_listener = tcp_socket.listen(self.globals.proxy_config.tcp_listen_backlog)?;
+    let tls_acceptor = TlsAcceptor::from(tls_server_config);
+    info!(
+      "Listening on DoH: {:?} (path: {})",
+      tcp_listener.local_addr()?,
+      self.globals.proxy_config.doh_server_path
+    );
+
+    // receive from src
+    let doh_listener_service = async {
+      loop {
+        let (stream, src_addr) = match tcp_listener.accept().await {
+          Err(e) => {
+            error!("Error in DoH listener: {}", e);
+            continue;
+          }
+          Ok(res) => res,
+        };
+        if self.drop_if_denied(&src_addr) {
+          continue;
+        }
+        let self_clone = self.clone();
+        let tls_acceptor = tls_acceptor.clone();
+        self.globals.runtime_handle.spawn(async move {
+          if let Err(e) = self_clone.serve_doh_connection(stream, src_addr, tls_acceptor).await {
+            error!("Failed to handle DoH connection: {}", e);
+          }
+        });
+      }
+    };
+    doh_listener_service.await;
+
+    Ok(())
+  }
+
+  /// Serve HTTP connection after the TLS handshake
+  async fn serve_doh_connection(self, stream: TcpStream, src_addr: SocketAddr, tls_acceptor: TlsAcceptor) -> Result<()> {
+    debug!("handle doh connection from {:?}", src_addr);
+    let tls_stream = tokio::time::timeout(Duration::from_secs(TLS_HANDSHAKE_TIMEOUT_SEC), tls_acceptor.accept(stream))
+      .await
+      .map_err(|_| Error::TlsHandshakeTimeout)??;
+    self.serve_http_connection(tls_stream, src_addr).await
+  }
+
+  /// Serve HTTP connection, where HTTP/2 or HTTP/1.1 is automatically chosen.
+  /// The connection is closed when no request is in flight and none arrives within the idle timeout.
+  async fn serve_http_connection<S>(self, stream: S, src_addr: SocketAddr) -> Result<()>
+  where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+  {
+    // http connections share the connection counter with other stream transports
+    let counter = self.counter.clone();
+    if counter.increment(CounterType::Connection) >= self.globals.proxy_config.max_connections as isize {
+      error!(
+        "Too many stream connections: max = {}",
+        self.globals.proxy_config.max_connections
+      );
+      counter.decrement(CounterType::Connection);
+      return Err(Error::TooManyConnections);
+    }
+
+    let idle_timeout = self.globals.proxy_config.tcp_idle_timeout_sec;
+    let in_flight = Arc::new(watch::Sender::new(0usize));
+    let self_clone = self.clone();
+    let in_flight_clone = in_flight.clone();
+    let service = service_fn(move |req| {
+      let request = InFlightRequest::new(&in_flight_clone);
+      let self_clone = self_clone.clone();
+      async move {
+        let res = self_clone.serve_doh_request(req, src_addr).await;
+        drop(request);
+        res
+      }
+    });
+    // slow clients sending headers and dead peers of HTTP/2 are detected by hyper with the timer
+    let mut builder = Builder::new(TokioExecutor::new());
+    builder.http1().timer(TokioTimer::new()).header_read_timeout(idle_timeout);
+    builder
+      .http2()
+      .timer(TokioTimer::new())
+      .keep_alive_interval(idle_timeout)
+      .keep_alive_timeout(idle_timeout);
+    let connection = builder.serve_connection(TokioIo::new(stream), service);
+    tokio::pin!(connection);
+    // on termination, stop accepting further requests and wait for in-flight ones to be responded
+    let res = tokio::select! {
+      res = connection.as_mut() => res,
+      _ = self.drain_signaled() => {
+        connection.as_mut().graceful_shutdown();
+        connection.await
+      }
+      _ = wait_for_idle(&in_flight, idle_timeout) => {
+        debug!("Idle timeout of DoH connection from {:?}", src_addr);
+        Ok(())
+      }
+    }
+    .map_err(|e| Error::DohServerError(e.to_string()));
+
+    counter.decrement(CounterType::Connection); // decrement counter anyways
+    res
+  }
+
+  /// Serve DoH request with GET or POST method. Errors are returned to the client as HTTP status codes.
+  async fn serve_doh_request(
+    self,
+    req: Request<Incoming>,
+    src_addr: SocketAddr,
+  ) -> std::result::Result<Response<Full<Bytes>>, Infallible> {
+    if req.uri().path() != self.globals.proxy_config.doh_server_path {
+      return Ok(build_error_response(StatusCode::NOT_FOUND));
+    }
+
+    // retrieve dns message from the request
+    let packet_buf = match *req.method() {
+      Method::GET => {
+        let Some(dns) = req.uri().query().and_then(|query| {
+          url::form_urlencoded::parse(query.as_bytes())
+            .find(|(k, _)| k == "dns")
+            .map(|(_, v)| v.into_owned())
+        }) else {
+          return Ok(build_error_response(StatusCode::BAD_REQUEST));
+        };
+        let Ok(packet_buf) = BASE64URL_NOPAD.decode(dns.as_bytes()) else {
+          return Ok(build_error_response(StatusCode::BAD_REQUEST));
+        };
+        packet_buf
+      }
+      Method::POST => {
+        let is_dns_message = req
+          .headers()
+          .get(header::CONTENT_TYPE)
+          .is_some_and(|v| v.as_bytes() == DOH_CONTENT_TYPE.as_bytes());
+        if !is_dns_message {
+          return Ok(build_error_response(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+        }
+        match Limited::new(req.into_body(), DOH_MAX_MESSAGE_SIZE).collect().await {
+          Ok(body) => body.to_bytes().to_vec(),
+          Err(e) if e.is::<LengthLimitError>() => return Ok(build_error_response(StatusCode::PAYLOAD_TOO_LARGE)),
+          Err(e) => {
+            debug!("Failed to read DoH request body from {src_addr}: {e}");
+            return Ok(build_error_response(StatusCode::BAD_REQUEST));
+          }
+        }
+      }
+      _ => return Ok(build_error_response(StatusCode::METHOD_NOT_ALLOWED)),
+    };
+    if packet_buf.is_empty() || packet_buf.len() > DOH_MAX_MESSAGE_SIZE {
+      return Ok(build_error_response(StatusCode::BAD_REQUEST));
+    }
+
+    // http connections share the TCP counter
+    let counter = self.counter.clone();
+    if counter.increment(CounterType::Tcp) >= self.globals.proxy_config.max_connections as isize {
+      error!(
+        "Too many connections: max = {} (udp+tcp)",
+        self.globals.proxy_config.max_connections
+      );
+      counter.decrement(CounterType::Tcp);
+      return Ok(build_error_response(StatusCode::SERVICE_UNAVAILABLE));
+    }
+
+    // make DoH query, where failures of upstream query are answered with SERVFAIL
+    let res = self.serve_query(&packet_buf, ProxyProtocol::Doh, &src_addr).await;
+
+    counter.decrement(CounterType::Tcp); // decrement counter anyways
+
+    let response_buf = match res {
+      Ok(r) => r,
+      Err(DohClientError::InvalidDnsQuery) => return Ok(build_error_response(StatusCode::BAD_REQUEST)),
+      Err(e) => {
+        error!("Failed to make DoH query for DoH server: {e}");
+        return Ok(build_error_response(StatusCode::BAD_GATEWAY));
+      }
+    };
+
+    // freshness lifetime must be no longer than the smallest TTL in the answer section (RFC 8484 Section 5.1)
+    let max_age = Message::from_vec(&response_buf)
+      .ok()
+      .and_then(|msg| msg.answers().iter().map(|rr| rr.ttl()).min())
+      .unwrap_or(0);
+
+    let response = Response::builder()
+      .status(StatusCode::OK)
+      .header(header::CONTENT_TYPE, DOH_CONTENT_TYPE)
+      .header(header::CACHE_CONTROL, format!("max-age={max_age}"))
+      .body(Full::new(Bytes::from(response_buf)))
+      .unwrap_or_else(|_| build_error_response(StatusCode::INTERNAL_SERVER_ERROR));
+    Ok(response)
+  }
+}
+
+/// Guard of a request in flight over a DoH connection, which is counted until dropped
+struct InFlightRequest(Arc<watch::Sender<usize>>);
+
+impl InFlightRequest {
+  fn new(in_flight: &Arc<watch::Sender<usize>>) -> Self {
+    in_flight.send_modify(|cnt| *cnt += 1);
+    Self(in_flight.clone())
+  }
+}
+
+impl Drop for InFlightRequest {
+  fn drop(&mut self) {
+    self.0.send_modify(|cnt| *cnt -= 1);
+  }
+}
+
+/// Wait until the connection gets idle, i.e., no request is in flight and none arrives within the idle timeout
+async fn wait_for_idle(in_flight: &watch::Sender<usize>, idle_timeout: Duration) {
+  let mut in_flight_rx = in_flight.subscribe();
+  loop {
+    // never fails since the sender is borrowed
+    let _ = in_flight_rx.wait_for(|cnt| *cnt == 0).await;
+    if tokio::time::timeout(idle_timeout, in_flight_rx.changed()).await.is_err() {
+      return;
+    }
+  }
+}
+
+/// Build an empty response with the given status code
+fn build_error_response(status: StatusCode) -> Response<Full<Bytes>> {
+  let mut response = Response::new(Full::new(Bytes::new()));
+  *response.status_mut() = status;
+  response
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{globals::ProxyConfig, proxy::proxy_main::tests::build_proxy};
+  use hickory_proto::{
+    op::Query,
+    rr::{Name, RecordType},
+  };
+  use std::str::FromStr;
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+  #[tokio::test]
+  async fn idle_connection_is_closed() {
+    let proxy_config = ProxyConfig {
+      tcp_idle_timeout_sec: Duration::from_millis(300),
+      ..Default::default()
+    };
+    let (proxy, _drain_tx) = build_proxy(proxy_config).await;
+    let counter = proxy.counter.clone();
+    let src_addr: SocketAddr = "127.0.0.1:10443".parse().unwrap();
+
+    // connection sending nothing
+    let (_client, server) = tokio::io::duplex(4096);
+    let serving = tokio::spawn(proxy.clone().serve_http_connection(server, src_addr));
+    // closed by the idle timeout, or by hyper on the header read timeout whichever comes first
+    assert!(tokio::time::timeout(Duration::from_secs(3), serving).await.is_ok());
+    assert_eq!(counter.get_current(CounterType::Connection), 0);
+
+    // connection kept open after a request is responded
+    let (mut client, server) = tokio::io::duplex(4096);
+    let serving = tokio::spawn(proxy.serve_http_connection(server, src_addr));
+    let mut query = Message::new();
+    query
+      .set_id(1)
+      .set_recursion_desired(true)
+      .add_query(Query::query(Name::from_str("localhost.").unwrap(), RecordType::A));
+    let dns = BASE64URL_NOPAD.encode(&query.to_vec().unwrap());
+    let request = format!("GET /dns-query?dns={dns} HTTP/1.1\r\nHost: localhost\r\nAccept: {DOH_CONTENT_TYPE}\r\n\r\n");
+    client.write_all(request.as_bytes()).await.unwrap();
+    let mut buf = vec![0u8; 4096];
+    let len = client.read(&mut buf).await.unwrap();
+    assert!(buf[..len].starts_with(b"HTTP/1.1 200"));
+    assert_eq!(counter.get_current(CounterType::Connection), 1);
+
+    // closed by the idle timeout, or by hyper on the header read timeout whichever comes first
+    assert!(tokio::time::timeout(Duration::from_secs(3), serving).await.is_ok());
+    assert_eq!(counter.get_current(CounterType::Connection), 0);
+    assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+  }
+}
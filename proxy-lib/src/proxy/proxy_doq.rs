@@ -0,0 +1,152 @@
+use super::{counter::CounterType, proxy_main::Proxy, socket::bind_udp_socket, ProxyProtocol};
+use crate::{
+  constants::{DOQ_EXCESSIVE_LOAD, DOQ_INTERNAL_ERROR, DOQ_NO_ERROR, DOQ_PROTOCOL_ERROR, TLS_HANDSHAKE_TIMEOUT_SEC},
+  error::*,
+  log::*,
+};
+use quinn::{
+  crypto::rustls::QuicServerConfig, ConnectionError, Endpoint, EndpointConfig, RecvStream, SendStream, TokioRuntime, VarInt,
+};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::time::Duration;
+use tokio_rustls::rustls::ServerConfig;
+
+impl Proxy {
+  /// Start DoQ (DNS over QUIC, RFC 9250) listener
+  pub async fn start_doq_listener(&self, tls_server_config: Arc<ServerConfig>) -> Result<()> {
+    let quic_server_config =
+      QuicServerConfig::try_from(tls_server_config).map_err(|e| Error::InvalidTlsConfig(format!("Unusable for QUIC: {e}")))?;
+    let server_config = quinn::ServerConfig::with_crypto(Arc::new(quic_server_config));
+
+    let udp_socket = bind_udp_socket(&self.listening_on, false)?;
+    let endpoint = Endpoint::new(
+      EndpointConfig::default(),
+      Some(server_config),
+      udp_socket,
+      Arc::new(TokioRuntime),
+    )?;
+    info!("Listening on DoQ: {:?}", endpoint.local_addr()?);
+
+    // receive from src
+    let doq_listener_service = async {
+      while let Some(incoming) = endpoint.accept().await {
+        if self.drop_if_denied(&incoming.remote_address()) {
+          incoming.ignore();
+          continue;
+        }
+        let self_clone = self.clone();
+        self.globals.runtime_handle.spawn(async move {
+          if let Err(e) = self_clone.serve_doq_connection(incoming).await {
+            error!("Failed to handle DoQ connection: {}", e);
+          }
+        });
+      }
+    };
+    doq_listener_service.await;
+    endpoint.close(VarInt::from_u32(DOQ_NO_ERROR), b"");
+
+    Ok(())
+  }
+
+  /// Serve QUIC connection after the handshake, where every bidirectional stream carries a single query
+  async fn serve_doq_connection(self, incoming: quinn::Incoming) -> Result<()> {
+    let connection = tokio::time::timeout(Duration::from_secs(TLS_HANDSHAKE_TIMEOUT_SEC), incoming)
+      .await
+      .map_err(|_| Error::TlsHandshakeTimeout)?
+      .map_err(|e| Error::DoqError(e.to_string()))?;
+    let src_addr = connection.remote_address();
+    debug!("handle doq connection from {:?}", src_addr);
+
+    loop {
+      let accepted = tokio::select! {
+        res = connection.accept_bi() => res,
+        _ = self.drain_signaled() => {
+          // in-flight streams are kept open until responded, and the connection is closed after they are finished
+          debug!("Stop accepting DoQ streams from {:?} to drain", src_addr);
+          return Ok(());
+        }
+      };
+      let (send_stream, recv_stream) = match accepted {
+        Ok(streams) => streams,
+        Err(ConnectionError::ApplicationClosed(_) | ConnectionError::LocallyClosed | ConnectionError::TimedOut) => {
+          return Ok(());
+        }
+        Err(e) => return Err(Error::DoqError(e.to_string())),
+      };
+      let self_clone = self.clone();
+      self.globals.runtime_handle.spawn(async move {
+        if let Err(e) = self_clone.serve_doq_query(send_stream, recv_stream, src_addr).await {
+          error!("Failed to handle DoQ query: {}", e);
+        }
+      });
+    }
+  }
+
+  /// Serve DoQ query received over a bidirectional stream.
+  /// Like TCP, the DNS message is prefixed with its length in two bytes, and the stream is finished after the response.
+  async fn serve_doq_query(self, mut send_stream: SendStream, mut recv_stream: RecvStream, src_addr: SocketAddr) -> Result<()> {
+    // every query over quic is counted as a udp query
+    let counter = self.counter.clone();
+    if counter.increment(CounterType::Udp) >= self.globals.proxy_config.max_connections as isize {
+      error!(
+        "Too many connections: max = {} (udp+tcp)",
+        self.globals.proxy_config.max_connections
+      );
+      counter.decrement(CounterType::Udp);
+      let _ = send_stream.reset(VarInt::from_u32(DOQ_EXCESSIVE_LOAD));
+      return Err(Error::TooManyConnections);
+    }
+
+    let res = self.handle_doq_stream(&mut send_stream, &mut recv_stream, src_addr).await;
+
+    counter.decrement(CounterType::Udp); // decrement counter anyways
+
+    if let Err(e) = &res {
+      let error_code = match e {
+        Error::InvalidDoqStream => DOQ_PROTOCOL_ERROR,
+        _ => DOQ_INTERNAL_ERROR,
+      };
+      let _ = recv_stream.stop(VarInt::from_u32(error_code));
+      let _ = send_stream.reset(VarInt::from_u32(error_code));
+    }
+    res
+  }
+
+  /// Read a query from the stream, make DoH query and write back the response
+  async fn handle_doq_stream(
+    &self,
+    send_stream: &mut SendStream,
+    recv_stream: &mut RecvStream,
+    src_addr: SocketAddr,
+  ) -> Result<()> {
+    // the client must indicate through the STREAM FIN that no further data will be sent on the stream
+    let buf = recv_stream
+      .read_to_end(u16::MAX as usize + 2)
+      .await
+      .map_err(|e| Error::DoqError(e.to_string()))?;
+    if buf.len() < 2 {
+      return Err(Error::InvalidDoqStream);
+    }
+    let msg_length = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+    if msg_length == 0 || msg_length != buf.len() - 2 {
+      return Err(Error::InvalidDoqStream);
+    }
+    let packet_buf = &buf[2..];
+
+    // make DoH query, where failures of upstream query are answered with SERVFAIL
+    let Ok(r) = self.serve_query(packet_buf, ProxyProtocol::Doq, &src_addr).await else {
+      return Err(Error::InvalidDoqStream);
+    };
+    if r.len() > (u16::MAX as usize) {
+      return Err(Error::InvalidDnsResponseSize);
+    }
+    let length_buf = u16::to_be_bytes(r.len() as u16);
+    send_stream
+      .write_all(&[length_buf.as_slice(), r.as_slice()].concat())
+      .await
+      .map_err(|e| Error::DoqError(e.to_string()))?;
+    send_stream.finish().map_err(|e| Error::DoqError(e.to_string()))?;
+
+    Ok(())
+  }
+}
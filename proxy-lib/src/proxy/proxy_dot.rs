@@ -0,0 +1,54 @@
+use super::{proxy_main::Proxy, rate_limit::ClientKey, socket::bind_tcp_socket, ProxyProtocol};
+use crate::{constants::TLS_HANDSHAKE_TIMEOUT_SEC, error::*, log::*};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::{net::TcpStream, time::Duration};
+use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+
+impl Proxy {
+  /// Start DoT (DNS over TLS, RFC 7858) listener
+  pub async fn start_dot_listener(&self, tls_server_config: Arc<ServerConfig>) -> Result<()> {
+    let tcp_socket = bind_tcp_socket(&self.listening_on, false)?;
+    let tcp_listener = tcp_socket.listen(self.globals.proxy_config.tcp_listen_backlog)?;
+    let tls_acceptor = TlsAcceptor::from(tls_server_config);
+    info!("Listening on DoT: {:?}", tcp_listener.local_addr()?);
+
+    // receive from src
+    let dot_listener_service = async {
+      loop {
+        let (stream, src_addr) = match tcp_listener.accept().await {
+          Err(e) => {
+            error!("Error in DoT listener: {}", e);
+            continue;
+          }
+          Ok(res) => res,
+        };
+        if self.drop_if_denied(&src_addr) {
+          continue;
+        }
+        let self_clone = self.clone();
+        let tls_acceptor = tls_acceptor.clone();
+        self.globals.runtime_handle.spawn(async move {
+          if let Err(e) = self_clone.serve_dot_query(stream, src_addr, tls_acceptor).await {
+            error!("Failed to handle DoT query: {}", e);
+          }
+        });
+      }
+    };
+    dot_listener_service.await;
+
+    Ok(())
+  }
+
+  /// Serve DoT query after the TLS handshake
+  async fn serve_dot_query(self, stream: TcpStream, src_addr: SocketAddr, tls_acceptor: TlsAcceptor) -> Result<()> {
+    debug!("handle dot query from {:?}", src_addr);
+    let tls_stream = tokio::time::timeout(Duration::from_secs(TLS_HANDSHAKE_TIMEOUT_SEC), tls_acceptor.accept(stream))
+      .await
+      .map_err(|_| Error::TlsHandshakeTimeout)??;
+
+    let client_key = ClientKey::Addr(src_addr.ip());
+    self
+      .serve_stream_query(tls_stream, vec![], src_addr, client_key, ProxyProtocol::Dot)
+      .await
+  }
+}
@@ -1,27 +1,179 @@
-use super::counter::ConnCounter;
-use crate::{doh_client::DoHClient, error::*, globals::Globals, log::*};
+use super::{
+  access_control::AccessControl,
+  counter::{ConnCounter, CounterType},
+  rate_limit::{ClientKey, RateLimiter},
+  ProxyProtocol,
+};
+use crate::{
+  constants::{ACCESS_CONTROL_STATS_LOG_INTERVAL_SEC, DRAIN_CHECK_INTERVAL_MSEC},
+  doh_client::{
+    dns_message::{self, ExtendedErrorCode},
+    DoHClient, DoHResponseType,
+  },
+  error::*,
+  globals::{AccessDeniedAction, Globals},
+  log::*,
+};
 use futures::future::select;
+use hickory_proto::op::Message;
 use std::{net::SocketAddr, sync::Arc};
+use tokio::time::Duration;
+use tokio_rustls::rustls::ServerConfig;
 
-/// Proxy object serving UDP and TCP queries
+/// Proxy object serving UDP, TCP, DoT, DoH, DoQ and Unix domain socket queries
 #[derive(Clone)]
 pub struct Proxy {
   pub(super) globals: Arc<Globals>,
   pub(super) counter: Arc<ConnCounter>,
   pub(super) doh_client: Arc<DoHClient>,
   pub(super) listening_on: SocketAddr,
+  pub(super) rate_limiter: Option<Arc<RateLimiter>>,
+  pub(super) access_control: Option<Arc<AccessControl>>,
 }
 
 impl Proxy {
   /// Create a new proxy object
-  pub fn new(globals: Arc<Globals>, listening_on: &SocketAddr, doh_client: &Arc<DoHClient>) -> Self {
+  /// The rate limiter is shared among all listeners so that the limit is applied to each client regardless of protocols.
+  pub(crate) fn new(
+    globals: Arc<Globals>,
+    listening_on: &SocketAddr,
+    doh_client: &Arc<DoHClient>,
+    rate_limiter: &Option<Arc<RateLimiter>>,
+  ) -> Self {
+    let access_control = AccessControl::new(
+      globals.proxy_config.access_control_config.as_ref(),
+      globals.proxy_config.listener_access_control_configs.get(listening_on),
+      globals.proxy_config.access_denied_action,
+    )
+    .map(Arc::new);
     Self {
       globals,
       counter: Arc::new(ConnCounter::default()),
       doh_client: doh_client.clone(),
       listening_on: *listening_on,
+      rate_limiter: rate_limiter.clone(),
+      access_control,
     }
   }
+
+  /// Check the source address against the access control list before any work for the query or connection,
+  /// and return true if it must be dropped. If the action for denied sources is to refuse, queries are refused in `serve_query`.
+  pub(super) fn drop_if_denied(&self, src_addr: &SocketAddr) -> bool {
+    let Some(access_control) = &self.access_control else {
+      return false;
+    };
+    if access_control.action() != AccessDeniedAction::Drop || access_control.is_allowed(&src_addr.ip()) {
+      return false;
+    }
+    let cnt = access_control.count_dropped();
+    debug!(
+      "Dropped query or connection from denied source {} on {} (total dropped: {})",
+      src_addr, self.listening_on, cnt
+    );
+    true
+  }
+
+  /// Serve a DNS query from the client via the DoH client, where the access control and the per-client rate limit are applied beforehand.
+  /// The query from a denied source or exceeding the limit is answered with REFUSED and recorded in the query log.
+  /// If the DoH query fails or times out, the query is answered with SERVFAIL with an Extended DNS Error explaining the reason.
+  /// Error is returned only when the given packet is not a valid DNS query.
+  pub(super) async fn serve_query(
+    &self,
+    packet_buf: &[u8],
+    proto: ProxyProtocol,
+    src_addr: &SocketAddr,
+  ) -> std::result::Result<Vec<u8>, DohClientError> {
+    self
+      .serve_query_as(packet_buf, proto, src_addr, &ClientKey::Addr(src_addr.ip()))
+      .await
+  }
+
+  /// Serve a DNS query like `serve_query`, where the rate limit is applied to the given client key instead of the source address
+  pub(super) async fn serve_query_as(
+    &self,
+    packet_buf: &[u8],
+    proto: ProxyProtocol,
+    src_addr: &SocketAddr,
+    client_key: &ClientKey,
+  ) -> std::result::Result<Vec<u8>, DohClientError> {
+    if let Some(access_control) = &self.access_control {
+      if !access_control.is_allowed(&src_addr.ip()) {
+        let cnt = access_control.count_refused();
+        debug!(
+          "Refused query from denied source {} on {} (total refused: {})",
+          src_addr, self.listening_on, cnt
+        );
+        return self.respond_synthetic(packet_buf, proto, src_addr, DoHResponseType::AccessDenied, |msg| {
+          dns_message::build_response_refused(msg)
+        });
+      }
+    }
+    if let Some(rate_limiter) = &self.rate_limiter {
+      if let Err(e) = rate_limiter.check(client_key) {
+        debug!("Refuse query from {}: {}", src_addr, e);
+        return self.respond_synthetic(packet_buf, proto, src_addr, DoHResponseType::RateLimited, |msg| {
+          dns_message::build_response_refused(msg)
+        });
+      }
+    }
+
+    let res = tokio::time::timeout(
+      self.globals.proxy_config.http_timeout_sec + std::time::Duration::from_secs(1),
+      self.doh_client.make_doh_query(packet_buf, proto, src_addr),
+    )
+    .await;
+    let (info_code, extra_text) = match res {
+      Ok(Ok(r)) => return Ok(r),
+      Ok(Err(DohClientError::InvalidDnsQuery)) => return Err(DohClientError::InvalidDnsQuery),
+      Ok(Err(e)) => {
+        error!("Failed to make DoH query for {}: {}", src_addr, e);
+        e.extended_dns_error()
+      }
+      Err(_) => {
+        error!("Timeout to make DoH query for {}", src_addr);
+        (ExtendedErrorCode::NoReachableAuthority, "upstream timeout")
+      }
+    };
+    self.respond_synthetic(packet_buf, proto, src_addr, DoHResponseType::ServFail, |msg| {
+      dns_message::build_response_servfail(msg, info_code, extra_text)
+    })
+  }
+
+  /// Wait for the signal to drain in-flight queries on termination.
+  /// Persistent connections stop receiving further queries upon this signal and are closed after responding to in-flight ones.
+  pub(super) async fn drain_signaled(&self) {
+    let mut drain_rx = self.globals.drain_rx.clone();
+    // error means the sender has been dropped, i.e., the proxy service has already finished
+    let _ = drain_rx.wait_for(|draining| *draining).await;
+  }
+
+  /// Wait until all the in-flight queries and stream connections on this listener are finished
+  pub(crate) async fn wait_for_drain(&self) {
+    let interval = Duration::from_millis(DRAIN_CHECK_INTERVAL_MSEC);
+    while self.counter.get_current_total() + self.counter.get_current(CounterType::Connection) > 0 {
+      tokio::time::sleep(interval).await;
+    }
+  }
+
+  /// Build a synthetic response to the query and log it with the given response type
+  pub(super) fn respond_synthetic<F>(
+    &self,
+    packet_buf: &[u8],
+    proto: ProxyProtocol,
+    src_addr: &SocketAddr,
+    res_type: DoHResponseType,
+    build_response: F,
+  ) -> std::result::Result<Vec<u8>, DohClientError>
+  where
+    F: FnOnce(&Message) -> Message,
+  {
+    let start = std::time::Instant::now();
+    let query_msg = dns_message::is_query(packet_buf).map_err(|_| DohClientError::InvalidDnsQuery)?;
+    let res = dns_message::encode(&build_response(&query_msg))?;
+    self.doh_client.log_dns_message(&res, proto, src_addr, res_type, None, start);
+    Ok(res)
+  }
+
   /// Start proxy for single port
   pub async fn start(self) -> Result<()> {
     let term_notify = self.globals.term_notify.clone();
@@ -80,4 +232,187 @@ impl Proxy {
 
     Ok(())
   }
+
+  /// Listen address of this proxy
+  pub(crate) fn listening_on(&self) -> &SocketAddr {
+    &self.listening_on
+  }
+
+  /// Whether the access control is applied to this listener
+  pub(crate) fn has_access_control(&self) -> bool {
+    self.access_control.is_some()
+  }
+
+  /// Start service logging the statistics of the access control periodically while denied queries increase
+  pub(crate) async fn start_access_control_stats_service(self) -> Result<()> {
+    let Some(access_control) = self.access_control.clone() else {
+      return Ok(());
+    };
+    let stats_service = async {
+      loop {
+        tokio::time::sleep(Duration::from_secs(ACCESS_CONTROL_STATS_LOG_INTERVAL_SEC)).await;
+        if let Some(stats) = access_control.take_stats() {
+          info!(
+            "Access control on {}: {} dropped and {} refused in the last {} secs (total dropped: {}, total refused: {})",
+            self.listening_on,
+            stats.dropped,
+            stats.refused,
+            ACCESS_CONTROL_STATS_LOG_INTERVAL_SEC,
+            stats.total_dropped,
+            stats.total_refused
+          );
+        }
+      }
+    };
+    match &self.globals.term_notify {
+      Some(term) => {
+        tokio::select! {
+          _ = stats_service => Ok(()),
+          _ = term.notified() => Ok(()),
+        }
+      }
+      None => stats_service.await,
+    }
+  }
+
+  /// Start DoT proxy for single port
+  pub async fn start_dot(self, tls_server_config: Arc<ServerConfig>) -> Result<()> {
+    let term_notify = self.globals.term_notify.clone();
+    match term_notify {
+      Some(term) => {
+        tokio::select! {
+          res = self.start_dot_listener(tls_server_config) => {
+            warn!("DoT listener service got down");
+            res
+          }
+          _ = term.notified() => {
+            info!("DoT listener received term signal");
+            Ok(())
+          }
+        }
+      }
+      None => {
+        let res = self.start_dot_listener(tls_server_config).await;
+        warn!("DoT listener service got down");
+        res
+      }
+    }
+  }
+
+  /// Start DoH server for single port
+  pub async fn start_doh(self, tls_server_config: Arc<ServerConfig>) -> Result<()> {
+    let term_notify = self.globals.term_notify.clone();
+    match term_notify {
+      Some(term) => {
+        tokio::select! {
+          res = self.start_doh_listener(tls_server_config) => {
+            warn!("DoH listener service got down");
+            res
+          }
+          _ = term.notified() => {
+            info!("DoH listener received term signal");
+            Ok(())
+          }
+        }
+      }
+      None => {
+        let res = self.start_doh_listener(tls_server_config).await;
+        warn!("DoH listener service got down");
+        res
+      }
+    }
+  }
+
+  #[cfg(unix)]
+  /// Start Unix domain socket listener for single path
+  pub async fn start_unix(self, path: std::path::PathBuf) -> Result<()> {
+    let term_notify = self.globals.term_notify.clone();
+    match term_notify {
+      Some(term) => {
+        tokio::select! {
+          res = self.start_unix_listener(&path) => {
+            warn!("Unix domain socket listener service got down");
+            res
+          }
+          _ = term.notified() => {
+            info!("Unix domain socket listener received term signal");
+            Ok(())
+          }
+        }
+      }
+      None => {
+        let res = self.start_unix_listener(&path).await;
+        warn!("Unix domain socket listener service got down");
+        res
+      }
+    }
+  }
+
+  /// Start DoQ listener for single port
+  pub async fn start_doq(self, tls_server_config: Arc<ServerConfig>) -> Result<()> {
+    let term_notify = self.globals.term_notify.clone();
+    match term_notify {
+      Some(term) => {
+        tokio::select! {
+          res = self.start_doq_listener(tls_server_config) => {
+            warn!("DoQ listener service got down");
+            res
+          }
+          _ = term.notified() => {
+            info!("DoQ listener received term signal");
+            Ok(())
+          }
+        }
+      }
+      None => {
+        let res = self.start_doq_listener(tls_server_config).await;
+        warn!("DoQ listener service got down");
+        res
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+  use super::*;
+  use crate::{
+    globals::ProxyConfig,
+    http_client::{HttpClient, ResolveIpResponse, ResolveIps},
+  };
+  use async_trait::async_trait;
+  use tokio::sync::watch;
+  use url::Url;
+
+  /// Resolver of tests that never reaches upstream
+  struct NoResolver;
+
+  #[async_trait]
+  impl ResolveIps for NoResolver {
+    type Err = ();
+    async fn resolve_ips(&self, _target_url: &Url) -> std::result::Result<ResolveIpResponse, Self::Err> {
+      Err(())
+    }
+  }
+
+  /// Build a proxy over the DoH client without upstream endpoints, which answers queries for localhost by itself.
+  /// The returned sender signals the proxy to drain, and has to be kept while the proxy serves queries.
+  pub(in crate::proxy) async fn build_proxy(proxy_config: ProxyConfig) -> (Proxy, watch::Sender<bool>) {
+    let (drain_tx, drain_rx) = watch::channel(false);
+    let (query_log_tx, _) = crossbeam_channel::unbounded();
+    let http_client = HttpClient::new(&proxy_config, &[], None, NoResolver).await.unwrap();
+    let globals = Arc::new(Globals {
+      proxy_config,
+      runtime_handle: tokio::runtime::Handle::current(),
+      term_notify: None,
+      query_log_tx,
+      drain_rx,
+    });
+    let doh_client = DoHClient::new(globals.clone(), &globals.proxy_config.upstream, http_client.inner(), None)
+      .await
+      .unwrap();
+    let listening_on = "127.0.0.1:53".parse().unwrap();
+    let proxy = Proxy::new(globals, &listening_on, &Arc::new(doh_client), &None);
+    (proxy, drain_tx)
+  }
 }
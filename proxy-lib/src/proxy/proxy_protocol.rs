@@ -0,0 +1,179 @@
+use crate::{
+  constants::{PROXY_PROTOCOL_V1_MAX_LENGTH, PROXY_PROTOCOL_V2_SIGNATURE},
+  error::*,
+};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Read PROXY protocol v1 or v2 header (https://www.haproxy.org/download/3.0/doc/proxy-protocol.txt) sent by a load balancer
+/// at the beginning of the connection, and return the source address of the original client with the bytes read beyond the header.
+/// `None` is returned as the address if the header carries no address, e.g., health checks by the load balancer itself with LOCAL command.
+/// The stream is read in chunks instead of byte by byte, so the leftover bytes must be processed before the following DNS messages.
+pub(super) async fn read_proxy_protocol_header<R>(reader: &mut R) -> Result<(Option<SocketAddr>, Vec<u8>)>
+where
+  R: AsyncRead + Unpin,
+{
+  let sig_len = PROXY_PROTOCOL_V2_SIGNATURE.len();
+  let mut buf = Vec::with_capacity(PROXY_PROTOCOL_V1_MAX_LENGTH);
+  loop {
+    if buf.len() >= sig_len && buf[..sig_len] == PROXY_PROTOCOL_V2_SIGNATURE {
+      // v2: signature, version and command (1), address family and protocol (1), length (2), and addresses
+      if buf.len() >= sig_len + 4 {
+        let header_len = sig_len + 4 + u16::from_be_bytes([buf[sig_len + 2], buf[sig_len + 3]]) as usize;
+        if buf.len() < header_len {
+          let mut rest = vec![0u8; header_len - buf.len()];
+          reader.read_exact(&mut rest).await?;
+          buf.extend_from_slice(&rest);
+        }
+        let leftover = buf.split_off(header_len);
+        let client_addr = parse_v2(buf[sig_len], buf[sig_len + 1], &buf[sig_len + 4..])?;
+        return Ok((client_addr, leftover));
+      }
+    } else if !PROXY_PROTOCOL_V2_SIGNATURE.starts_with(&buf) {
+      // v1: "PROXY ... \r\n"
+      let prefix_len = buf.len().min(6);
+      if buf[..prefix_len] != b"PROXY "[..prefix_len] {
+        return Err(Error::InvalidProxyProtocolHeader);
+      }
+      if let Some(pos) = buf.windows(2).position(|w| w == b"\r\n") {
+        if pos + 2 > PROXY_PROTOCOL_V1_MAX_LENGTH {
+          return Err(Error::InvalidProxyProtocolHeader);
+        }
+        let leftover = buf.split_off(pos + 2);
+        return Ok((parse_v1(&buf[..pos])?, leftover));
+      }
+      if buf.len() >= PROXY_PROTOCOL_V1_MAX_LENGTH {
+        return Err(Error::InvalidProxyProtocolHeader);
+      }
+    }
+
+    let mut chunk = [0u8; PROXY_PROTOCOL_V1_MAX_LENGTH];
+    let len = reader.read(&mut chunk).await?;
+    if len == 0 {
+      return Err(Error::Io(std::io::ErrorKind::UnexpectedEof.into()));
+    }
+    buf.extend_from_slice(&chunk[..len]);
+  }
+}
+
+/// Parse v1 header line without CRLF like "PROXY TCP4 192.0.2.1 198.51.100.1 56324 53"
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>> {
+  let line = std::str::from_utf8(line).map_err(|_| Error::InvalidProxyProtocolHeader)?;
+  let fields = line.split(' ').collect::<Vec<_>>();
+  match fields.as_slice() {
+    ["PROXY", "UNKNOWN", ..] => Ok(None),
+    ["PROXY", proto @ ("TCP4" | "TCP6"), src_ip, _dst_ip, src_port, _dst_port] => {
+      let src_ip = src_ip.parse::<IpAddr>().map_err(|_| Error::InvalidProxyProtocolHeader)?;
+      let src_port = src_port.parse::<u16>().map_err(|_| Error::InvalidProxyProtocolHeader)?;
+      if (*proto == "TCP4") != src_ip.is_ipv4() {
+        return Err(Error::InvalidProxyProtocolHeader);
+      }
+      Ok(Some(SocketAddr::new(src_ip, src_port)))
+    }
+    _ => Err(Error::InvalidProxyProtocolHeader),
+  }
+}
+
+/// Parse v2 header following the signature, i.e., version and command, address family and protocol, and addresses
+fn parse_v2(ver_cmd: u8, family: u8, body: &[u8]) -> Result<Option<SocketAddr>> {
+  if ver_cmd >> 4 != 2 {
+    return Err(Error::InvalidProxyProtocolHeader);
+  }
+  match ver_cmd & 0x0f {
+    // LOCAL command
+    0x0 => return Ok(None),
+    // PROXY command
+    0x1 => (),
+    _ => return Err(Error::InvalidProxyProtocolHeader),
+  }
+  match family >> 4 {
+    // AF_INET: src addr (4), dst addr (4), src port (2), dst port (2)
+    0x1 => {
+      if body.len() < 12 {
+        return Err(Error::InvalidProxyProtocolHeader);
+      }
+      let src_ip = Ipv4Addr::from(<[u8; 4]>::try_from(&body[0..4]).unwrap_or_default());
+      let src_port = u16::from_be_bytes([body[8], body[9]]);
+      Ok(Some(SocketAddr::new(IpAddr::V4(src_ip), src_port)))
+    }
+    // AF_INET6: src addr (16), dst addr (16), src port (2), dst port (2)
+    0x2 => {
+      if body.len() < 36 {
+        return Err(Error::InvalidProxyProtocolHeader);
+      }
+      let src_ip = Ipv6Addr::from(<[u8; 16]>::try_from(&body[0..16]).unwrap_or_default());
+      let src_port = u16::from_be_bytes([body[32], body[33]]);
+      Ok(Some(SocketAddr::new(IpAddr::V6(src_ip), src_port)))
+    }
+    // AF_UNSPEC and AF_UNIX carry no usable address
+    _ => Ok(None),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn read_v1_header() {
+    let mut stream: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 53\r\n\x00\x1d";
+    let (src, leftover) = read_proxy_protocol_header(&mut stream).await.unwrap();
+    assert_eq!(src, Some("192.0.2.1:56324".parse().unwrap()));
+    // following data is kept intact
+    assert_eq!(leftover, b"\x00\x1d");
+
+    let mut stream: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 53\r\n";
+    let (src, leftover) = read_proxy_protocol_header(&mut stream).await.unwrap();
+    assert_eq!(src, Some("[2001:db8::1]:56324".parse().unwrap()));
+    assert!(leftover.is_empty());
+
+    let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+    assert_eq!(read_proxy_protocol_header(&mut stream).await.unwrap().0, None);
+
+    // header split into multiple segments
+    let mut stream = (&b"PROXY TCP4 192.0.2.1 "[..]).chain(&b"198.51.100.1 56324 53\r\n\x00\x1d"[..]);
+    let (src, leftover) = read_proxy_protocol_header(&mut stream).await.unwrap();
+    assert_eq!(src, Some("192.0.2.1:56324".parse().unwrap()));
+    assert_eq!(leftover, b"\x00\x1d");
+
+    let mut stream: &[u8] = b"PROXY TCP4 2001:db8::1 192.0.2.1 56324 53\r\n";
+    assert!(read_proxy_protocol_header(&mut stream).await.is_err());
+    let mut stream: &[u8] = b"\x00\x1d\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00";
+    assert!(read_proxy_protocol_header(&mut stream).await.is_err());
+    let long = [b"PROXY ".as_slice(), &[b'A'; PROXY_PROTOCOL_V1_MAX_LENGTH], b"\r\n"].concat();
+    let mut stream = long.as_slice();
+    assert!(read_proxy_protocol_header(&mut stream).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn read_v2_header() {
+    let mut header = PROXY_PROTOCOL_V2_SIGNATURE.to_vec();
+    // PROXY command, TCP over IPv4
+    header.extend_from_slice(&[0x21, 0x11, 0x00, 0x0c]);
+    header.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x00, 0x35]);
+    header.extend_from_slice(b"\x00\x1d");
+    let mut stream = header.as_slice();
+    let (src, leftover) = read_proxy_protocol_header(&mut stream).await.unwrap();
+    assert_eq!(src, Some("192.0.2.1:56324".parse().unwrap()));
+    assert_eq!(leftover, b"\x00\x1d");
+
+    // signature split into multiple segments
+    let mut stream = (&header[..5]).chain(&header[5..]);
+    let (src, leftover) = read_proxy_protocol_header(&mut stream).await.unwrap();
+    assert_eq!(src, Some("192.0.2.1:56324".parse().unwrap()));
+    assert_eq!(leftover, b"\x00\x1d");
+
+    let mut header = PROXY_PROTOCOL_V2_SIGNATURE.to_vec();
+    // LOCAL command without addresses
+    header.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+    let mut stream = header.as_slice();
+    assert_eq!(read_proxy_protocol_header(&mut stream).await.unwrap().0, None);
+
+    let mut header = PROXY_PROTOCOL_V2_SIGNATURE.to_vec();
+    // truncated addresses
+    header.extend_from_slice(&[0x21, 0x21, 0x00, 0x0c]);
+    header.extend_from_slice(&[0u8; 12]);
+    let mut stream = header.as_slice();
+    assert!(read_proxy_protocol_header(&mut stream).await.is_err());
+  }
+}
@@ -1,15 +1,32 @@
-use super::{counter::CounterType, proxy_main::Proxy, socket::bind_tcp_socket, ProxyProtocol};
-use crate::{error::*, log::*};
-use std::net::SocketAddr;
+use super::{
+  counter::CounterType,
+  proxy_main::Proxy,
+  proxy_protocol::read_proxy_protocol_header,
+  rate_limit::ClientKey,
+  socket::{bind_tcp_socket, original_dst_addr},
+  ProxyProtocol,
+};
+use crate::{
+  constants::{PROXY_PROTOCOL_HEADER_TIMEOUT_SEC, TCP_PIPELINE_CHANNEL_CAPACITY},
+  doh_client::{
+    dns_message::{self, ExtendedErrorCode},
+    DoHResponseType,
+  },
+  error::*,
+  log::*,
+};
+use std::{net::SocketAddr, sync::Arc};
 use tokio::{
-  io::{AsyncReadExt, AsyncWriteExt},
+  io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
   net::TcpStream,
+  sync::{mpsc, OwnedSemaphorePermit, Semaphore},
+  time::Duration,
 };
 
 impl Proxy {
   /// Start TCP listener
   pub async fn start_tcp_listener(&self) -> Result<()> {
-    let tcp_socket = bind_tcp_socket(&self.listening_on)?;
+    let tcp_socket = bind_tcp_socket(&self.listening_on, self.globals.proxy_config.transparent_mode)?;
     let tcp_listener = tcp_socket.listen(self.globals.proxy_config.tcp_listen_backlog)?;
     info!("Listening on TCP: {:?}", tcp_listener.local_addr()?);
 
@@ -23,9 +40,29 @@ impl Proxy {
           }
           Ok(res) => res,
         };
+        // access control is applied to the original client carried in the PROXY protocol header if the peer is trusted
+        let with_proxy_protocol = self.is_proxy_protocol_trusted(&src_addr);
+        if !with_proxy_protocol && self.drop_if_denied(&src_addr) {
+          continue;
+        }
         let self_clone = self.clone();
         self.globals.runtime_handle.spawn(async move {
-          if let Err(e) = self_clone.serve_tcp_query(stream, src_addr).await {
+          let mut stream = stream;
+          let (src_addr, buffered) = if with_proxy_protocol {
+            match self_clone.read_client_addr(&mut stream, src_addr).await {
+              Ok(res) => res,
+              Err(e) => {
+                error!("Failed to read PROXY protocol header from {}: {}", src_addr, e);
+                return;
+              }
+            }
+          } else {
+            (src_addr, vec![])
+          };
+          if with_proxy_protocol && self_clone.drop_if_denied(&src_addr) {
+            return;
+          }
+          if let Err(e) = self_clone.serve_tcp_query(stream, src_addr, buffered).await {
             error!("Failed to handle TCP query: {}", e);
           }
         });
@@ -36,55 +73,258 @@ impl Proxy {
     Ok(())
   }
 
-  /// Serve TCP query
-  pub async fn serve_tcp_query(self, mut stream: TcpStream, src_addr: SocketAddr) -> Result<()> {
-    debug!("handle tcp query from {:?}", src_addr);
+  /// Check if the peer is a load balancer trusted to send PROXY protocol header
+  fn is_proxy_protocol_trusted(&self, peer_addr: &SocketAddr) -> bool {
+    let peer_ip = peer_addr.ip().to_canonical();
+    let trusted_sources = &self.globals.proxy_config.proxy_protocol_trusted_sources;
+    trusted_sources.iter().any(|net| net.contains(&peer_ip))
+  }
+
+  /// Read PROXY protocol header from the trusted load balancer, and return the address of the original client
+  /// with the bytes read beyond the header. The address of the load balancer is returned if the header carries no client address.
+  async fn read_client_addr(&self, stream: &mut TcpStream, peer_addr: SocketAddr) -> Result<(SocketAddr, Vec<u8>)> {
+    let (client_addr, buffered) = tokio::time::timeout(
+      Duration::from_secs(PROXY_PROTOCOL_HEADER_TIMEOUT_SEC),
+      read_proxy_protocol_header(stream),
+    )
+    .await
+    .map_err(|_| Error::ProxyProtocolHeaderTimeout)??;
+    let client_addr = client_addr.unwrap_or(peer_addr);
+    debug!("PROXY protocol header from {:?}: client {:?}", peer_addr, client_addr);
+    Ok((client_addr, buffered))
+  }
+
+  /// Serve TCP query, where the bytes already read from the stream, e.g., following the PROXY protocol header, are processed first
+  pub async fn serve_tcp_query(self, stream: TcpStream, src_addr: SocketAddr, buffered: Vec<u8>) -> Result<()> {
+    if self.globals.proxy_config.transparent_mode {
+      debug!(
+        "handle tcp query from {:?} to original destination {:?}",
+        src_addr,
+        original_dst_addr(&stream)
+      );
+    } else {
+      debug!("handle tcp query from {:?}", src_addr);
+    }
+    let client_key = ClientKey::Addr(src_addr.ip());
+    self
+      .serve_stream_query(stream, buffered, src_addr, client_key, ProxyProtocol::Tcp)
+      .await
+  }
+
+  /// Serve queries over a persistent stream transport (TCP and DoT), where every DNS message is prefixed with its length in two bytes.
+  /// Multiple queries can be pipelined over a connection and they are processed concurrently,
+  /// where responses are sent back in the order of completion (RFC 7766 Section 6.2.1.1).
+  /// In-flight queries are capped per connection, so that no more query is read until their responses are taken by the writer.
+  /// The connection is closed when no query arrives within the idle timeout, when the client takes no response within the timeout,
+  /// or after in-flight queries are responded on termination.
+  /// The rate limit is applied to the given client key, and the bytes already read from the stream are processed first.
+  pub(super) async fn serve_stream_query<S>(
+    self,
+    stream: S,
+    buffered: Vec<u8>,
+    src_addr: SocketAddr,
+    client_key: ClientKey,
+    proto: ProxyProtocol,
+  ) -> Result<()>
+  where
+    S: AsyncRead + AsyncWrite + Unpin,
+  {
+    // stream transports share the connection counter
     let counter = self.counter.clone();
-    if counter.increment(CounterType::Tcp) >= self.globals.proxy_config.max_connections as isize {
+    if counter.increment(CounterType::Connection) >= self.globals.proxy_config.max_connections as isize {
       error!(
-        "Too many connections: max = {} (udp+tcp)",
+        "Too many stream connections: max = {}",
         self.globals.proxy_config.max_connections
       );
-      counter.decrement(CounterType::Tcp);
+      counter.decrement(CounterType::Connection);
       return Err(Error::TooManyConnections);
     }
-    // let doh_client = self.context.get_random_client().await?;
+
+    let res = self
+      .serve_pipelined_queries(stream, buffered, src_addr, client_key, proto)
+      .await;
+
+    counter.decrement(CounterType::Connection); // decrement counter anyways
+    res
+  }
+
+  /// Read queries from the stream until the idle timeout or EOF, and write back responses as soon as they are ready.
+  async fn serve_pipelined_queries<S>(
+    &self,
+    stream: S,
+    buffered: Vec<u8>,
+    src_addr: SocketAddr,
+    client_key: ClientKey,
+    proto: ProxyProtocol,
+  ) -> Result<()>
+  where
+    S: AsyncRead + AsyncWrite + Unpin,
+  {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = std::io::Cursor::new(buffered).chain(reader);
+    let (response_tx, mut response_rx) = mpsc::channel::<(Vec<u8>, OwnedSemaphorePermit)>(TCP_PIPELINE_CHANNEL_CAPACITY);
+    // every query holds a permit until its response is dequeued by the writer
+    let in_flight = Arc::new(Semaphore::new(TCP_PIPELINE_CHANNEL_CAPACITY));
+    let idle_timeout = self.globals.proxy_config.tcp_idle_timeout_sec;
 
     // read data from stream
     // first 2bytes indicates the length of dns message following from the 3rd byte
-    let mut length_buf = [0u8; 2];
-    stream.read_exact(&mut length_buf).await?;
-    let msg_length = u16::from_be_bytes(length_buf) as usize;
-    if msg_length == 0 {
-      return Err(Error::NullTcpStream);
-    }
-    let mut packet_buf = vec![0u8; msg_length];
-    stream.read_exact(&mut packet_buf).await?;
-
-    // make DoH query
-    let res = tokio::time::timeout(
-      self.globals.proxy_config.http_timeout_sec + std::time::Duration::from_secs(1),
-      // serve tcp dns message here
-      self.doh_client.make_doh_query(&packet_buf, ProxyProtocol::Tcp, &src_addr),
-    )
-    .await
-    .ok();
-    // debug!("response from DoH server: {:?}", res);
+    let reader_service = async move {
+      loop {
+        // stop reading while the cap of in-flight queries is reached, i.e., the client does not read responses
+        let permit = tokio::select! {
+          permit = in_flight.clone().acquire_owned() => permit,
+          _ = self.drain_signaled() => {
+            debug!("Stop receiving queries over {} connection from {:?} to drain", proto, src_addr);
+            break;
+          }
+        };
+        // never fails since the semaphore is not closed
+        let Ok(permit) = permit else {
+          break;
+        };
+        let mut length_buf = [0u8; 2];
+        let read_res = tokio::select! {
+          res = tokio::time::timeout(idle_timeout, reader.read_exact(&mut length_buf)) => res,
+          _ = self.drain_signaled() => {
+            debug!("Stop receiving queries over {} connection from {:?} to drain", proto, src_addr);
+            break;
+          }
+        };
+        match read_res {
+          Err(_) => {
+            debug!("Idle timeout of {} connection from {:?}", proto, src_addr);
+            break;
+          }
+          Ok(Err(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break, // closed by client
+          Ok(Err(e)) => return Err(Error::Io(e)),
+          Ok(Ok(_)) => (),
+        };
+        let msg_length = u16::from_be_bytes(length_buf) as usize;
+        if msg_length == 0 {
+          return Err(Error::NullTcpStream);
+        }
+        let mut packet_buf = vec![0u8; msg_length];
+        tokio::time::timeout(idle_timeout, reader.read_exact(&mut packet_buf))
+          .await
+          .map_err(|_| Error::Io(std::io::ErrorKind::TimedOut.into()))??;
 
-    // send response via stream
-    counter.decrement(CounterType::Tcp); // decrement counter anyways
+        // in-flight queries share the TCP counter, and the query over the limit is answered with SERVFAIL
+        // instead of being dropped, since the client would otherwise wait for the response on the open connection
+        let counter = self.counter.clone();
+        if counter.increment(CounterType::Tcp) >= self.globals.proxy_config.max_connections as isize {
+          error!(
+            "Too many connections: max = {} (udp+tcp)",
+            self.globals.proxy_config.max_connections
+          );
+          counter.decrement(CounterType::Tcp);
+          let res = self.respond_synthetic(&packet_buf, proto, &src_addr, DoHResponseType::ServFail, |msg| {
+            dns_message::build_response_servfail(msg, ExtendedErrorCode::Other, "too many queries")
+          });
+          match res.map(|r| length_prefixed(&r)) {
+            Ok(Ok(response_buf)) => {
+              // fails only if the writer has been closed
+              let _ = response_tx.send((response_buf, permit)).await;
+            }
+            Ok(Err(e)) => error!("{}", e),
+            Err(e) => debug!("Dropped invalid query over {} connection from {:?}: {}", proto, src_addr, e),
+          }
+          continue;
+        }
 
-    if let Some(Ok(r)) = res {
-      if r.len() > (u16::MAX as usize) {
-        return Err(Error::InvalidDnsResponseSize);
+        let self_clone = self.clone();
+        let response_tx = response_tx.clone();
+        self.globals.runtime_handle.spawn(async move {
+          // make DoH query, where failures of upstream query are answered with SERVFAIL
+          let res = self_clone.serve_query_as(&packet_buf, proto, &src_addr, &client_key).await;
+
+          match res.map(|r| length_prefixed(&r)) {
+            Ok(Ok(response_buf)) => {
+              // fails only if the writer has been closed
+              let _ = response_tx.send((response_buf, permit)).await;
+            }
+            Ok(Err(e)) => error!("{}", e),
+            Err(e) => error!("{}: {}", Error::FailedToMakeDohQuery, e),
+          }
+          // the query is counted until its response is handed to the writer
+          counter.decrement(CounterType::Tcp);
+        });
       }
-      let length_buf = u16::to_be_bytes(r.len() as u16);
-      stream.write_all(&length_buf).await?;
-      stream.write_all(&r).await?;
-    } else {
-      return Err(Error::FailedToMakeDohQuery);
-    }
+      Ok(()) as Result<()>
+    };
+
+    // send responses via stream in the order of completion
+    // this finishes after all the in-flight queries are responded, since every query task holds a sender
+    let writer_service = async move {
+      while let Some((response_buf, permit)) = response_rx.recv().await {
+        drop(permit);
+        // the client not reading responses is disconnected as idle
+        tokio::time::timeout(idle_timeout, async {
+          writer.write_all(&response_buf).await?;
+          writer.flush().await
+        })
+        .await
+        .map_err(|_| Error::Io(std::io::ErrorKind::TimedOut.into()))??;
+      }
+      writer.shutdown().await?;
+      Ok(()) as Result<()>
+    };
 
+    tokio::try_join!(reader_service, writer_service)?;
     Ok(())
   }
 }
+
+/// Prefix the DNS message with its length in two bytes for stream transports
+fn length_prefixed(msg: &[u8]) -> Result<Vec<u8>> {
+  if msg.len() > u16::MAX as usize {
+    return Err(Error::InvalidDnsResponseSize);
+  }
+  Ok([u16::to_be_bytes(msg.len() as u16).as_slice(), msg].concat())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{globals::ProxyConfig, proxy::proxy_main::tests::build_proxy};
+  use hickory_proto::{
+    op::{Message, Query},
+    rr::{Name, RecordType},
+  };
+  use std::str::FromStr;
+
+  fn localhost_query(id: u16) -> Vec<u8> {
+    let mut query = Message::new();
+    query
+      .set_id(id)
+      .set_recursion_desired(true)
+      .add_query(Query::query(Name::from_str("localhost.").unwrap(), RecordType::A));
+    length_prefixed(&dns_message::encode(&query).unwrap()).unwrap()
+  }
+
+  #[tokio::test]
+  async fn client_not_reading_responses_is_capped_and_disconnected() {
+    let proxy_config = ProxyConfig {
+      tcp_idle_timeout_sec: Duration::from_millis(500),
+      ..Default::default()
+    };
+    let (proxy, _drain_tx) = build_proxy(proxy_config).await;
+    let counter = proxy.counter.clone();
+    let (mut client, server) = tokio::io::duplex(1024);
+    let src_addr: SocketAddr = "127.0.0.1:10053".parse().unwrap();
+    let serving =
+      tokio::spawn(proxy.serve_stream_query(server, vec![], src_addr, ClientKey::Addr(src_addr.ip()), ProxyProtocol::Tcp));
+
+    // the proxy stops reading queries at the cap, so the client never gets all the queries written
+    let queries = (0..1000).flat_map(localhost_query).collect::<Vec<_>>();
+    let written = tokio::time::timeout(Duration::from_millis(300), client.write_all(&queries)).await;
+    assert!(written.is_err());
+    assert!(counter.get_current(CounterType::Tcp) <= TCP_PIPELINE_CHANNEL_CAPACITY as isize);
+
+    // the connection is closed since no response is taken within the timeout
+    let res = tokio::time::timeout(Duration::from_secs(3), serving).await.unwrap().unwrap();
+    assert!(res.is_err());
+    assert_eq!(counter.get_current(CounterType::Connection), 0);
+    assert_eq!(counter.get_current(CounterType::Tcp), 0);
+  }
+}
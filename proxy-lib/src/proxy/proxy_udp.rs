@@ -1,31 +1,96 @@
-use super::{counter::CounterType, proxy_main::Proxy, socket::bind_udp_socket, ProxyProtocol};
-use crate::{error::*, log::*};
-use std::{net::SocketAddr, sync::Arc};
-use tokio::{
-  net::UdpSocket,
-  sync::{mpsc, Notify},
-  time::Duration,
+use super::{
+  counter::CounterType,
+  proxy_main::Proxy,
+  socket::{bind_udp_socket, bind_udp_socket_from},
+  ProxyProtocol,
 };
+#[cfg(target_os = "linux")]
+use super::{socket::set_recv_pktinfo, udp_cmsg};
+use crate::{
+  constants::{MIN_UDP_PAYLOAD_SIZE, UDP_REPLY_SOCKET_CACHE_SIZE},
+  doh_client::dns_message,
+  error::*,
+  log::*,
+};
+use ahash::HashMap;
+#[cfg(target_os = "linux")]
+use socket2::SockRef;
+use std::{
+  collections::hash_map::Entry,
+  net::{IpAddr, SocketAddr},
+  sync::Arc,
+};
+use tokio::{net::UdpSocket, sync::mpsc};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Local address and interface of a received datagram (IP_PKTINFO or IPV6_PKTINFO), from which the reply is sent
+pub(super) struct PktInfo {
+  /// local address for the reply, i.e., the destination address of the datagram unless it is broadcast or multicast
+  pub(super) local_ip: IpAddr,
+  /// index of the interface on which the datagram is received
+  pub(super) ifindex: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+/// Local endpoint of a received query, from which the response must be sent back
+pub(super) struct LocalEndpoint {
+  /// original destination of the query intercepted in transparent mode
+  pub(super) orig_dst_addr: Option<SocketAddr>,
+  /// destination address and interface of the query received on a wildcard address
+  pub(super) pktinfo: Option<PktInfo>,
+}
+
+#[derive(Debug)]
+/// UDP datagram received along with its local endpoint
+pub(super) struct RecvMeta {
+  /// length of the received datagram
+  pub(super) len: usize,
+  /// source address of the datagram
+  pub(super) src_addr: SocketAddr,
+  /// local endpoint of the datagram
+  pub(super) local: LocalEndpoint,
+}
+
+#[derive(Debug)]
+/// Response to a UDP query, which is sent out by the responder service
+struct UdpResponse {
+  /// response message
+  buf: Vec<u8>,
+  /// address of the client
+  dst_addr: SocketAddr,
+  /// local endpoint of the query, from which the response is sent
+  local: LocalEndpoint,
+}
 
 impl Proxy {
   /// Start UDP listener
   pub async fn start_udp_listener(self) -> Result<()> {
     // setup a channel for sending out responses
-    let (channel_sender, channel_receiver) =
-      mpsc::channel::<(Vec<u8>, SocketAddr)>(self.globals.proxy_config.udp_channel_capacity);
+    let (channel_sender, channel_receiver) = mpsc::channel::<UdpResponse>(self.globals.proxy_config.udp_channel_capacity);
 
-    let udp_socket = UdpSocket::from_std(bind_udp_socket(&self.listening_on)?)?;
-    info!("Listening on UDP: {:?}", udp_socket.local_addr()?);
+    let transparent = self.globals.proxy_config.transparent_mode;
+    let udp_socket = UdpSocket::from_std(bind_udp_socket(&self.listening_on, transparent)?)?;
+    // on wildcard addresses, responses must be sent from the address the query was destined to.
+    // in transparent mode, they are sent from the original destination instead.
+    let with_pktinfo = cfg!(target_os = "linux") && !transparent && self.listening_on.ip().is_unspecified();
+    #[cfg(target_os = "linux")]
+    if with_pktinfo {
+      set_recv_pktinfo(&SockRef::from(&udp_socket), self.listening_on.is_ipv6())?;
+    }
+    info!(
+      "Listening on UDP: {:?}{}",
+      udp_socket.local_addr()?,
+      if transparent { " (transparent mode)" } else { "" }
+    );
 
     let socket_sender = Arc::new(udp_socket);
     let socket_receiver = socket_sender.clone();
 
     // create sender thread that sends out response given through channel
-    self.globals.runtime_handle.spawn(Self::udp_responder_service(
-      socket_sender,
-      channel_receiver,
-      self.globals.term_notify.clone(),
-    ));
+    self
+      .globals
+      .runtime_handle
+      .spawn(Self::udp_responder_service(socket_sender, channel_receiver));
 
     // Setup buffer
     let mut udp_buf = vec![0u8; self.globals.proxy_config.udp_buffer_size];
@@ -33,20 +98,27 @@ impl Proxy {
     // receive from src
     let udp_socket_service = async {
       loop {
-        let (buf_size, src_addr) = match socket_receiver.recv_from(&mut udp_buf).await {
+        let recv_meta = match recv_udp(&socket_receiver, &mut udp_buf, transparent || with_pktinfo).await {
           Err(e) => {
             error!("Error in UDP listener: {}", e);
             continue;
           }
           Ok(res) => res,
         };
-        // debug!("received {} bytes from {}", buf_size, src_addr);
+        let src_addr = recv_meta.src_addr;
+        // debug!("received {} bytes from {}", recv_meta.len, src_addr);
+        if self.drop_if_denied(&src_addr) {
+          continue;
+        }
 
-        let packet_buf = udp_buf[..buf_size].to_vec();
+        let packet_buf = udp_buf[..recv_meta.len].to_vec();
         let self_clone = self.clone();
         let channel_sender_clone = channel_sender.clone();
         self.globals.runtime_handle.spawn(async move {
-          if let Err(e) = self_clone.serve_udp_query(packet_buf, src_addr, channel_sender_clone).await {
+          if let Err(e) = self_clone
+            .serve_udp_query(packet_buf, src_addr, recv_meta.local, channel_sender_clone)
+            .await
+          {
             error!("Failed to handle UDP query: {}", e);
           }
         });
@@ -57,48 +129,21 @@ impl Proxy {
     Ok(())
   }
 
-  /// Send response to source client
-  async fn udp_responder_service(
-    socket_sender: Arc<UdpSocket>,
-    mut channel_receiver: mpsc::Receiver<(Vec<u8>, std::net::SocketAddr)>,
-    term_notify: Option<Arc<Notify>>,
-  ) {
-    let service = async {
-      loop {
-        let (bytes, addr) = match channel_receiver.recv().await {
-          None => {
-            error!("udp channel_receiver.recv()");
-            continue;
-          }
-          Some(res) => res,
-        };
-        match &socket_sender.send_to(&bytes, addr).await {
-          Ok(len) => {
-            debug!("send_to source with response of {:?} bytes", len);
-          }
-          Err(e) => {
-            error!("send_to error: {:?}", e);
-          }
-        };
-      }
-    };
-
-    match term_notify {
-      Some(term) => {
-        tokio::select! {
-          _ = service => {
-            warn!("Udp responder service got down");
-          }
-          _ = term.notified() => {
-            info!("Udp responder service receives term signal");
-          }
+  /// Send response to source client.
+  /// This keeps running after the listener is terminated, until all the in-flight queries are responded and the channel is closed.
+  async fn udp_responder_service(socket_sender: Arc<UdpSocket>, mut channel_receiver: mpsc::Receiver<UdpResponse>) {
+    let mut reply_sockets = ReplySockets::default();
+    while let Some(response) = channel_receiver.recv().await {
+      match send_udp(&socket_sender, &mut reply_sockets, &response).await {
+        Ok(len) => {
+          debug!("send_to source with response of {:?} bytes", len);
         }
-      }
-      None => {
-        service.await;
-        warn!("Udp responder service got down");
-      }
+        Err(e) => {
+          error!("send_to error: {:?}", e);
+        }
+      };
     }
+    info!("Udp responder service finished since all senders are closed");
   }
 
   /// Serve UDP query from source client
@@ -106,7 +151,8 @@ impl Proxy {
     self,
     packet_buf: Vec<u8>,
     src_addr: SocketAddr,
-    res_sender: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+    local: LocalEndpoint,
+    res_sender: mpsc::Sender<UdpResponse>,
   ) -> Result<()> {
     debug!("handle udp query from {:?}", src_addr);
     let counter = self.counter.clone();
@@ -119,21 +165,35 @@ impl Proxy {
       return Err(Error::TooManyConnections);
     }
 
-    let res = tokio::time::timeout(
-      self.globals.proxy_config.http_timeout_sec + Duration::from_secs(1),
-      // serve udp dns message here
-      self.doh_client.make_doh_query(&packet_buf, ProxyProtocol::Udp, &src_addr),
-    )
-    .await
-    .ok();
+    let res = self.respond_udp_query(&packet_buf, src_addr, local, res_sender).await;
 
-    // send response via channel to the dispatch socket
+    // the query is in flight until the response is passed to the responder service
     counter.decrement(CounterType::Udp); // decrement counter anyways
+    res
+  }
+
+  /// Make DoH query and send the response via channel to the dispatch socket
+  async fn respond_udp_query(
+    &self,
+    packet_buf: &[u8],
+    src_addr: SocketAddr,
+    local: LocalEndpoint,
+    res_sender: mpsc::Sender<UdpResponse>,
+  ) -> Result<()> {
+    // serve udp dns message here, where failures of upstream query are answered with SERVFAIL
+    let res = self.serve_query(packet_buf, ProxyProtocol::Udp, &src_addr).await;
 
-    let Some(Ok(r)) = res else {
+    let Ok(r) = res else {
       return Err(Error::FailedToMakeDohQuery);
     };
-    let res = tokio::time::timeout(self.globals.proxy_config.udp_timeout_sec, res_sender.send((r, src_addr))).await;
+    let r = fit_to_udp_payload_size(packet_buf, r, self.globals.proxy_config.udp_buffer_size)?;
+
+    let response = UdpResponse {
+      buf: r,
+      dst_addr: src_addr,
+      local,
+    };
+    let res = tokio::time::timeout(self.globals.proxy_config.udp_timeout_sec, res_sender.send(response)).await;
     match res {
       Err(e) => {
         error!("res_sender on channel timeout: {:?}", e);
@@ -141,9 +201,194 @@ impl Proxy {
       }
       Ok(Err(e)) => {
         error!("res_sender on channel fail: {:?}", e);
-        Err(Error::UdpChannelSendError(e))
+        Err(Error::UdpChannelSendError)
       }
       Ok(Ok(_)) => Ok(()),
     }
   }
 }
+
+/// Receive a UDP datagram, along with its local endpoint via ancillary data if enabled
+async fn recv_udp(socket: &UdpSocket, buf: &mut [u8], with_cmsg: bool) -> std::io::Result<RecvMeta> {
+  #[cfg(target_os = "linux")]
+  if with_cmsg {
+    return udp_cmsg::recv_with_meta(socket, buf).await;
+  }
+  #[cfg(not(target_os = "linux"))]
+  let _ = with_cmsg;
+  let (len, src_addr) = socket.recv_from(buf).await?;
+  Ok(RecvMeta {
+    len,
+    src_addr,
+    local: LocalEndpoint::default(),
+  })
+}
+
+#[derive(Default)]
+/// Transparent sockets bound to original destinations of intercepted queries, which are reused for responses
+/// instead of binding a new socket for every response. The least recently used one is closed over the max number.
+/// They are never connected, so TPROXY rules never deliver datagrams from clients to them.
+struct ReplySockets {
+  /// sockets keyed by the original destinations, with the sequence number of their last use
+  sockets: HashMap<SocketAddr, (UdpSocket, u64)>,
+  /// sequence number of uses
+  seq: u64,
+}
+
+impl ReplySockets {
+  /// Get the socket bound to the original destination, or bind a new one
+  fn get(&mut self, orig_dst_addr: &SocketAddr) -> Result<&UdpSocket> {
+    if !self.sockets.contains_key(orig_dst_addr) && self.sockets.len() >= UDP_REPLY_SOCKET_CACHE_SIZE {
+      let lru = self.sockets.iter().min_by_key(|(_, (_, seq))| *seq).map(|(addr, _)| *addr);
+      if let Some(lru) = lru {
+        self.sockets.remove(&lru);
+      }
+    }
+    let (socket, seq) = match self.sockets.entry(*orig_dst_addr) {
+      Entry::Occupied(entry) => entry.into_mut(),
+      Entry::Vacant(entry) => entry.insert((UdpSocket::from_std(bind_udp_socket_from(orig_dst_addr)?)?, 0)),
+    };
+    self.seq += 1;
+    *seq = self.seq;
+    Ok(socket)
+  }
+}
+
+/// Send the response to the client from the local endpoint of the query.
+/// - The response to the query intercepted in transparent mode is sent from its original destination address.
+///   If only the address differs from the listener, it is sent as the source address via the listener socket,
+///   which is allowed for non-local addresses with `IP_TRANSPARENT`. Otherwise, it is sent via a transparent socket bound to
+///   the original destination.
+/// - The response to the query received on a wildcard address is sent from the destination address and interface of the query.
+async fn send_udp(socket: &UdpSocket, reply_sockets: &mut ReplySockets, response: &UdpResponse) -> Result<usize> {
+  match response.local {
+    #[cfg(target_os = "linux")]
+    LocalEndpoint {
+      orig_dst_addr: Some(orig_dst_addr),
+      ..
+    } if socket
+      .local_addr()
+      .is_ok_and(|local_addr| local_addr != orig_dst_addr && local_addr.port() == orig_dst_addr.port()) =>
+    {
+      let pktinfo = PktInfo {
+        local_ip: orig_dst_addr.ip(),
+        ifindex: 0,
+      };
+      Ok(udp_cmsg::send_with_pktinfo(socket, &response.buf, &response.dst_addr, &pktinfo).await?)
+    }
+    LocalEndpoint {
+      orig_dst_addr: Some(orig_dst_addr),
+      ..
+    } if socket.local_addr().is_ok_and(|local_addr| local_addr != orig_dst_addr) => {
+      let reply_socket = reply_sockets.get(&orig_dst_addr)?;
+      Ok(reply_socket.send_to(&response.buf, response.dst_addr).await?)
+    }
+    #[cfg(target_os = "linux")]
+    LocalEndpoint {
+      pktinfo: Some(pktinfo), ..
+    } => Ok(udp_cmsg::send_with_pktinfo(socket, &response.buf, &response.dst_addr, &pktinfo).await?),
+    _ => Ok(socket.send_to(&response.buf, response.dst_addr).await?),
+  }
+}
+
+/// Truncate the response if it exceeds the UDP payload size acceptable to the client, i.e., 512 bytes for clients without EDNS,
+/// or the payload size advertised in the EDNS OPT record of the query, which is also capped by our UDP buffer size.
+/// The truncated response has TC bit and no records so that the client retries over TCP (RFC 7766 Section 5).
+fn fit_to_udp_payload_size(query_buf: &[u8], response_buf: Vec<u8>, udp_buffer_size: usize) -> Result<Vec<u8>> {
+  if response_buf.len() <= MIN_UDP_PAYLOAD_SIZE {
+    return Ok(response_buf);
+  }
+  let max_payload_size = dns_message::decode(query_buf)
+    .map(|query| query.max_payload() as usize)
+    .unwrap_or(MIN_UDP_PAYLOAD_SIZE)
+    .min(udp_buffer_size)
+    .max(MIN_UDP_PAYLOAD_SIZE);
+  if response_buf.len() <= max_payload_size {
+    return Ok(response_buf);
+  }
+
+  debug!(
+    "Truncate UDP response of {} bytes exceeding {} bytes",
+    response_buf.len(),
+    max_payload_size
+  );
+  let truncated = dns_message::decode(&response_buf)
+    .and_then(|response| dns_message::encode(&dns_message::build_response_truncated(&response)))
+    .map_err(|_| Error::InvalidDnsResponseSize)?;
+  Ok(truncated)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use hickory_proto::{
+    op::{Edns, Message, MessageType, Query},
+    rr::{rdata::TXT, Name, RData, Record, RecordType},
+  };
+  use std::str::FromStr;
+
+  fn build_query(edns_payload: Option<u16>) -> Message {
+    let mut query = Message::new();
+    query
+      .set_id(1)
+      .set_message_type(MessageType::Query)
+      .add_query(Query::query(Name::from_str("example.com.").unwrap(), RecordType::TXT));
+    if let Some(payload) = edns_payload {
+      query.extensions_mut().get_or_insert_with(Edns::new).set_max_payload(payload);
+    }
+    query
+  }
+
+  fn build_response(query: &Message, txt_num: usize) -> Message {
+    let mut response = query.clone();
+    response.set_message_type(MessageType::Response);
+    let name = Name::from_str("example.com.").unwrap();
+    for _ in 0..txt_num {
+      let txt = TXT::new(vec!["a".repeat(200)]);
+      response.add_answer(Record::from_rdata(name.clone(), 60, RData::TXT(txt)));
+    }
+    response
+  }
+
+  #[test]
+  fn small_response_is_not_truncated() {
+    let query = build_query(None);
+    let response_buf = dns_message::encode(&build_response(&query, 1)).unwrap();
+    let query_buf = dns_message::encode(&query).unwrap();
+    let res = fit_to_udp_payload_size(&query_buf, response_buf.clone(), 2048).unwrap();
+    assert_eq!(res, response_buf);
+  }
+
+  #[test]
+  fn large_response_is_truncated_without_edns() {
+    let query = build_query(None);
+    let response_buf = dns_message::encode(&build_response(&query, 4)).unwrap();
+    assert!(response_buf.len() > MIN_UDP_PAYLOAD_SIZE);
+    let query_buf = dns_message::encode(&query).unwrap();
+    let res = fit_to_udp_payload_size(&query_buf, response_buf, 2048).unwrap();
+    let res = dns_message::decode(&res).unwrap();
+    assert!(res.truncated());
+    assert!(res.answers().is_empty());
+    assert_eq!(res.queries().len(), 1);
+  }
+
+  #[test]
+  fn edns_payload_size_is_honored() {
+    let query = build_query(Some(1232));
+    let query_buf = dns_message::encode(&query).unwrap();
+
+    let response_buf = dns_message::encode(&build_response(&query, 4)).unwrap();
+    let res = fit_to_udp_payload_size(&query_buf, response_buf.clone(), 2048).unwrap();
+    assert_eq!(res, response_buf);
+
+    // capped by our buffer size
+    let res = fit_to_udp_payload_size(&query_buf, response_buf, 512).unwrap();
+    assert!(dns_message::decode(&res).unwrap().truncated());
+
+    let response_buf = dns_message::encode(&build_response(&query, 8)).unwrap();
+    let res = fit_to_udp_payload_size(&query_buf, response_buf, 2048).unwrap();
+    let res = dns_message::decode(&res).unwrap();
+    assert!(res.truncated());
+    assert!(res.extensions().is_some());
+  }
+}
@@ -0,0 +1,170 @@
+use super::{proxy_main::Proxy, rate_limit::ClientKey, ProxyProtocol};
+use crate::{error::*, log::*};
+use std::{
+  fs::{DirBuilder, Permissions},
+  net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+  os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt},
+  path::{Path, PathBuf},
+  sync::atomic::{AtomicUsize, Ordering},
+};
+use tokio::net::{UnixListener, UnixStream};
+
+/// Source address of clients over Unix domain sockets, which is used for access control and query logs.
+/// The rate limit is applied to each user of the peer process instead.
+pub(crate) const UNIX_PEER_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0));
+
+/// Socket file created by the listener, which is removed when the listener stops.
+/// The file is identified by its inode not to remove one re-created by another instance.
+struct SocketFile {
+  /// path to the socket file
+  path: PathBuf,
+  /// device and inode numbers of the socket file
+  id: Option<(u64, u64)>,
+}
+
+impl SocketFile {
+  fn new(path: &Path) -> Self {
+    Self {
+      path: path.to_path_buf(),
+      id: file_id(path),
+    }
+  }
+}
+
+impl Drop for SocketFile {
+  fn drop(&mut self) {
+    if self.id.is_some() && file_id(&self.path) == self.id {
+      match std::fs::remove_file(&self.path) {
+        Ok(()) => debug!("Removed Unix domain socket file {}", self.path.display()),
+        Err(e) => warn!("Failed to remove Unix domain socket file {}: {}", self.path.display(), e),
+      }
+    }
+  }
+}
+
+/// Device and inode numbers of the socket file
+fn file_id(path: &Path) -> Option<(u64, u64)> {
+  std::fs::symlink_metadata(path)
+    .ok()
+    .filter(|meta| meta.file_type().is_socket())
+    .map(|meta| (meta.dev(), meta.ino()))
+}
+
+/// Bind the Unix domain socket. If the mode is given, the socket is bound in a private directory accessible only by the owner,
+/// and moved into place after its mode is set, so that the socket file is never accessible with broader permissions than the mode.
+fn bind_unix_listener(path: &Path, mode: Option<u32>) -> std::io::Result<UnixListener> {
+  let Some(mode) = mode else {
+    return UnixListener::bind(path);
+  };
+  // directories are distinguished by process and listener, where the name is kept short not to exceed the limit of socket paths
+  static PRIVATE_DIR_SEQ: AtomicUsize = AtomicUsize::new(0);
+  let parent = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+  let private_dir = parent.join(format!(
+    ".doh-auth-proxy-{}-{}",
+    std::process::id(),
+    PRIVATE_DIR_SEQ.fetch_add(1, Ordering::Relaxed)
+  ));
+  DirBuilder::new().mode(0o700).create(&private_dir)?;
+  let res = bind_and_move(&private_dir.join("sock"), path, mode);
+  // the socket file is left in the directory only on failure
+  if let Err(e) = std::fs::remove_dir_all(&private_dir) {
+    warn!("Failed to remove directory {}: {}", private_dir.display(), e);
+  }
+  res
+}
+
+/// Bind the Unix domain socket at the temporary path, and move it to the path after setting the mode
+fn bind_and_move(tmp_path: &Path, path: &Path, mode: u32) -> std::io::Result<UnixListener> {
+  let listener = UnixListener::bind(tmp_path)?;
+  std::fs::set_permissions(tmp_path, Permissions::from_mode(mode))?;
+  std::fs::rename(tmp_path, path)?;
+  Ok(listener)
+}
+
+/// Client key of the peer process for the rate limit, i.e., its user id
+fn peer_client_key(stream: &UnixStream) -> ClientKey {
+  match stream.peer_cred() {
+    Ok(cred) => ClientKey::UnixUser(cred.uid()),
+    Err(e) => {
+      debug!("Failed to get credentials of Unix domain socket peer: {}", e);
+      ClientKey::Addr(UNIX_PEER_ADDR.ip())
+    }
+  }
+}
+
+impl Proxy {
+  /// Start Unix domain socket listener serving DNS messages prefixed with their length like TCP
+  pub async fn start_unix_listener(&self, path: &Path) -> Result<()> {
+    // remove the stale socket file left by the previous instance
+    if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+      std::fs::remove_file(path)?;
+    }
+    let unix_listener = match bind_unix_listener(path, self.globals.proxy_config.unix_socket_mode) {
+      Ok(listener) => listener,
+      Err(e) => {
+        error!("Failed to bind Unix domain socket {}: {}", path.display(), e);
+        return Err(Error::Io(e));
+      }
+    };
+    // removed when this listener finishes or is dropped on termination
+    let _socket_file = SocketFile::new(path);
+    info!("Listening on Unix domain socket: {}", path.display());
+
+    // receive from src
+    let unix_listener_service = async {
+      loop {
+        let stream = match unix_listener.accept().await {
+          Err(e) => {
+            error!("Error in Unix domain socket listener: {}", e);
+            continue;
+          }
+          Ok((stream, _)) => stream,
+        };
+        if self.drop_if_denied(&UNIX_PEER_ADDR) {
+          continue;
+        }
+        let client_key = peer_client_key(&stream);
+        let self_clone = self.clone();
+        self.globals.runtime_handle.spawn(async move {
+          debug!("handle unix domain socket query from {:?}", client_key);
+          if let Err(e) = self_clone
+            .serve_stream_query(stream, vec![], UNIX_PEER_ADDR, client_key, ProxyProtocol::Unix)
+            .await
+          {
+            error!("Failed to handle Unix domain socket query: {}", e);
+          }
+        });
+      }
+    };
+    unix_listener_service.await;
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn socket_file_is_created_with_mode_and_removed() {
+    let path = std::env::temp_dir().join(format!("doh-auth-proxy-test-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let listener = bind_unix_listener(&path, Some(0o600)).unwrap();
+    let meta = std::fs::metadata(&path).unwrap();
+    assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+    // the private directory to bind the socket is removed after the socket is moved into place
+    let private_dir_prefix = format!(".doh-auth-proxy-{}-", std::process::id());
+    assert!(!std::fs::read_dir(std::env::temp_dir()).unwrap().any(|entry| entry
+      .unwrap()
+      .file_name()
+      .to_string_lossy()
+      .starts_with(&private_dir_prefix)));
+
+    let socket_file = SocketFile::new(&path);
+    drop(listener);
+    drop(socket_file);
+    assert!(std::fs::symlink_metadata(&path).is_err());
+  }
+}
@@ -0,0 +1,350 @@
+use crate::{
+  constants::{RATE_LIMIT_MAX_TRACKED_CLIENTS, RATE_LIMIT_PURGE_INTERVAL_SEC},
+  globals::RateLimitConfig,
+  log::*,
+};
+use ahash::HashMap;
+use std::{
+  net::{IpAddr, Ipv4Addr, Ipv6Addr},
+  sync::Mutex,
+  time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Reason why a query is refused by the rate limiter
+pub(crate) enum RateLimitExceeded {
+  /// Token bucket is empty
+  Rate,
+  /// Query quota within the rolling period is exhausted
+  Quota,
+  /// Client is new while the max number of clients are tracked
+  Untracked,
+}
+
+impl std::fmt::Display for RateLimitExceeded {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      RateLimitExceeded::Rate => write!(f, "rate limit exceeded"),
+      RateLimitExceeded::Quota => write!(f, "query quota exceeded"),
+      RateLimitExceeded::Untracked => write!(f, "too many clients tracked by rate limiter"),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// Key identifying a client of the rate limiter
+pub(crate) enum ClientKey {
+  /// source address of clients over the network, which is aggregated with the configured prefix length
+  Addr(IpAddr),
+  /// user id of the peer process over Unix domain sockets
+  UnixUser(u32),
+}
+
+#[derive(Debug)]
+/// State of a client, i.e., an aggregated source address or a local user
+struct ClientState {
+  /// remaining tokens in the bucket
+  tokens: f64,
+  /// last time the bucket was refilled
+  last_refill: Instant,
+  /// start of the current quota window
+  window_start: Instant,
+  /// number of queries in the current quota window
+  current_count: u64,
+  /// number of queries in the previous quota window
+  previous_count: u64,
+}
+
+impl ClientState {
+  fn new(config: &RateLimitConfig, now: Instant) -> Self {
+    Self {
+      tokens: config.burst as f64,
+      last_refill: now,
+      window_start: now,
+      current_count: 0,
+      previous_count: 0,
+    }
+  }
+
+  /// Refill the token bucket according to the elapsed time
+  fn refill(&mut self, config: &RateLimitConfig, now: Instant) {
+    let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+    self.tokens = (self.tokens + elapsed * config.queries_per_sec as f64).min(config.burst as f64);
+    self.last_refill = now;
+  }
+
+  /// Slide the quota window and return the estimated number of queries within the rolling period,
+  /// where the count of the previous window is weighted by its overlap with the rolling period.
+  fn rolling_count(&mut self, config: &RateLimitConfig, now: Instant) -> f64 {
+    let period = config.quota_period_sec.as_secs_f64();
+    let mut elapsed = now.saturating_duration_since(self.window_start).as_secs_f64();
+    if elapsed >= 2.0 * period {
+      self.previous_count = 0;
+      self.current_count = 0;
+      self.window_start = now;
+      elapsed = 0.0;
+    } else if elapsed >= period {
+      self.previous_count = self.current_count;
+      self.current_count = 0;
+      self.window_start += config.quota_period_sec;
+      elapsed -= period;
+    }
+    self.previous_count as f64 * (1.0 - elapsed / period) + self.current_count as f64
+  }
+
+  /// Check if the client is inactive, i.e., the bucket is full and no query is counted in the rolling period
+  fn is_inactive(&mut self, config: &RateLimitConfig, now: Instant) -> bool {
+    self.refill(config, now);
+    self.tokens >= config.burst as f64 && (config.quota.is_none() || self.rolling_count(config, now) == 0.0)
+  }
+}
+
+/// Tracked clients
+struct Clients {
+  /// states of clients keyed by aggregated source addresses or local users
+  states: HashMap<ClientKey, ClientState>,
+  /// last time inactive clients were purged
+  last_purge: Instant,
+}
+
+/// Per-client rate limiter with token buckets and rolling query quotas.
+/// The number of tracked clients is capped, where inactive clients are purged at most once every purge interval
+/// when the cap is reached. A new client is not tracked while the cap is still reached, and its query is refused
+/// unless configured to be allowed.
+pub(crate) struct RateLimiter {
+  /// rate limit settings
+  config: RateLimitConfig,
+  /// tracked clients
+  clients: Mutex<Clients>,
+  /// max number of tracked clients
+  max_clients: usize,
+  /// min interval of purging inactive clients
+  purge_interval: Duration,
+}
+
+impl RateLimiter {
+  /// Create a new rate limiter
+  pub(crate) fn new(config: &RateLimitConfig) -> Self {
+    Self {
+      config: config.clone(),
+      clients: Mutex::new(Clients {
+        states: HashMap::default(),
+        last_purge: Instant::now(),
+      }),
+      max_clients: RATE_LIMIT_MAX_TRACKED_CLIENTS,
+      purge_interval: Duration::from_secs(RATE_LIMIT_PURGE_INTERVAL_SEC),
+    }
+  }
+
+  /// Check if a query from the given client is allowed, and consume a token and the quota if allowed
+  pub(crate) fn check(&self, client: &ClientKey) -> Result<(), RateLimitExceeded> {
+    self.check_key_at(client, Instant::now())
+  }
+
+  #[cfg(test)]
+  fn check_at(&self, src_ip: &IpAddr, now: Instant) -> Result<(), RateLimitExceeded> {
+    self.check_key_at(&ClientKey::Addr(*src_ip), now)
+  }
+
+  fn check_key_at(&self, client: &ClientKey, now: Instant) -> Result<(), RateLimitExceeded> {
+    let key = match client {
+      // IPv4-mapped IPv6 addresses are aggregated as IPv4 ones
+      ClientKey::Addr(src_ip) => ClientKey::Addr(self.aggregate(&src_ip.to_canonical())),
+      ClientKey::UnixUser(_) => *client,
+    };
+    let Ok(mut clients) = self.clients.lock() else {
+      error!("Failed to lock rate limiter, allow the query");
+      return Ok(());
+    };
+    let clients = &mut *clients;
+    if clients.states.len() >= self.max_clients && !clients.states.contains_key(&key) {
+      if now.saturating_duration_since(clients.last_purge) >= self.purge_interval {
+        clients.last_purge = now;
+        clients.states.retain(|_, state| !state.is_inactive(&self.config, now));
+        debug!("Purged inactive clients from rate limiter: {} remain", clients.states.len());
+      }
+      if clients.states.len() >= self.max_clients {
+        if self.config.allow_untracked {
+          return Ok(());
+        }
+        return Err(RateLimitExceeded::Untracked);
+      }
+    }
+    let state = clients
+      .states
+      .entry(key)
+      .or_insert_with(|| ClientState::new(&self.config, now));
+
+    if let Some(quota) = self.config.quota {
+      if state.rolling_count(&self.config, now) >= quota as f64 {
+        return Err(RateLimitExceeded::Quota);
+      }
+    }
+    state.refill(&self.config, now);
+    if state.tokens < 1.0 {
+      return Err(RateLimitExceeded::Rate);
+    }
+    state.tokens -= 1.0;
+    state.current_count += 1;
+    Ok(())
+  }
+
+  /// Aggregate the source address into the subnet with the configured prefix length
+  fn aggregate(&self, src_ip: &IpAddr) -> IpAddr {
+    match src_ip {
+      IpAddr::V4(v4) => {
+        let prefix_len = self.config.ipv4_prefix_len.min(32) as u32;
+        let mask = u32::MAX.checked_shl(32 - prefix_len).unwrap_or(0);
+        IpAddr::V4(Ipv4Addr::from(u32::from(*v4) & mask))
+      }
+      IpAddr::V6(v6) => {
+        let prefix_len = self.config.ipv6_prefix_len.min(128) as u32;
+        let mask = u128::MAX.checked_shl(128 - prefix_len).unwrap_or(0);
+        IpAddr::V6(Ipv6Addr::from(u128::from(*v6) & mask))
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  #[test]
+  fn token_bucket_works() {
+    let config = RateLimitConfig {
+      queries_per_sec: 2,
+      burst: 3,
+      ..Default::default()
+    };
+    let limiter = RateLimiter::new(&config);
+    let ip: IpAddr = "192.168.0.1".parse().unwrap();
+    let now = Instant::now();
+
+    for _ in 0..3 {
+      assert!(limiter.check_at(&ip, now).is_ok());
+    }
+    assert_eq!(limiter.check_at(&ip, now), Err(RateLimitExceeded::Rate));
+    // another client is not affected
+    assert!(limiter.check_at(&"192.168.0.2".parse().unwrap(), now).is_ok());
+
+    // refilled with 2 tokens after 1 sec
+    let later = now + Duration::from_secs(1);
+    assert!(limiter.check_at(&ip, later).is_ok());
+    assert!(limiter.check_at(&ip, later).is_ok());
+    assert_eq!(limiter.check_at(&ip, later), Err(RateLimitExceeded::Rate));
+  }
+
+  #[test]
+  fn clients_are_aggregated_by_prefix() {
+    let config = RateLimitConfig {
+      burst: 1,
+      ipv4_prefix_len: 24,
+      ipv6_prefix_len: 64,
+      ..Default::default()
+    };
+    let limiter = RateLimiter::new(&config);
+    let now = Instant::now();
+
+    assert!(limiter.check_at(&"10.0.0.1".parse().unwrap(), now).is_ok());
+    assert!(limiter.check_at(&"10.0.0.2".parse().unwrap(), now).is_err());
+    assert!(limiter.check_at(&"10.0.1.1".parse().unwrap(), now).is_ok());
+
+    assert!(limiter.check_at(&"2001:db8::1".parse().unwrap(), now).is_ok());
+    assert!(limiter.check_at(&"2001:db8::ffff".parse().unwrap(), now).is_err());
+    assert!(limiter.check_at(&"2001:db8:0:1::1".parse().unwrap(), now).is_ok());
+  }
+
+  #[test]
+  fn rolling_quota_works() {
+    let config = RateLimitConfig {
+      queries_per_sec: 1000,
+      burst: 1000,
+      quota: Some(10),
+      quota_period_sec: Duration::from_secs(100),
+      ..Default::default()
+    };
+    let limiter = RateLimiter::new(&config);
+    let ip: IpAddr = "192.168.0.1".parse().unwrap();
+    let now = Instant::now();
+
+    for _ in 0..10 {
+      assert!(limiter.check_at(&ip, now).is_ok());
+    }
+    assert_eq!(limiter.check_at(&ip, now), Err(RateLimitExceeded::Quota));
+
+    // half of the previous window still counts in the rolling period
+    let later = now + Duration::from_secs(150);
+    for _ in 0..5 {
+      assert!(limiter.check_at(&ip, later).is_ok());
+    }
+    assert_eq!(limiter.check_at(&ip, later), Err(RateLimitExceeded::Quota));
+
+    // quota is fully restored after two periods without queries
+    let much_later = later + Duration::from_secs(200);
+    for _ in 0..10 {
+      assert!(limiter.check_at(&ip, much_later).is_ok());
+    }
+  }
+
+  #[test]
+  fn tracked_clients_are_capped() {
+    let config = RateLimitConfig {
+      queries_per_sec: 1,
+      burst: 1,
+      ..Default::default()
+    };
+    let mut limiter = RateLimiter::new(&config);
+    limiter.max_clients = 2;
+    let now = Instant::now();
+    limiter.clients.lock().unwrap().last_purge = now;
+
+    assert!(limiter.check_at(&"192.168.0.1".parse().unwrap(), now).is_ok());
+    assert!(limiter.check_at(&"192.168.0.2".parse().unwrap(), now).is_ok());
+    // new client is refused without being tracked until the next purge
+    let new_ip: IpAddr = "192.168.0.3".parse().unwrap();
+    assert_eq!(limiter.check_at(&new_ip, now), Err(RateLimitExceeded::Untracked));
+    assert_eq!(limiter.clients.lock().unwrap().states.len(), 2);
+
+    // inactive clients are purged after the purge interval
+    let later = now + limiter.purge_interval;
+    assert!(limiter.check_at(&new_ip, later).is_ok());
+    assert_eq!(limiter.clients.lock().unwrap().states.len(), 1);
+
+    // new client is allowed but not tracked if configured
+    limiter.config.allow_untracked = true;
+    assert!(limiter.check_at(&"192.168.0.4".parse().unwrap(), later).is_ok());
+    assert!(limiter.check_at(&"192.168.0.5".parse().unwrap(), later).is_ok());
+    assert!(limiter.check_at(&"192.168.0.5".parse().unwrap(), later).is_ok());
+    assert_eq!(limiter.clients.lock().unwrap().states.len(), 2);
+  }
+
+  #[test]
+  fn ipv4_mapped_client_shares_bucket() {
+    let config = RateLimitConfig {
+      burst: 1,
+      ..Default::default()
+    };
+    let limiter = RateLimiter::new(&config);
+    let now = Instant::now();
+
+    assert!(limiter.check_at(&"192.168.0.1".parse().unwrap(), now).is_ok());
+    assert!(limiter.check_at(&"::ffff:192.168.0.1".parse().unwrap(), now).is_err());
+  }
+
+  #[test]
+  fn unix_users_have_own_buckets() {
+    let config = RateLimitConfig {
+      burst: 1,
+      ..Default::default()
+    };
+    let limiter = RateLimiter::new(&config);
+    let now = Instant::now();
+
+    assert!(limiter.check_at(&"127.0.0.1".parse().unwrap(), now).is_ok());
+    assert!(limiter.check_key_at(&ClientKey::UnixUser(1000), now).is_ok());
+    assert!(limiter.check_key_at(&ClientKey::UnixUser(1001), now).is_ok());
+    assert!(limiter.check_key_at(&ClientKey::UnixUser(1000), now).is_err());
+  }
+}
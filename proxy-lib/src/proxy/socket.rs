@@ -1,11 +1,27 @@
 use crate::{error::*, log::*};
-use socket2::{Domain, Protocol, Socket, Type};
-use std::net::{SocketAddr, UdpSocket};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::{
+  net::{SocketAddr, UdpSocket},
+  sync::OnceLock,
+};
 use tokio::net::TcpSocket;
 
+/// Sockets passed from the service manager like systemd, which are taken from the environment only once in the process
+static INHERITED_SOCKETS: OnceLock<InheritedSockets> = OnceLock::new();
+
 /// Bind TCP socket to the given `SocketAddr`, and returns the TCP socket with `SO_REUSEADDR` and `SO_REUSEPORT` options.
 /// This option is required to re-bind the socket address when the proxy instance is reconstructed.
-pub(super) fn bind_tcp_socket(listening_on: &SocketAddr) -> Result<TcpSocket> {
+/// If a listening TCP socket for the address is passed via socket activation, it is adopted instead of binding a new one.
+/// In transparent mode, the socket accepts connections destined to any address redirected by TPROXY rules.
+pub(super) fn bind_tcp_socket(listening_on: &SocketAddr, transparent: bool) -> Result<TcpSocket> {
+  if let Some(socket) = inherited_sockets().adopt(listening_on, Type::STREAM) {
+    socket.set_nonblocking(true)?;
+    if transparent {
+      set_transparent(&socket, listening_on.is_ipv6())?;
+    }
+    return Ok(TcpSocket::from_std_stream(socket.into()));
+  }
+
   let tcp_socket = if listening_on.is_ipv6() {
     TcpSocket::new_v6()
   } else {
@@ -16,6 +32,10 @@ pub(super) fn bind_tcp_socket(listening_on: &SocketAddr) -> Result<TcpSocket> {
   #[cfg(not(target_os = "windows"))]
   tcp_socket.set_reuseport(true)?;
 
+  if transparent {
+    set_transparent(&SockRef::from(&tcp_socket), listening_on.is_ipv6())?;
+  }
+
   if let Err(e) = tcp_socket.bind(*listening_on) {
     error!("Failed to bind TCP socket: {}", e);
     return Err(Error::Io(e));
@@ -25,7 +45,19 @@ pub(super) fn bind_tcp_socket(listening_on: &SocketAddr) -> Result<TcpSocket> {
 
 /// Bind UDP socket to the given `SocketAddr`, and returns the UDP socket with `SO_REUSEADDR` and `SO_REUSEPORT` options.
 /// This option is required to re-bind the socket address when the proxy instance is reconstructed.
-pub(super) fn bind_udp_socket(listening_on: &SocketAddr) -> Result<UdpSocket> {
+/// If a UDP socket for the address is passed via socket activation, it is adopted instead of binding a new one.
+/// In transparent mode, the socket receives datagrams destined to any address redirected by TPROXY rules,
+/// along with their original destination addresses.
+pub(super) fn bind_udp_socket(listening_on: &SocketAddr, transparent: bool) -> Result<UdpSocket> {
+  if let Some(socket) = inherited_sockets().adopt(listening_on, Type::DGRAM) {
+    socket.set_nonblocking(true)?;
+    if transparent {
+      set_transparent(&socket, listening_on.is_ipv6())?;
+      set_recv_orig_dst(&socket, listening_on.is_ipv6())?;
+    }
+    return Ok(socket.into());
+  }
+
   let socket = if listening_on.is_ipv6() {
     Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))
   } else {
@@ -38,6 +70,11 @@ pub(super) fn bind_udp_socket(listening_on: &SocketAddr) -> Result<UdpSocket> {
 
   socket.set_nonblocking(true)?; // This is important to use `recv_from` in the UDP listener
 
+  if transparent {
+    set_transparent(&socket, listening_on.is_ipv6())?;
+    set_recv_orig_dst(&socket, listening_on.is_ipv6())?;
+  }
+
   if let Err(e) = socket.bind(&(*listening_on).into()) {
     error!("Failed to bind UDP socket: {}", e);
     return Err(Error::Io(e));
@@ -46,3 +83,205 @@ pub(super) fn bind_udp_socket(listening_on: &SocketAddr) -> Result<UdpSocket> {
 
   Ok(udp_socket)
 }
+
+/// Bind UDP socket to the original destination of an intercepted query in transparent mode,
+/// which is used to send back the response as if it came from the original destination.
+pub(super) fn bind_udp_socket_from(orig_dst_addr: &SocketAddr) -> Result<UdpSocket> {
+  let socket = Socket::new(Domain::for_address(*orig_dst_addr), Type::DGRAM, Some(Protocol::UDP))?;
+  socket.set_reuse_address(true)?;
+  set_transparent(&socket, orig_dst_addr.is_ipv6())?;
+  socket.set_nonblocking(true)?;
+  socket.bind(&(*orig_dst_addr).into())?;
+  Ok(socket.into())
+}
+
+#[cfg(target_os = "linux")]
+/// Set `IP_TRANSPARENT` or `IPV6_TRANSPARENT` to bind and accept non-local addresses, which requires CAP_NET_ADMIN
+fn set_transparent(socket: &Socket, is_ipv6: bool) -> Result<()> {
+  if is_ipv6 {
+    setsockopt_enable(socket, libc::SOL_IPV6, libc::IPV6_TRANSPARENT)
+  } else {
+    socket.set_ip_transparent(true).map_err(Error::Io)
+  }
+}
+
+#[cfg(target_os = "linux")]
+/// Set `IP_RECVORIGDSTADDR` or `IPV6_RECVORIGDSTADDR` to receive the original destination address of each datagram
+pub(super) fn set_recv_orig_dst(socket: &Socket, is_ipv6: bool) -> Result<()> {
+  if is_ipv6 {
+    setsockopt_enable(socket, libc::SOL_IPV6, libc::IPV6_RECVORIGDSTADDR)
+  } else {
+    setsockopt_enable(socket, libc::SOL_IP, libc::IP_RECVORIGDSTADDR)
+  }
+}
+
+#[cfg(target_os = "linux")]
+/// Set `IP_PKTINFO` or `IPV6_RECVPKTINFO` to receive the destination address and interface of each datagram
+pub(super) fn set_recv_pktinfo(socket: &Socket, is_ipv6: bool) -> Result<()> {
+  if is_ipv6 {
+    setsockopt_enable(socket, libc::SOL_IPV6, libc::IPV6_RECVPKTINFO)
+  } else {
+    setsockopt_enable(socket, libc::SOL_IP, libc::IP_PKTINFO)
+  }
+}
+
+#[cfg(target_os = "linux")]
+/// Enable the boolean socket option
+fn setsockopt_enable(socket: &Socket, level: libc::c_int, name: libc::c_int) -> Result<()> {
+  use std::os::fd::AsRawFd;
+
+  let enable: libc::c_int = 1;
+  // SAFETY: the option value is a valid c_int with its size
+  let res = unsafe {
+    libc::setsockopt(
+      socket.as_raw_fd(),
+      level,
+      name,
+      (&enable as *const libc::c_int).cast(),
+      std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+    )
+  };
+  if res != 0 {
+    return Err(Error::Io(std::io::Error::last_os_error()));
+  }
+  Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_transparent(_socket: &Socket, _is_ipv6: bool) -> Result<()> {
+  Err(Error::ProxyServiceError(
+    "Transparent mode is supported only on Linux".to_string(),
+  ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_recv_orig_dst(_socket: &Socket, _is_ipv6: bool) -> Result<()> {
+  Err(Error::ProxyServiceError(
+    "Transparent mode is supported only on Linux".to_string(),
+  ))
+}
+
+#[cfg(target_os = "linux")]
+/// Recover the original destination of the TCP connection, i.e., `SO_ORIGINAL_DST` for NAT redirection (REDIRECT rules)
+/// and the local address for TPROXY rules.
+pub(super) fn original_dst_addr(stream: &tokio::net::TcpStream) -> Option<SocketAddr> {
+  let socket = SockRef::from(stream);
+  let local_addr = stream.local_addr().ok()?;
+  let orig_dst = if local_addr.is_ipv6() {
+    socket.original_dst_ipv6()
+  } else {
+    socket.original_dst()
+  };
+  orig_dst.ok().and_then(|addr| addr.as_socket()).or(Some(local_addr))
+}
+
+#[cfg(not(target_os = "linux"))]
+/// Original destination is not available on this platform, and the local address is returned
+pub(super) fn original_dst_addr(stream: &tokio::net::TcpStream) -> Option<SocketAddr> {
+  stream.local_addr().ok()
+}
+
+/// Get sockets passed via socket activation
+fn inherited_sockets() -> &'static InheritedSockets {
+  INHERITED_SOCKETS.get_or_init(InheritedSockets::from_env)
+}
+
+#[derive(Debug, Default)]
+/// Pre-opened sockets inherited from the parent process, i.e., the service manager
+struct InheritedSockets {
+  /// inherited sockets with their local addresses and types
+  sockets: Vec<(SocketAddr, Type, Socket)>,
+}
+
+impl InheritedSockets {
+  #[cfg(unix)]
+  /// Take sockets passed via `LISTEN_FDS` and `LISTEN_PID` (systemd socket activation, see sd_listen_fds(3))
+  fn from_env() -> Self {
+    use crate::constants::SD_LISTEN_FDS_START;
+    use std::os::fd::FromRawFd;
+
+    let listen_pid = std::env::var("LISTEN_PID").ok().and_then(|v| v.parse::<u32>().ok());
+    let listen_fds = std::env::var("LISTEN_FDS").ok().and_then(|v| v.parse::<i32>().ok());
+    // the variables are left set, since modifying the environment is unsound once other threads are running.
+    // child processes never take the sockets because of the mismatched LISTEN_PID, and this process takes them only once.
+    if listen_pid != Some(std::process::id()) {
+      return Self::default();
+    }
+    let Some(listen_fds) = listen_fds else {
+      return Self::default();
+    };
+
+    let sockets = (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + listen_fds)
+      .filter_map(|fd| {
+        // SAFETY: fds starting from SD_LISTEN_FDS_START are passed to and owned by this process
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        let (Ok(local_addr), Ok(ty)) = (socket.local_addr(), socket.r#type()) else {
+          warn!("Ignore inherited fd {} that is not a socket", fd);
+          return None;
+        };
+        let Some(local_addr) = local_addr.as_socket() else {
+          warn!("Ignore inherited fd {} that is not an inet socket", fd);
+          return None;
+        };
+        // not to leak the fd to child processes
+        let _ = socket.set_cloexec(true);
+        info!("Inherited socket via socket activation: fd {} ({:?} {})", fd, ty, local_addr);
+        Some((local_addr, ty, socket))
+      })
+      .collect();
+    Self { sockets }
+  }
+
+  #[cfg(not(unix))]
+  /// Socket activation is not supported on this platform
+  fn from_env() -> Self {
+    Self::default()
+  }
+
+  /// Duplicate the inherited socket matching the address and the type, where the original one is kept open
+  /// so that it can be adopted again when the proxy instance is reconstructed on reload.
+  fn adopt(&self, listening_on: &SocketAddr, ty: Type) -> Option<Socket> {
+    let (_, _, socket) = self
+      .sockets
+      .iter()
+      .find(|(addr, socket_type, _)| addr == listening_on && *socket_type == ty)?;
+    match socket.try_clone() {
+      Ok(socket) => {
+        debug!("Adopt inherited {:?} socket for {}", ty, listening_on);
+        Some(socket)
+      }
+      Err(e) => {
+        error!(
+          "Failed to duplicate inherited socket for {}, bind instead: {}",
+          listening_on, e
+        );
+        None
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn adopt_matching_inherited_socket() {
+    let udp_socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+    udp_socket.bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap().into()).unwrap();
+    let udp_addr = udp_socket.local_addr().unwrap().as_socket().unwrap();
+    let inherited = InheritedSockets {
+      sockets: vec![(udp_addr, Type::DGRAM, udp_socket)],
+    };
+
+    assert!(inherited.adopt(&udp_addr, Type::STREAM).is_none());
+    assert!(inherited.adopt(&"127.0.0.1:1".parse().unwrap(), Type::DGRAM).is_none());
+
+    // adopted socket can be dropped and adopted again, as on reload
+    let adopted = inherited.adopt(&udp_addr, Type::DGRAM).unwrap();
+    assert_eq!(adopted.local_addr().unwrap().as_socket(), Some(udp_addr));
+    drop(adopted);
+    let adopted: UdpSocket = inherited.adopt(&udp_addr, Type::DGRAM).unwrap().into();
+    assert_eq!(adopted.local_addr().unwrap(), udp_addr);
+  }
+}
@@ -0,0 +1,28 @@
+use crate::{error::*, globals::TlsConfig};
+use std::sync::Arc;
+use tokio_rustls::rustls::{crypto::ring::default_provider, ServerConfig};
+
+/// Build TLS server configuration for encrypted listeners from PEM-encoded certificate chain and private key.
+/// `alpn_protocols` are advertised in the TLS handshake in the given order of preference.
+pub(crate) fn build_tls_server_config(tls_config: &TlsConfig, alpn_protocols: &[&[u8]]) -> Result<Arc<ServerConfig>> {
+  let certificate_chain = rustls_pemfile::certs(&mut tls_config.certificate_chain.as_bytes())
+    .collect::<std::result::Result<Vec<_>, _>>()
+    .map_err(|e| Error::InvalidTlsConfig(format!("Failed to read certificate chain: {e}")))?;
+  if certificate_chain.is_empty() {
+    return Err(Error::InvalidTlsConfig("No certificate is found".to_string()));
+  }
+
+  let Some(private_key) = rustls_pemfile::private_key(&mut tls_config.private_key.as_bytes())
+    .map_err(|e| Error::InvalidTlsConfig(format!("Failed to read private key: {e}")))?
+  else {
+    return Err(Error::InvalidTlsConfig("No private key is found".to_string()));
+  };
+
+  let mut server_config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+    .with_safe_default_protocol_versions()?
+    .with_no_client_auth()
+    .with_single_cert(certificate_chain, private_key)?;
+  server_config.alpn_protocols = alpn_protocols.iter().map(|v| v.to_vec()).collect();
+
+  Ok(Arc::new(server_config))
+}
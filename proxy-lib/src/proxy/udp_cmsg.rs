@@ -0,0 +1,208 @@
+use super::proxy_udp::{LocalEndpoint, PktInfo, RecvMeta};
+use crate::constants::UDP_CMSG_BUFFER_SIZE;
+use socket2::SockAddr;
+use std::{
+  io,
+  mem::{self, MaybeUninit},
+  net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+  os::fd::{AsRawFd, RawFd},
+};
+use tokio::{io::Interest, net::UdpSocket};
+
+/// Receive a datagram with ancillary data via recvmsg(2)
+pub(super) async fn recv_with_meta(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<RecvMeta> {
+  socket.async_io(Interest::READABLE, || recvmsg(socket.as_raw_fd(), buf)).await
+}
+
+/// Send a datagram from the destination address and interface of the query via sendmsg(2)
+pub(super) async fn send_with_pktinfo(
+  socket: &UdpSocket,
+  buf: &[u8],
+  dst_addr: &SocketAddr,
+  pktinfo: &PktInfo,
+) -> io::Result<usize> {
+  socket
+    .async_io(Interest::WRITABLE, || sendmsg(socket.as_raw_fd(), buf, dst_addr, pktinfo))
+    .await
+}
+
+fn recvmsg(fd: RawFd, buf: &mut [u8]) -> io::Result<RecvMeta> {
+  // SAFETY: all zero is valid for sockaddr_storage and msghdr
+  let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+  let mut iov = libc::iovec {
+    iov_base: buf.as_mut_ptr().cast(),
+    iov_len: buf.len(),
+  };
+  // aligned for cmsghdr
+  let mut control = [MaybeUninit::<u64>::uninit(); UDP_CMSG_BUFFER_SIZE / mem::size_of::<u64>()];
+  let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+  msg.msg_name = (&mut name as *mut libc::sockaddr_storage).cast();
+  msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+  msg.msg_iov = &mut iov;
+  msg.msg_iovlen = 1;
+  msg.msg_control = control.as_mut_ptr().cast();
+  msg.msg_controllen = mem::size_of_val(&control) as _;
+
+  // SAFETY: all the buffers given to msghdr outlive the call
+  let len = unsafe { libc::recvmsg(fd, &mut msg, 0) };
+  if len < 0 {
+    return Err(io::Error::last_os_error());
+  }
+  // SAFETY: the kernel filled the storage with the length of msg_namelen
+  let src_addr = unsafe { SockAddr::new(name, msg.msg_namelen) }
+    .as_socket()
+    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Non-inet source address"))?;
+
+  let mut local = LocalEndpoint::default();
+  // SAFETY: control messages are iterated within msg_controllen set by the kernel, and their data may be unaligned
+  let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+  while !cmsg.is_null() {
+    let hdr = unsafe { &*cmsg };
+    let data = unsafe { libc::CMSG_DATA(cmsg) };
+    match (hdr.cmsg_level, hdr.cmsg_type) {
+      (libc::SOL_IP, libc::IP_ORIGDSTADDR) | (libc::SOL_IPV6, libc::IPV6_ORIGDSTADDR) => {
+        let data_len = hdr.cmsg_len as usize - unsafe { libc::CMSG_LEN(0) } as usize;
+        local.orig_dst_addr = unsafe { sockaddr_from_cmsg_data(data, data_len) };
+      }
+      (libc::SOL_IP, libc::IP_PKTINFO) => {
+        // ipi_spec_dst is the local address for replies, while ipi_addr is the destination in the header,
+        // which may be a broadcast or multicast address
+        let info = unsafe { std::ptr::read_unaligned(data.cast::<libc::in_pktinfo>()) };
+        local.pktinfo = Some(PktInfo {
+          local_ip: IpAddr::V4(Ipv4Addr::from(info.ipi_spec_dst.s_addr.to_ne_bytes())),
+          ifindex: info.ipi_ifindex as u32,
+        });
+      }
+      (libc::SOL_IPV6, libc::IPV6_PKTINFO) => {
+        // the kernel chooses the source address for replies to multicast destinations
+        let info = unsafe { std::ptr::read_unaligned(data.cast::<libc::in6_pktinfo>()) };
+        let dst_ip = Ipv6Addr::from(info.ipi6_addr.s6_addr);
+        local.pktinfo = Some(PktInfo {
+          local_ip: IpAddr::V6(if dst_ip.is_multicast() {
+            Ipv6Addr::UNSPECIFIED
+          } else {
+            dst_ip
+          }),
+          ifindex: info.ipi6_ifindex,
+        });
+      }
+      _ => (),
+    }
+    cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+  }
+
+  Ok(RecvMeta {
+    len: len as usize,
+    src_addr,
+    local,
+  })
+}
+
+fn sendmsg(fd: RawFd, buf: &[u8], dst_addr: &SocketAddr, pktinfo: &PktInfo) -> io::Result<usize> {
+  let name = SockAddr::from(*dst_addr);
+  let mut iov = libc::iovec {
+    iov_base: buf.as_ptr() as *mut libc::c_void,
+    iov_len: buf.len(),
+  };
+  // aligned for cmsghdr, and zeroed for CMSG_FIRSTHDR
+  let mut control = [0u64; UDP_CMSG_BUFFER_SIZE / mem::size_of::<u64>()];
+  // SAFETY: all zero is valid for msghdr
+  let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+  msg.msg_name = name.as_ptr() as *mut libc::c_void;
+  msg.msg_namelen = name.len();
+  msg.msg_iov = &mut iov;
+  msg.msg_iovlen = 1;
+  msg.msg_control = control.as_mut_ptr().cast();
+
+  // SAFETY: the control buffer is large enough for a single pktinfo message
+  unsafe {
+    match pktinfo.local_ip {
+      IpAddr::V4(local_ip) => {
+        let info = libc::in_pktinfo {
+          ipi_ifindex: pktinfo.ifindex as libc::c_int,
+          ipi_spec_dst: libc::in_addr {
+            s_addr: u32::from_ne_bytes(local_ip.octets()),
+          },
+          ipi_addr: libc::in_addr { s_addr: 0 },
+        };
+        write_cmsg(&mut msg, libc::SOL_IP, libc::IP_PKTINFO, info);
+      }
+      IpAddr::V6(local_ip) => {
+        let info = libc::in6_pktinfo {
+          ipi6_addr: libc::in6_addr {
+            s6_addr: local_ip.octets(),
+          },
+          ipi6_ifindex: pktinfo.ifindex,
+        };
+        write_cmsg(&mut msg, libc::SOL_IPV6, libc::IPV6_PKTINFO, info);
+      }
+    }
+  }
+
+  // SAFETY: all the buffers given to msghdr outlive the call
+  let len = unsafe { libc::sendmsg(fd, &msg, 0) };
+  if len < 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(len as usize)
+}
+
+/// Write a single control message at the head of the control buffer of msghdr
+unsafe fn write_cmsg<T>(msg: &mut libc::msghdr, level: libc::c_int, cmsg_type: libc::c_int, data: T) {
+  msg.msg_controllen = libc::CMSG_SPACE(mem::size_of::<T>() as u32) as _;
+  let cmsg = libc::CMSG_FIRSTHDR(msg);
+  (*cmsg).cmsg_level = level;
+  (*cmsg).cmsg_type = cmsg_type;
+  (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<T>() as u32) as _;
+  std::ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<T>(), data);
+}
+
+/// Copy sockaddr_in or sockaddr_in6 carried in the control message, which may be unaligned
+unsafe fn sockaddr_from_cmsg_data(data: *const u8, data_len: usize) -> Option<SocketAddr> {
+  let mut storage: libc::sockaddr_storage = mem::zeroed();
+  let len = data_len.min(mem::size_of::<libc::sockaddr_storage>());
+  std::ptr::copy_nonoverlapping(data, (&mut storage as *mut libc::sockaddr_storage).cast::<u8>(), len);
+  SockAddr::new(storage, len as libc::socklen_t).as_socket()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::proxy::socket::{set_recv_orig_dst, set_recv_pktinfo};
+
+  #[tokio::test]
+  async fn recv_with_orig_dst() {
+    let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    set_recv_orig_dst(&socket2::SockRef::from(&receiver), false).unwrap();
+    let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    sender.send_to(b"query", receiver.local_addr().unwrap()).await.unwrap();
+
+    let mut buf = [0u8; 16];
+    let meta = recv_with_meta(&receiver, &mut buf).await.unwrap();
+    assert_eq!(&buf[..meta.len], b"query");
+    assert_eq!(meta.src_addr, sender.local_addr().unwrap());
+    // not intercepted, so the original destination is the local address
+    assert_eq!(meta.local.orig_dst_addr, Some(receiver.local_addr().unwrap()));
+  }
+
+  #[tokio::test]
+  async fn reply_from_destination_of_query() {
+    let receiver = UdpSocket::bind("0.0.0.0:0").await.unwrap();
+    set_recv_pktinfo(&socket2::SockRef::from(&receiver), false).unwrap();
+    let receiver_port = receiver.local_addr().unwrap().port();
+    let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    sender.send_to(b"query", ("127.0.0.1", receiver_port)).await.unwrap();
+
+    let mut buf = [0u8; 16];
+    let meta = recv_with_meta(&receiver, &mut buf).await.unwrap();
+    let pktinfo = meta.local.pktinfo.unwrap();
+    assert_eq!(pktinfo.local_ip, IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+    send_with_pktinfo(&receiver, b"response", &meta.src_addr, &pktinfo)
+      .await
+      .unwrap();
+    let (len, from) = sender.recv_from(&mut buf).await.unwrap();
+    assert_eq!(&buf[..len], b"response");
+    assert_eq!(from, SocketAddr::from((Ipv4Addr::LOCALHOST, receiver_port)));
+  }
+}
@@ -0,0 +1,323 @@
+use crate::{
+  constants::{SUPERVISOR_RESTART_BACKOFF_MAX_SEC, SUPERVISOR_RESTART_BACKOFF_MIN_SEC},
+  error::*,
+  log::*,
+};
+use futures::{stream::FuturesUnordered, StreamExt};
+use std::{
+  collections::HashMap,
+  fmt::Display,
+  future::Future,
+  sync::{Arc, Mutex},
+};
+use tokio::{
+  sync::{watch, Notify},
+  task::{AbortHandle, JoinHandle},
+  time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// State of a background service under the supervisor
+pub enum ServiceState {
+  /// Service is running
+  Running,
+  /// Service failed and is waiting for restart with backoff
+  Restarting {
+    /// number of restarts so far
+    restarts: usize,
+    /// error of the last failure
+    last_error: String,
+  },
+  /// Service is stopped by the term signal
+  Stopped,
+  /// Service failed with a fatal error, which shuts down the proxy
+  Failed {
+    /// the fatal error
+    last_error: String,
+  },
+}
+
+#[derive(Debug, Clone, Default)]
+/// States of background services under the supervisor, shared with the caller of the entrypoint
+pub struct ServiceStates(Arc<Mutex<HashMap<String, ServiceState>>>);
+
+impl ServiceStates {
+  /// Get the states of all services sorted by their names
+  pub fn get(&self) -> Vec<(String, ServiceState)> {
+    let Ok(inner) = self.0.lock() else {
+      error!("Failed to lock service states");
+      return vec![];
+    };
+    let mut states = inner.iter().map(|(k, v)| (k.clone(), v.clone())).collect::<Vec<_>>();
+    states.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    states
+  }
+
+  /// Update the state of the service
+  fn set(&self, name: &str, state: ServiceState) {
+    let Ok(mut inner) = self.0.lock() else {
+      error!("Failed to lock service states");
+      return;
+    };
+    inner.insert(name.to_string(), state);
+  }
+}
+
+/// Supervisor of background services, which restarts an individual failed service with exponential backoff
+/// instead of tearing down the whole proxy. A service finishing without error, i.e., term notified, is not restarted,
+/// and a service failing with a fatal error shuts down the proxy. Services are aborted when the supervisor is dropped.
+pub(crate) struct Supervisor {
+  /// runtime handle
+  runtime_handle: tokio::runtime::Handle,
+  /// flag set once term notified, which is kept unlike the notification to waiters
+  terminated: Option<watch::Receiver<bool>>,
+  /// task watching the term notification to set the flag
+  term_watcher: Option<JoinHandle<()>>,
+  /// states of services
+  states: ServiceStates,
+  /// supervising tasks, which return the fatal error if any
+  services: Vec<JoinHandle<Option<String>>>,
+  /// initial backoff of restart
+  backoff_min: Duration,
+  /// max backoff of restart, which is also the uptime to regard a restarted service as recovered
+  backoff_max: Duration,
+}
+
+impl Supervisor {
+  /// Create a new supervisor
+  pub(crate) fn new(runtime_handle: &tokio::runtime::Handle, term_notify: &Option<Arc<Notify>>, states: ServiceStates) -> Self {
+    let (terminated, term_watcher) = match term_notify {
+      Some(term) => {
+        let (tx, rx) = watch::channel(false);
+        // registered as a waiter right now so that the notification is not missed
+        let mut notified = Box::pin(term.clone().notified_owned());
+        notified.as_mut().enable();
+        let term_watcher = runtime_handle.spawn(async move {
+          notified.await;
+          let _ = tx.send(true);
+        });
+        (Some(rx), Some(term_watcher))
+      }
+      None => (None, None),
+    };
+    Self {
+      runtime_handle: runtime_handle.clone(),
+      terminated,
+      term_watcher,
+      states,
+      services: vec![],
+      backoff_min: Duration::from_secs(SUPERVISOR_RESTART_BACKOFF_MIN_SEC),
+      backoff_max: Duration::from_secs(SUPERVISOR_RESTART_BACKOFF_MAX_SEC),
+    }
+  }
+
+  /// Spawn the service built by the factory under supervision, where the factory is called again for every restart
+  pub(crate) fn spawn<F, Fut, E>(&mut self, name: &str, factory: F)
+  where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = std::result::Result<(), E>> + Send + 'static,
+    E: Display + Send + 'static,
+  {
+    self.spawn_with_fatal(name, factory, |_| false);
+  }
+
+  /// Spawn the service built by the factory under supervision, where an error classified as fatal is not restarted
+  /// and shuts down the proxy, e.g., the service has already given up after its own retries
+  pub(crate) fn spawn_with_fatal<F, Fut, E, C>(&mut self, name: &str, factory: F, is_fatal: C)
+  where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = std::result::Result<(), E>> + Send + 'static,
+    E: Display + Send + 'static,
+    C: Fn(&E) -> bool + Send + 'static,
+  {
+    let name = name.to_string();
+    let runtime_handle = self.runtime_handle.clone();
+    let mut terminated = self.terminated.clone();
+    let states = self.states.clone();
+    let (backoff_min, backoff_max) = (self.backoff_min, self.backoff_max);
+
+    let service = self.runtime_handle.spawn(async move {
+      let mut restarts = 0;
+      let mut backoff = backoff_min;
+      loop {
+        // term may be notified while the service is failing, where the stale service must not be restarted
+        if terminated.as_ref().is_some_and(|v| *v.borrow()) {
+          info!("[{name}] Service stopped before restart");
+          states.set(&name, ServiceState::Stopped);
+          return None;
+        }
+        states.set(&name, ServiceState::Running);
+        let started = Instant::now();
+        // spawned as a separate task so that a panic is caught as a failure, which is aborted together with the supervising task
+        let task = runtime_handle.spawn(factory());
+        let _abort_task = AbortOnDrop(vec![task.abort_handle()]);
+        let last_error = match task.await {
+          Ok(Ok(())) => {
+            info!("[{name}] Service stopped");
+            states.set(&name, ServiceState::Stopped);
+            return None;
+          }
+          Ok(Err(e)) if is_fatal(&e) => {
+            let last_error = e.to_string();
+            error!("[{name}] Service failed with a fatal error: {last_error}");
+            states.set(
+              &name,
+              ServiceState::Failed {
+                last_error: last_error.clone(),
+              },
+            );
+            return Some(format!("[{name}] {last_error}"));
+          }
+          Ok(Err(e)) => e.to_string(),
+          Err(e) => format!("panicked: {e}"),
+        };
+
+        // service that ran long enough is regarded as recovered from the previous failures
+        if started.elapsed() >= backoff_max {
+          backoff = backoff_min;
+        }
+        restarts += 1;
+        warn!(
+          "[{name}] Service failed: {last_error}. Restart in {:?} (restarts: {restarts})",
+          backoff
+        );
+        states.set(&name, ServiceState::Restarting { restarts, last_error });
+
+        match &mut terminated {
+          Some(terminated) => {
+            tokio::select! {
+              _ = tokio::time::sleep(backoff) => (),
+              _ = terminated.wait_for(|v| *v) => {
+                info!("[{name}] Service stopped while waiting for restart");
+                states.set(&name, ServiceState::Stopped);
+                return None;
+              }
+            }
+          }
+          None => tokio::time::sleep(backoff).await,
+        }
+        backoff = (backoff * 2).min(backoff_max);
+      }
+    });
+    self.services.push(service);
+  }
+
+  /// Wait until all services stop, i.e., term notified, or return error as soon as a service fails with a fatal error.
+  /// The remaining services are aborted when the supervisor is dropped, e.g., on return of the entrypoint.
+  pub(crate) async fn wait(mut self) -> Result<()> {
+    if self.services.is_empty() {
+      return std::future::pending().await;
+    }
+    let mut services = self.services.iter_mut().collect::<FuturesUnordered<_>>();
+    while let Some(res) = services.next().await {
+      if let Ok(Some(e)) = res {
+        return Err(Error::ServiceDown(e));
+      }
+    }
+    Ok(())
+  }
+}
+
+impl Drop for Supervisor {
+  fn drop(&mut self) {
+    self.services.iter().for_each(|service| service.abort());
+    if let Some(term_watcher) = &self.term_watcher {
+      term_watcher.abort();
+    }
+  }
+}
+
+/// Abort the tasks when dropped, e.g., listeners on return of the entrypoint
+pub(crate) struct AbortOnDrop(pub(crate) Vec<AbortHandle>);
+
+impl Drop for AbortOnDrop {
+  fn drop(&mut self) {
+    self.0.iter().for_each(|task| task.abort());
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  #[tokio::test]
+  async fn supervisor_restarts_failed_service() {
+    let states = ServiceStates::default();
+    let mut supervisor = Supervisor::new(&tokio::runtime::Handle::current(), &None, states.clone());
+    supervisor.backoff_min = Duration::from_millis(10);
+    supervisor.backoff_max = Duration::from_millis(40);
+
+    // fails twice, panics once, and then stops
+    let runs = Arc::new(AtomicUsize::new(0));
+    let runs_clone = runs.clone();
+    supervisor.spawn("flaky", move || {
+      let runs = runs_clone.clone();
+      async move {
+        match runs.fetch_add(1, Ordering::Relaxed) {
+          0 | 1 => Err("failure"),
+          2 => panic!("panic"),
+          _ => Ok(()),
+        }
+      }
+    });
+    supervisor.wait().await.unwrap();
+
+    assert_eq!(runs.load(Ordering::Relaxed), 4);
+    assert_eq!(states.get(), vec![("flaky".to_string(), ServiceState::Stopped)]);
+  }
+
+  #[tokio::test]
+  async fn fatal_error_stops_supervisor_and_aborts_services() {
+    let states = ServiceStates::default();
+    let mut supervisor = Supervisor::new(&tokio::runtime::Handle::current(), &None, states.clone());
+
+    // long-running service that is aborted when the supervisor is dropped
+    let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+    let tx = Arc::new(Mutex::new(Some(tx)));
+    supervisor.spawn("long", move || {
+      let tx = tx.lock().unwrap().take();
+      async move {
+        let _tx = tx;
+        std::future::pending::<()>().await;
+        Ok(()) as std::result::Result<(), String>
+      }
+    });
+    supervisor.spawn_with_fatal("fatal", || async { Err("gave up") }, |e| *e == "gave up");
+
+    assert!(supervisor.wait().await.is_err());
+    assert!(rx.await.is_err());
+    assert!(states.get().contains(&(
+      "fatal".to_string(),
+      ServiceState::Failed {
+        last_error: "gave up".to_string()
+      }
+    )));
+  }
+
+  #[tokio::test]
+  async fn term_during_failure_stops_restart() {
+    let term = Arc::new(Notify::new());
+    let states = ServiceStates::default();
+    let mut supervisor = Supervisor::new(&tokio::runtime::Handle::current(), &Some(term.clone()), states.clone());
+    supervisor.backoff_min = Duration::from_millis(10);
+
+    // fails after term notified without listening to it, i.e., nobody but the supervisor is waiting for the term
+    let runs = Arc::new(AtomicUsize::new(0));
+    let runs_clone = runs.clone();
+    supervisor.spawn("failing", move || {
+      let runs = runs_clone.clone();
+      async move {
+        runs.fetch_add(1, Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        Err("failure")
+      }
+    });
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    term.notify_waiters();
+    supervisor.wait().await.unwrap();
+
+    assert_eq!(runs.load(Ordering::Relaxed), 1);
+    assert_eq!(states.get(), vec![("failing".to_string(), ServiceState::Stopped)]);
+  }
+}